@@ -0,0 +1,45 @@
+// Shared by the build scripts of the board crates, which `include!` it.
+
+use std::{env, fs, path::PathBuf};
+
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct Config {
+    #[serde(default)]
+    devices: Devices,
+}
+
+#[derive(Deserialize, Default)]
+struct Devices {
+    #[serde(default, rename = "block-partition")]
+    block_partition: Option<String>,
+}
+
+/// Passes `block-partition` from `axconfig.toml`, or the file named by
+/// `AX_CONFIG_PATH`, to the crate as `AXBSP_BLOCK_PARTITION`. Unset means
+/// the whole device.
+fn emit_block_partition() {
+    let path = env::var_os("AX_CONFIG_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("axconfig.toml")
+        });
+    println!("cargo:rerun-if-env-changed=AX_CONFIG_PATH");
+    println!("cargo:rerun-if-changed={}", path.display());
+
+    let config: Config = match fs::read_to_string(&path) {
+        Ok(text) => {
+            toml::from_str(&text).unwrap_or_else(|err| panic!("invalid {}: {err}", path.display()))
+        }
+        Err(_) => Config {
+            devices: Devices::default(),
+        },
+    };
+
+    let partition = config.devices.block_partition;
+    println!(
+        "cargo:rustc-env=AXBSP_BLOCK_PARTITION={}",
+        partition.as_deref().unwrap_or_default()
+    );
+}
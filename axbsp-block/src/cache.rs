@@ -0,0 +1,42 @@
+//! Volatile write caches: flush requests and forced unit access writes.
+//!
+//! Like erases, these have no `rdif_block` request kind; queues of devices
+//! with a write cache implement [`FlushQueue`] on top of [`IQueue`].
+
+use alloc::boxed::Box;
+
+use rdif_block::{BlkError, IQueue, Interface, RequestId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheConfig {
+    /// Completed writes may sit in a volatile cache until flushed.
+    pub write_cache: bool,
+    /// [`FlushQueue::submit_write_fua`] is supported.
+    pub fua: bool,
+}
+
+/// A queue that also takes flush and forced unit access requests.
+pub trait FlushQueue: IQueue {
+    fn cache_config(&self) -> CacheConfig;
+
+    /// Submits a flush, which completes once every write completed before
+    /// it is on stable storage. Without a write cache it completes at once.
+    fn submit_flush(&mut self) -> Result<RequestId, BlkError>;
+
+    /// Submits a write that is on stable storage, not just in the cache,
+    /// when it completes. `buffer` stays borrowed until then, as for
+    /// [`IQueue::submit_request`].
+    fn submit_write_fua(&mut self, block_id: usize, buffer: &[u8]) -> Result<RequestId, BlkError>;
+}
+
+/// A block device whose queues take flush and FUA requests.
+///
+/// Like [`EraseInterface`](crate::EraseInterface), handed out by the board
+/// crates for their registered devices by rdrive device ID.
+pub trait FlushInterface: Interface {
+    /// Same as [`FlushQueue::cache_config`] of its queues, without creating
+    /// one.
+    fn cache_config(&self) -> CacheConfig;
+
+    fn create_flush_queue(&mut self) -> Option<Box<dyn FlushQueue>>;
+}
@@ -0,0 +1,283 @@
+//! Identity and health of SD/MMC cards, decoded from their registers.
+
+use alloc::string::String;
+use core::fmt;
+
+const EXT_CSD_REV: usize = 192;
+const EXT_CSD_PRE_EOL_INFO: usize = 267;
+const EXT_CSD_DEVICE_LIFE_TIME_EST_TYP_A: usize = 268;
+const EXT_CSD_DEVICE_LIFE_TIME_EST_TYP_B: usize = 269;
+
+/// `DEVICE_LIFE_TIME_EST_*` values at or above this mean 90 % of the rated
+/// life is used.
+const LIFE_TIME_WORN: u8 = 0x0a;
+
+/// Fields of the CID register.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cid {
+    pub manufacturer_id: u8,
+    /// OEM/application ID; two ASCII characters on SD cards.
+    pub oem_id: u16,
+    pub product_name: String,
+    /// Major and minor product revision.
+    pub revision: (u8, u8),
+    pub serial: u32,
+    /// Year and month of manufacture.
+    pub date: (u16, u8),
+}
+
+impl Cid {
+    /// Decodes the CID of an MMC, most significant byte first. The year
+    /// field depends on `EXT_CSD_REV`.
+    pub fn from_mmc(raw: &[u8; 16], ext_csd_rev: u8) -> Self {
+        let mut year = 1997 + (raw[14] & 0xf) as u16;
+        // eMMC 4.41 之后年份从 2013 年开始循环
+        if ext_csd_rev > 4 && year < 2010 {
+            year += 16;
+        }
+        Cid {
+            manufacturer_id: raw[0],
+            oem_id: raw[2] as u16,
+            product_name: product_name(&raw[3..9]),
+            revision: (raw[9] >> 4, raw[9] & 0xf),
+            serial: u32::from_be_bytes([raw[10], raw[11], raw[12], raw[13]]),
+            date: (year, raw[14] >> 4),
+        }
+    }
+
+    /// Decodes the CID of an SD card, most significant byte first.
+    pub fn from_sd(raw: &[u8; 16]) -> Self {
+        Cid {
+            manufacturer_id: raw[0],
+            oem_id: u16::from_be_bytes([raw[1], raw[2]]),
+            product_name: product_name(&raw[3..8]),
+            revision: (raw[8] >> 4, raw[8] & 0xf),
+            serial: u32::from_be_bytes([raw[9], raw[10], raw[11], raw[12]]),
+            date: (
+                2000 + (((raw[13] & 0xf) << 4) | (raw[14] >> 4)) as u16,
+                raw[14] & 0xf,
+            ),
+        }
+    }
+}
+
+fn product_name(raw: &[u8]) -> String {
+    raw.iter()
+        .map(|&c| {
+            if c.is_ascii_graphic() || c == b' ' {
+                c as char
+            } else {
+                '?'
+            }
+        })
+        .collect::<String>()
+        .trim_end()
+        .into()
+}
+
+/// Specification version of a card.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardVersion {
+    /// `EXT_CSD_REV`.
+    Emmc(u8),
+    /// `CSD_STRUCTURE`: 0 for SDSC, 1 for SDHC/SDXC, 2 for SDUC.
+    Sd(u8),
+}
+
+impl CardVersion {
+    /// Reads `CSD_STRUCTURE` from the CSD of an SD card, most significant
+    /// byte first.
+    pub fn from_sd_csd(raw: &[u8; 16]) -> Self {
+        CardVersion::Sd(raw[0] >> 6)
+    }
+}
+
+impl fmt::Display for CardVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            CardVersion::Emmc(rev) => match rev {
+                0..=3 => write!(f, "eMMC 4.{}", rev),
+                5 => write!(f, "eMMC 4.41"),
+                6 => write!(f, "eMMC 4.5"),
+                7 => write!(f, "eMMC 5.0"),
+                8 => write!(f, "eMMC 5.1"),
+                _ => write!(f, "eMMC (EXT_CSD_REV {})", rev),
+            },
+            CardVersion::Sd(0) => write!(f, "SDSC (CSD 1.0)"),
+            CardVersion::Sd(1) => write!(f, "SDHC/SDXC (CSD 2.0)"),
+            CardVersion::Sd(2) => write!(f, "SDUC (CSD 3.0)"),
+            CardVersion::Sd(structure) => write!(f, "SD (CSD_STRUCTURE {})", structure),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CardIdentity {
+    pub cid: Cid,
+    pub version: CardVersion,
+    /// User area size in 512-byte blocks.
+    pub capacity_blocks: u64,
+}
+
+/// `PRE_EOL_INFO`: consumption of the reserved blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PreEol {
+    /// Not reported.
+    #[default]
+    Undefined,
+    Normal,
+    /// 80 % of the reserved blocks are consumed.
+    Warning,
+    /// 90 % of the reserved blocks are consumed.
+    Urgent,
+}
+
+/// Wear of a card.
+///
+/// Only eMMC 5.0+ reports wear, in `EXT_CSD`. The SD Status register has no
+/// standard equivalent, so SD cards report nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CardHealth {
+    /// `DEVICE_LIFE_TIME_EST_TYP_A`, for SLC memory: `n` means
+    /// `(n - 1) * 10` to `n * 10` % of the rated life is used, 11 means it is
+    /// exceeded.
+    pub life_time_a: Option<u8>,
+    /// `DEVICE_LIFE_TIME_EST_TYP_B`, for MLC memory, like
+    /// [`CardHealth::life_time_a`].
+    pub life_time_b: Option<u8>,
+    pub pre_eol: PreEol,
+}
+
+impl CardHealth {
+    pub fn from_ext_csd(ext_csd: &[u8; 512]) -> Self {
+        if ext_csd[EXT_CSD_REV] < 7 {
+            return CardHealth::default();
+        }
+        let life_time = |index: usize| match ext_csd[index] {
+            0 => None,
+            value => Some(value),
+        };
+        CardHealth {
+            life_time_a: life_time(EXT_CSD_DEVICE_LIFE_TIME_EST_TYP_A),
+            life_time_b: life_time(EXT_CSD_DEVICE_LIFE_TIME_EST_TYP_B),
+            pre_eol: match ext_csd[EXT_CSD_PRE_EOL_INFO] {
+                1 => PreEol::Normal,
+                2 => PreEol::Warning,
+                3 => PreEol::Urgent,
+                _ => PreEol::Undefined,
+            },
+        }
+    }
+
+    /// The card is close to or past the end of its life: reserved blocks
+    /// are running out or 90 % of the rated life is used.
+    pub fn worn(&self) -> bool {
+        matches!(self.pre_eol, PreEol::Warning | PreEol::Urgent)
+            || [self.life_time_a, self.life_time_b]
+                .into_iter()
+                .flatten()
+                .any(|value| value >= LIFE_TIME_WORN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Samsung eMMC CID made in `date`, `(month << 4) | year`.
+    fn mmc_cid(date: u8) -> [u8; 16] {
+        [
+            0x15, 0x01, 0x00, b'8', b'G', b'M', b'E', b'4', b'R', 0x12, 0xde, 0xad, 0xbe, 0xef,
+            date, 0x01,
+        ]
+    }
+
+    #[test]
+    fn mmc_cid_fields() {
+        let cid = Cid::from_mmc(&mmc_cid(0x3b), 3);
+        assert_eq!(cid.manufacturer_id, 0x15);
+        assert_eq!(cid.oem_id, 0);
+        assert_eq!(cid.product_name, "8GME4R");
+        assert_eq!(cid.revision, (1, 2));
+        assert_eq!(cid.serial, 0xdead_beef);
+        assert_eq!(cid.date, (2008, 3));
+    }
+
+    #[test]
+    fn mmc_cid_year_rollover() {
+        // eMMC 4.41 起 1997..=2009 的年份码表示 2013..=2025
+        assert_eq!(Cid::from_mmc(&mmc_cid(0x3b), 5).date, (2024, 3));
+        assert_eq!(Cid::from_mmc(&mmc_cid(0x3b), 8).date, (2024, 3));
+        assert_eq!(Cid::from_mmc(&mmc_cid(0x3b), 4).date, (2008, 3));
+        assert_eq!(Cid::from_mmc(&mmc_cid(0xcd), 8).date, (2010, 12));
+        assert_eq!(Cid::from_mmc(&mmc_cid(0x1f), 8).date, (2012, 1));
+    }
+
+    #[test]
+    fn sd_cid_fields() {
+        // SanDisk，2017 年 6 月
+        let raw = [
+            0x03, b'S', b'D', b'S', b'U', b'0', b'8', b'G', 0x80, 0x12, 0x34, 0x56, 0x78, 0x01,
+            0x16, 0x01,
+        ];
+        let cid = Cid::from_sd(&raw);
+        assert_eq!(cid.manufacturer_id, 0x03);
+        assert_eq!(cid.oem_id, u16::from_be_bytes(*b"SD"));
+        assert_eq!(cid.product_name, "SU08G");
+        assert_eq!(cid.revision, (8, 0));
+        assert_eq!(cid.serial, 0x1234_5678);
+        assert_eq!(cid.date, (2017, 6));
+    }
+
+    #[test]
+    fn product_name_is_printable() {
+        assert_eq!(product_name(b"AB\x01C  "), "AB?C");
+    }
+
+    #[test]
+    fn sd_version() {
+        let mut csd = [0; 16];
+        assert_eq!(CardVersion::from_sd_csd(&csd), CardVersion::Sd(0));
+        csd[0] = 0x40;
+        assert_eq!(CardVersion::from_sd_csd(&csd), CardVersion::Sd(1));
+    }
+
+    fn ext_csd(rev: u8, life_time_a: u8, life_time_b: u8, pre_eol: u8) -> [u8; 512] {
+        let mut ext_csd = [0; 512];
+        ext_csd[EXT_CSD_REV] = rev;
+        ext_csd[EXT_CSD_DEVICE_LIFE_TIME_EST_TYP_A] = life_time_a;
+        ext_csd[EXT_CSD_DEVICE_LIFE_TIME_EST_TYP_B] = life_time_b;
+        ext_csd[EXT_CSD_PRE_EOL_INFO] = pre_eol;
+        ext_csd
+    }
+
+    #[test]
+    fn health_from_ext_csd() {
+        let health = CardHealth::from_ext_csd(&ext_csd(8, 0x02, 0x00, 1));
+        assert_eq!(health.life_time_a, Some(2));
+        assert_eq!(health.life_time_b, None);
+        assert_eq!(health.pre_eol, PreEol::Normal);
+        assert!(!health.worn());
+    }
+
+    #[test]
+    fn health_before_emmc_5() {
+        // eMMC 4.5 的这些字节是保留位
+        let health = CardHealth::from_ext_csd(&ext_csd(6, 0x0b, 0x0b, 3));
+        assert_eq!(health, CardHealth::default());
+        assert!(!health.worn());
+    }
+
+    #[test]
+    fn worn_cards() {
+        let worn = |life_time_a, life_time_b, pre_eol| {
+            CardHealth::from_ext_csd(&ext_csd(7, life_time_a, life_time_b, pre_eol)).worn()
+        };
+        assert!(!worn(0x09, 0x09, 1));
+        assert!(worn(0x0a, 0x01, 1));
+        assert!(worn(0x01, 0x0b, 1));
+        assert!(worn(0x01, 0x01, 2));
+        assert!(worn(0x00, 0x00, 3));
+        assert!(!worn(0x01, 0x01, 4));
+    }
+}
@@ -0,0 +1,197 @@
+//! Discard, TRIM and erase requests.
+//!
+//! `rdif_block` requests only read and write, so queues that can erase
+//! blocks implement [`EraseQueue`] on top of [`IQueue`]; erase requests
+//! complete through the same [`IQueue::poll_request`].
+
+use alloc::boxed::Box;
+
+use rdif_block::{BlkError, IQueue, Interface, RequestId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EraseKind {
+    /// The blocks are no longer in use; their contents are undefined
+    /// afterwards.
+    Discard,
+    /// Erases single blocks, which read back as erased data.
+    Trim,
+    /// Erases whole erase groups, see [`EraseConfig::group_blocks`].
+    Erase,
+    /// Like [`EraseKind::Erase`], also purging copies the device keeps
+    /// internally.
+    SecureErase,
+}
+
+/// What a queue can erase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EraseConfig {
+    pub discard: bool,
+    pub trim: bool,
+    pub erase: bool,
+    pub secure_erase: bool,
+    /// Erase group size in blocks. [`EraseKind::Erase`] and
+    /// [`EraseKind::SecureErase`] ranges must start and end on a group.
+    pub group_blocks: usize,
+}
+
+impl Default for EraseConfig {
+    /// Nothing supported.
+    fn default() -> Self {
+        EraseConfig {
+            discard: false,
+            trim: false,
+            erase: false,
+            secure_erase: false,
+            group_blocks: 1,
+        }
+    }
+}
+
+impl EraseConfig {
+    /// What an SD card erases with `ERASE` (CMD38), from its CSD, most
+    /// significant byte first: single blocks with `ERASE_BLK_EN`, otherwise
+    /// only whole `SECTOR_SIZE` units.
+    ///
+    /// DISCARD is CMD38 with argument 1, only on SD 5.0+ cards that set
+    /// `DISCARD_SUPPORT` in the SD Status, so it is not derived from the
+    /// CSD; SD has no TRIM.
+    pub fn from_sd_csd(raw: &[u8; 16]) -> Self {
+        let group_blocks = if csd_bits(raw, 46, 46) == 1 {
+            1
+        } else {
+            (csd_bits(raw, 45, 39) + 1) * write_blocks(raw)
+        };
+        EraseConfig {
+            erase: true,
+            group_blocks,
+            ..EraseConfig::default()
+        }
+    }
+
+    /// What an eMMC before 4.3 erases, from its CSD: whole erase groups of
+    /// `ERASE_GRP_SIZE` × `ERASE_GRP_MULT` write blocks, nothing finer.
+    pub fn from_mmc_csd(raw: &[u8; 16]) -> Self {
+        let group = (csd_bits(raw, 46, 42) + 1) * (csd_bits(raw, 41, 37) + 1);
+        EraseConfig {
+            erase: true,
+            group_blocks: group * write_blocks(raw),
+            ..EraseConfig::default()
+        }
+    }
+
+    pub fn supports(&self, kind: EraseKind) -> bool {
+        match kind {
+            EraseKind::Discard => self.discard,
+            EraseKind::Trim => self.trim,
+            EraseKind::Erase => self.erase,
+            EraseKind::SecureErase => self.secure_erase,
+        }
+    }
+
+    /// Alignment of `kind` ranges in blocks.
+    pub fn granularity(&self, kind: EraseKind) -> usize {
+        match kind {
+            EraseKind::Discard | EraseKind::Trim => 1,
+            EraseKind::Erase | EraseKind::SecureErase => self.group_blocks,
+        }
+    }
+
+    /// Checks `blocks` blocks from `block_id` against the support and
+    /// alignment of `kind`.
+    pub fn check(&self, block_id: usize, blocks: usize, kind: EraseKind) -> Result<(), BlkError> {
+        if !self.supports(kind) {
+            return Err(BlkError::NotSupported);
+        }
+        let granularity = self.granularity(kind);
+        if blocks == 0
+            || !block_id.is_multiple_of(granularity)
+            || !blocks.is_multiple_of(granularity)
+        {
+            return Err(BlkError::InvalidBlockIndex(block_id));
+        }
+        Ok(())
+    }
+}
+
+/// Bits `hi..=lo` of a CSD, most significant byte first.
+fn csd_bits(raw: &[u8; 16], hi: u32, lo: u32) -> usize {
+    let csd = u128::from_be_bytes(*raw);
+    ((csd >> lo) & ((1 << (hi - lo + 1)) - 1)) as usize
+}
+
+/// `WRITE_BL_LEN` in 512-byte blocks.
+fn write_blocks(raw: &[u8; 16]) -> usize {
+    ((1 << csd_bits(raw, 25, 22)) / 512).max(1)
+}
+
+/// A queue that also takes erase requests.
+pub trait EraseQueue: IQueue {
+    fn erase_config(&self) -> EraseConfig;
+
+    /// Submits an erase of `blocks` blocks starting at `block_id`, ordered
+    /// with the reads and writes of the queue.
+    fn submit_erase(
+        &mut self,
+        block_id: usize,
+        blocks: usize,
+        kind: EraseKind,
+    ) -> Result<RequestId, BlkError>;
+}
+
+/// A block device whose queues take erase requests.
+///
+/// `rdif_block::Block` only forwards [`Interface`], so the board crates
+/// hand these out for their registered devices by rdrive device ID.
+pub trait EraseInterface: Interface {
+    /// Same as [`EraseQueue::erase_config`] of its queues, without
+    /// creating one.
+    fn erase_config(&self) -> EraseConfig;
+
+    fn create_erase_queue(&mut self) -> Option<Box<dyn EraseQueue>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A CSD with `fields` set, each `(hi, lo, value)`.
+    fn csd(fields: &[(u32, u32, u128)]) -> [u8; 16] {
+        let raw = fields
+            .iter()
+            .fold(0u128, |csd, &(_, lo, value)| csd | value << lo);
+        raw.to_be_bytes()
+    }
+
+    #[test]
+    fn sd_erase_blk_en() {
+        // SDHC：ERASE_BLK_EN 固定为 1，SECTOR_SIZE 为 0x7F
+        let raw = csd(&[(127, 126, 1), (46, 46, 1), (45, 39, 0x7F), (25, 22, 9)]);
+        let config = EraseConfig::from_sd_csd(&raw);
+        assert_eq!(config.group_blocks, 1);
+        assert!(config.erase);
+        assert!(!config.discard && !config.trim && !config.secure_erase);
+        assert!(config.check(3, 1, EraseKind::Erase).is_ok());
+    }
+
+    #[test]
+    fn sd_sector_units() {
+        // 32 个 1 KiB 写块为一个擦除单位
+        let raw = csd(&[(46, 46, 0), (45, 39, 31), (25, 22, 10)]);
+        let config = EraseConfig::from_sd_csd(&raw);
+        assert_eq!(config.group_blocks, 64);
+        assert!(config.erase);
+        assert!(!config.discard && !config.trim);
+        assert!(config.check(64, 128, EraseKind::Erase).is_ok());
+        assert!(config.check(32, 64, EraseKind::Erase).is_err());
+        assert!(config.check(0, 1, EraseKind::Trim).is_err());
+    }
+
+    #[test]
+    fn mmc_erase_groups() {
+        let raw = csd(&[(46, 42, 15), (41, 37, 3), (25, 22, 9)]);
+        let config = EraseConfig::from_mmc_csd(&raw);
+        assert_eq!(config.group_blocks, 64);
+        assert!(config.erase);
+        assert!(!config.discard && !config.trim && !config.secure_erase);
+    }
+}
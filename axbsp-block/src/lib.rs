@@ -0,0 +1,21 @@
+//! Block device helpers shared by the axbsp board crates.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod cache;
+pub mod card;
+pub mod erase;
+pub mod part;
+pub mod recovery;
+pub mod rpmb;
+
+pub use cache::{CacheConfig, FlushInterface, FlushQueue};
+pub use card::{CardHealth, CardIdentity, CardVersion, Cid, PreEol};
+pub use erase::{EraseConfig, EraseInterface, EraseKind, EraseQueue};
+pub use part::{
+    Guid, Layout, PartError, Partition, PartitionDevice, PartitionKind, Selector, layout,
+    read_partitions, select,
+};
+pub use recovery::{BusMode, BusSpeed, Recovery, RecoveryFailed, RecoveryHost};
@@ -0,0 +1,543 @@
+//! GPT/MBR partition tables.
+//!
+//! [`read_partitions`] parses the table through a caller supplied block
+//! reader, so it works on top of any host driver before the device is
+//! registered. [`PartitionDevice`] then exposes one partition as its own
+//! block device. [`layout`] does both for the `block-partition` setting of
+//! the board crates.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+use log::warn;
+
+mod device;
+mod gpt;
+mod mbr;
+
+pub use device::PartitionDevice;
+
+/// Logical block size the partition tables are laid out in.
+pub const BLOCK_SIZE: usize = 512;
+
+/// A GPT GUID, stored in its on-disk (mixed-endian) byte order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|&b| b == 0)
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        // 前三段为小端，后两段按字节顺序
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{byte:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionKind {
+    Gpt {
+        type_guid: Guid,
+        unique_guid: Guid,
+    },
+    Mbr {
+        os_type: u8,
+    },
+    /// A block range given by [`Selector::Offset`], not a table entry.
+    Raw,
+}
+
+/// One entry of a partition table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    /// 1-based position in the table; logical MBR partitions start at 5.
+    /// 0 for [`PartitionKind::Raw`].
+    pub index: usize,
+    /// GPT partition name, empty for MBR.
+    pub name: String,
+    pub kind: PartitionKind,
+    /// First block, in [`BLOCK_SIZE`] units.
+    pub start: u64,
+    /// Length in [`BLOCK_SIZE`] units.
+    pub blocks: u64,
+}
+
+impl Partition {
+    /// GPT partition type, `None` for MBR partitions.
+    pub fn type_guid(&self) -> Option<Guid> {
+        match self.kind {
+            PartitionKind::Gpt { type_guid, .. } => Some(type_guid),
+            PartitionKind::Mbr { .. } | PartitionKind::Raw => None,
+        }
+    }
+}
+
+impl fmt::Display for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "#{} [{:#x}, {:#x}) ",
+            self.index,
+            self.start,
+            self.start + self.blocks
+        )?;
+        match self.kind {
+            PartitionKind::Gpt { type_guid, .. } => write!(f, "\"{}\" {}", self.name, type_guid),
+            PartitionKind::Mbr { os_type } => write!(f, "type {os_type:#04x}"),
+            PartitionKind::Raw => write!(f, "raw"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PartError<E> {
+    /// The block reader failed.
+    Io(E),
+    /// No MBR signature on block 0.
+    NoTable,
+    /// A table was found but is inconsistent.
+    Corrupt(&'static str),
+    /// The selected partition is not in the table.
+    NotFound,
+    /// A [`Selector::Offset`] at or past the end of the disk.
+    Offset(u64),
+}
+
+impl<E: fmt::Debug> fmt::Display for PartError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartError::Io(err) => write!(f, "read failed: {err:?}"),
+            PartError::NoTable => write!(f, "no partition table"),
+            PartError::Corrupt(what) => write!(f, "corrupt partition table: {what}"),
+            PartError::NotFound => write!(f, "no such partition"),
+            PartError::Offset(start) => write!(f, "offset {start:#x} beyond end of disk"),
+        }
+    }
+}
+
+impl<E: fmt::Debug> core::error::Error for PartError<E> {}
+
+/// Reads the partition table of a disk with `num_blocks` blocks.
+///
+/// `read` fills its buffer, a whole number of [`BLOCK_SIZE`] blocks,
+/// starting at the given block. A protective MBR selects GPT, falling back
+/// to the backup header when the primary one is damaged.
+pub fn read_partitions<E>(
+    num_blocks: u64,
+    mut read: impl FnMut(u64, &mut [u8]) -> Result<(), E>,
+) -> Result<Vec<Partition>, PartError<E>> {
+    let mut sector = [0u8; BLOCK_SIZE];
+    read(0, &mut sector).map_err(PartError::Io)?;
+    let entries = mbr::primary_entries(&sector).ok_or(PartError::NoTable)?;
+
+    if entries.iter().any(|e| e.os_type == mbr::GPT_PROTECTIVE) {
+        return gpt::read(num_blocks, &mut read);
+    }
+    mbr::read(&entries, num_blocks, &mut read)
+}
+
+/// Picks the partition named by `selector`: a GPT partition name, or the
+/// 1-based partition index. An empty selector selects nothing.
+pub fn select<'a>(partitions: &'a [Partition], selector: &str) -> Option<&'a Partition> {
+    let selector = selector.trim();
+    if selector.is_empty() {
+        return None;
+    }
+    partitions
+        .iter()
+        .find(|p| !p.name.is_empty() && p.name == selector)
+        .or_else(|| {
+            let index = selector.parse::<usize>().ok()?;
+            partitions.iter().find(|p| p.index == index)
+        })
+}
+
+/// The device a `block-partition` setting selects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selector<'a> {
+    /// The whole device, from an empty setting.
+    Whole,
+    /// Everything from a block on, `@<block>` in decimal or `0x` hex. Keeps
+    /// layouts working that relied on a fixed offset instead of a table.
+    Offset(u64),
+    /// A table entry, see [`select`].
+    Entry(&'a str),
+}
+
+impl<'a> Selector<'a> {
+    pub fn parse(setting: &'a str) -> Self {
+        let setting = setting.trim();
+        if setting.is_empty() {
+            return Selector::Whole;
+        }
+        let offset = setting.strip_prefix('@').and_then(|block| {
+            match block
+                .strip_prefix("0x")
+                .or_else(|| block.strip_prefix("0X"))
+            {
+                Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                None => block.parse().ok(),
+            }
+        });
+        match offset {
+            Some(start) => Selector::Offset(start),
+            None => Selector::Entry(setting),
+        }
+    }
+}
+
+/// The partitions of a disk and the device a [`Selector`] picks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    /// Entries of the partition table, empty if the disk has none.
+    pub partitions: Vec<Partition>,
+    /// `None` for [`Selector::Whole`].
+    pub selected: Option<Partition>,
+}
+
+impl Layout {
+    /// Table entries other than the selected one, to be registered next to
+    /// it.
+    pub fn others(&self) -> impl Iterator<Item = &Partition> {
+        self.partitions
+            .iter()
+            .filter(|p| self.selected.as_ref() != Some(*p))
+    }
+}
+
+/// Reads the partition table like [`read_partitions`] and resolves
+/// `selector` against it.
+///
+/// A missing or unreadable table is only logged when the selector does not
+/// need one. A table entry that does not exist is an error rather than a
+/// fallback to the whole disk, so nothing gets written at the wrong place.
+pub fn layout<E: fmt::Debug>(
+    num_blocks: u64,
+    selector: Selector<'_>,
+    read: impl FnMut(u64, &mut [u8]) -> Result<(), E>,
+) -> Result<Layout, PartError<E>> {
+    let partitions = match read_partitions(num_blocks, read) {
+        Ok(partitions) => partitions,
+        Err(err) if !matches!(selector, Selector::Entry(_)) => {
+            warn!("{err}");
+            Vec::new()
+        }
+        Err(err) => return Err(err),
+    };
+
+    let selected = match selector {
+        Selector::Whole => None,
+        Selector::Offset(start) if start >= num_blocks => return Err(PartError::Offset(start)),
+        Selector::Offset(start) => Some(Partition {
+            index: 0,
+            name: String::new(),
+            kind: PartitionKind::Raw,
+            start,
+            blocks: num_blocks - start,
+        }),
+        Selector::Entry(name) => Some(
+            select(&partitions, name)
+                .cloned()
+                .ok_or(PartError::NotFound)?,
+        ),
+    };
+    Ok(Layout {
+        partitions,
+        selected,
+    })
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec, vec::Vec};
+
+    use super::*;
+
+    const DISK_BLOCKS: usize = 0x1000;
+    /// 128 entries of 128 bytes.
+    const ENTRY_BLOCKS: usize = 32;
+
+    const LINUX_FS: Guid = Guid([
+        0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D,
+        0xE4,
+    ]);
+
+    fn read_disk(disk: &[u8]) -> impl FnMut(u64, &mut [u8]) -> Result<(), ()> + '_ {
+        |lba, buf| {
+            let offset = lba as usize * BLOCK_SIZE;
+            let src = disk.get(offset..offset + buf.len()).ok_or(())?;
+            buf.copy_from_slice(src);
+            Ok(())
+        }
+    }
+
+    fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+        buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u64(buf: &mut [u8], offset: usize, value: u64) {
+        buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn mbr_entry(sector: &mut [u8], slot: usize, os_type: u8, start: u32, blocks: u32) {
+        let entry = &mut sector[446 + slot * 16..][..16];
+        entry[4] = os_type;
+        put_u32(entry, 8, start);
+        put_u32(entry, 12, blocks);
+        sector[510] = 0x55;
+        sector[511] = 0xAA;
+    }
+
+    fn block(disk: &mut [u8], lba: usize) -> &mut [u8] {
+        &mut disk[lba * BLOCK_SIZE..][..BLOCK_SIZE]
+    }
+
+    /// A disk with a protective MBR and primary and backup GPT holding
+    /// `(name, first block, last block)` entries.
+    fn gpt_disk(entries: &[(&str, u64, u64)]) -> Vec<u8> {
+        let mut disk = vec![0u8; DISK_BLOCKS * BLOCK_SIZE];
+        mbr_entry(block(&mut disk, 0), 0, 0xEE, 1, DISK_BLOCKS as u32 - 1);
+
+        let mut table = vec![0u8; ENTRY_BLOCKS * BLOCK_SIZE];
+        for (i, (name, first, last)) in entries.iter().enumerate() {
+            let raw = &mut table[i * 128..][..128];
+            raw[..16].copy_from_slice(&LINUX_FS.0);
+            raw[16] = i as u8 + 1;
+            put_u64(raw, 32, *first);
+            put_u64(raw, 40, *last);
+            for (j, unit) in name.encode_utf16().enumerate() {
+                raw[56 + j * 2..][..2].copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+
+        let last = DISK_BLOCKS - 1;
+        let backup_entries = last - ENTRY_BLOCKS;
+        for (lba, alternate, entries_lba) in [(1, last, 2), (last, 1, backup_entries)] {
+            disk[entries_lba * BLOCK_SIZE..][..table.len()].copy_from_slice(&table);
+
+            let header = block(&mut disk, lba);
+            header[..8].copy_from_slice(b"EFI PART");
+            put_u32(header, 8, 0x0001_0000);
+            put_u32(header, 12, 92);
+            put_u64(header, 24, lba as u64);
+            put_u64(header, 32, alternate as u64);
+            put_u64(header, 40, 2 + ENTRY_BLOCKS as u64);
+            put_u64(header, 48, (backup_entries - 1) as u64);
+            put_u64(header, 72, entries_lba as u64);
+            put_u32(header, 80, 128);
+            put_u32(header, 84, 128);
+            put_u32(header, 88, gpt::crc32(&table));
+            let crc = gpt::crc32(&header[..92]);
+            put_u32(header, 16, crc);
+        }
+        disk
+    }
+
+    fn two_partitions() -> Vec<u8> {
+        gpt_disk(&[("boot", 0x40, 0x7F), ("rootfs", 0x80, 0xFDE)])
+    }
+
+    fn check_two_partitions(partitions: &[Partition]) {
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[0].index, 1);
+        assert_eq!(partitions[0].name, "boot");
+        assert_eq!(partitions[0].start, 0x40);
+        assert_eq!(partitions[0].blocks, 0x40);
+        assert_eq!(partitions[1].index, 2);
+        assert_eq!(partitions[1].name, "rootfs");
+        assert_eq!(partitions[1].type_guid(), Some(LINUX_FS));
+        assert_eq!(
+            partitions[1].to_string(),
+            "#2 [0x80, 0xfdf) \"rootfs\" 0FC63DAF-8483-4772-8E79-3D69D8477DE4"
+        );
+    }
+
+    #[test]
+    fn gpt_entries() {
+        let disk = two_partitions();
+        let partitions = read_partitions(DISK_BLOCKS as u64, read_disk(&disk)).unwrap();
+        check_two_partitions(&partitions);
+    }
+
+    #[test]
+    fn gpt_corrupt_primary_header_uses_backup() {
+        let mut disk = two_partitions();
+        block(&mut disk, 1)[40] ^= 1;
+        let partitions = read_partitions(DISK_BLOCKS as u64, read_disk(&disk)).unwrap();
+        check_two_partitions(&partitions);
+    }
+
+    #[test]
+    fn gpt_corrupt_primary_entries_use_backup() {
+        let mut disk = two_partitions();
+        block(&mut disk, 2)[32] ^= 1;
+        let partitions = read_partitions(DISK_BLOCKS as u64, read_disk(&disk)).unwrap();
+        check_two_partitions(&partitions);
+    }
+
+    #[test]
+    fn gpt_both_headers_corrupt() {
+        let mut disk = two_partitions();
+        block(&mut disk, 1)[0] = 0;
+        block(&mut disk, DISK_BLOCKS - 1)[40] ^= 1;
+        let err = read_partitions(DISK_BLOCKS as u64, read_disk(&disk)).unwrap_err();
+        assert!(matches!(err, PartError::Corrupt("header CRC mismatch")));
+    }
+
+    #[test]
+    fn gpt_entry_outside_usable_range() {
+        let disk = gpt_disk(&[("data", 0x40, DISK_BLOCKS as u64)]);
+        let err = read_partitions(DISK_BLOCKS as u64, read_disk(&disk)).unwrap_err();
+        assert!(matches!(
+            err,
+            PartError::Corrupt("partition outside usable range")
+        ));
+    }
+
+    #[test]
+    fn mbr_with_ebr_chain() {
+        let mut disk = vec![0u8; DISK_BLOCKS * BLOCK_SIZE];
+        mbr_entry(block(&mut disk, 0), 0, 0x83, 0x100, 0x100);
+        mbr_entry(block(&mut disk, 0), 1, 0x0F, 0x800, 0x800);
+        // 逻辑分区相对所在 EBR，下一个 EBR 相对扩展分区起始
+        mbr_entry(block(&mut disk, 0x800), 0, 0x83, 0x10, 0x100);
+        mbr_entry(block(&mut disk, 0x800), 1, 0x05, 0x200, 0x200);
+        mbr_entry(block(&mut disk, 0xA00), 0, 0x07, 0x10, 0x80);
+
+        let partitions = read_partitions(DISK_BLOCKS as u64, read_disk(&disk)).unwrap();
+        let bounds: Vec<_> = partitions
+            .iter()
+            .map(|p| (p.index, p.kind, p.start, p.blocks))
+            .collect();
+        assert_eq!(
+            bounds,
+            [
+                (1, PartitionKind::Mbr { os_type: 0x83 }, 0x100, 0x100),
+                (5, PartitionKind::Mbr { os_type: 0x83 }, 0x810, 0x100),
+                (6, PartitionKind::Mbr { os_type: 0x07 }, 0xA10, 0x80),
+            ]
+        );
+        assert!(partitions.iter().all(|p| p.type_guid().is_none()));
+    }
+
+    #[test]
+    fn ebr_loop_is_bounded() {
+        let mut disk = vec![0u8; DISK_BLOCKS * BLOCK_SIZE];
+        mbr_entry(block(&mut disk, 0), 0, 0x05, 0x800, 0x800);
+        mbr_entry(block(&mut disk, 0x800), 0, 0x83, 0x10, 0x10);
+        mbr_entry(block(&mut disk, 0x800), 1, 0x05, 0, 0x800);
+
+        let err = read_partitions(DISK_BLOCKS as u64, read_disk(&disk)).unwrap_err();
+        assert!(matches!(
+            err,
+            PartError::Corrupt("too many logical partitions")
+        ));
+    }
+
+    #[test]
+    fn mbr_partition_beyond_end() {
+        let mut disk = vec![0u8; DISK_BLOCKS * BLOCK_SIZE];
+        mbr_entry(block(&mut disk, 0), 0, 0x83, 0x800, DISK_BLOCKS as u32);
+        let err = read_partitions(DISK_BLOCKS as u64, read_disk(&disk)).unwrap_err();
+        assert!(matches!(
+            err,
+            PartError::Corrupt("partition beyond end of disk")
+        ));
+    }
+
+    #[test]
+    fn no_table() {
+        let disk = vec![0u8; DISK_BLOCKS * BLOCK_SIZE];
+        let err = read_partitions(DISK_BLOCKS as u64, read_disk(&disk)).unwrap_err();
+        assert!(matches!(err, PartError::NoTable));
+    }
+
+    #[test]
+    fn selector_parse() {
+        assert_eq!(Selector::parse(""), Selector::Whole);
+        assert_eq!(Selector::parse(" @0x7A000 "), Selector::Offset(0x7A000));
+        assert_eq!(Selector::parse("@4096"), Selector::Offset(4096));
+        assert_eq!(Selector::parse("rootfs"), Selector::Entry("rootfs"));
+        assert_eq!(Selector::parse("@root"), Selector::Entry("@root"));
+    }
+
+    #[test]
+    fn layout_selects_entries() {
+        let disk = two_partitions();
+        let num_blocks = DISK_BLOCKS as u64;
+
+        let layout = layout(num_blocks, Selector::Entry("rootfs"), read_disk(&disk)).unwrap();
+        check_two_partitions(&layout.partitions);
+        assert_eq!(layout.selected.as_ref(), Some(&layout.partitions[1]));
+        let others: Vec<_> = layout.others().map(|p| p.index).collect();
+        assert_eq!(others, [1]);
+
+        let by_index = super::layout(num_blocks, Selector::Entry("1"), read_disk(&disk)).unwrap();
+        assert_eq!(by_index.selected.unwrap().name, "boot");
+
+        let whole = super::layout(num_blocks, Selector::Whole, read_disk(&disk)).unwrap();
+        assert_eq!(whole.selected, None);
+        assert_eq!(whole.others().count(), 2);
+    }
+
+    #[test]
+    fn layout_missing_entry_fails() {
+        let disk = two_partitions();
+        let err = layout(
+            DISK_BLOCKS as u64,
+            Selector::Entry("home"),
+            read_disk(&disk),
+        )
+        .unwrap_err();
+        assert!(matches!(err, PartError::NotFound));
+
+        // 没有分区表时也不能退回整个设备
+        let blank = vec![0u8; DISK_BLOCKS * BLOCK_SIZE];
+        let err = layout(
+            DISK_BLOCKS as u64,
+            Selector::Entry("rootfs"),
+            read_disk(&blank),
+        )
+        .unwrap_err();
+        assert!(matches!(err, PartError::NoTable));
+    }
+
+    #[test]
+    fn layout_offset_without_table() {
+        let blank = vec![0u8; DISK_BLOCKS * BLOCK_SIZE];
+        let num_blocks = DISK_BLOCKS as u64;
+
+        let layout = layout(num_blocks, Selector::Offset(0x800), read_disk(&blank)).unwrap();
+        assert!(layout.partitions.is_empty());
+        let selected = layout.selected.unwrap();
+        assert_eq!(selected.kind, PartitionKind::Raw);
+        assert_eq!((selected.start, selected.blocks), (0x800, 0x800));
+
+        let err =
+            super::layout(num_blocks, Selector::Offset(num_blocks), read_disk(&blank)).unwrap_err();
+        assert!(matches!(err, PartError::Offset(_)));
+    }
+}
@@ -0,0 +1,211 @@
+use alloc::{boxed::Box, sync::Arc};
+use log::warn;
+use rdif_block::{
+    BlkError, BuffConfig, DriverGeneric, Event, IQueue, Interface, KError, Request, RequestId,
+    RequestKind,
+};
+use spin::Mutex;
+
+use super::{BLOCK_SIZE, Partition};
+use crate::cache::{CacheConfig, FlushInterface, FlushQueue};
+use crate::erase::{EraseConfig, EraseInterface, EraseKind, EraseQueue};
+
+/// One partition of a disk, exposed as a block device of its own.
+///
+/// Block 0 of its queues is the partition's first block and requests past
+/// its end fail with [`BlkError::InvalidBlockIndex`]. Several partitions
+/// may share the same disk, and clones are further devices on the same
+/// partition.
+pub struct PartitionDevice<T> {
+    disk: Arc<Mutex<T>>,
+    partition: Partition,
+}
+
+impl<T> Clone for PartitionDevice<T> {
+    fn clone(&self) -> Self {
+        PartitionDevice {
+            disk: Arc::clone(&self.disk),
+            partition: self.partition.clone(),
+        }
+    }
+}
+
+impl<T: Interface> PartitionDevice<T> {
+    pub fn new(disk: Arc<Mutex<T>>, partition: Partition) -> Self {
+        PartitionDevice { disk, partition }
+    }
+
+    pub fn partition(&self) -> &Partition {
+        &self.partition
+    }
+
+    pub fn disk(&self) -> &Arc<Mutex<T>> {
+        &self.disk
+    }
+}
+
+impl<T: Interface> DriverGeneric for PartitionDevice<T> {
+    fn open(&mut self) -> Result<(), KError> {
+        self.disk.lock().open()
+    }
+
+    fn close(&mut self) -> Result<(), KError> {
+        self.disk.lock().close()
+    }
+}
+
+impl<T: Interface> PartitionDevice<T> {
+    fn wrap<Q: IQueue + ?Sized>(&self, inner: Box<Q>) -> Option<PartitionQueue<Q>> {
+        // 分区表以 512 字节为单位
+        if inner.block_size() != BLOCK_SIZE {
+            warn!(
+                "partition {}: unsupported block size {}",
+                self.partition.index,
+                inner.block_size()
+            );
+            return None;
+        }
+        Some(PartitionQueue {
+            inner,
+            start: self.partition.start as usize,
+            blocks: self.partition.blocks as usize,
+        })
+    }
+}
+
+impl<T: Interface> Interface for PartitionDevice<T> {
+    fn create_queue(&mut self) -> Option<Box<dyn IQueue>> {
+        let inner = self.disk.lock().create_queue()?;
+        Some(Box::new(self.wrap(inner)?))
+    }
+
+    fn enable_irq(&mut self) {
+        self.disk.lock().enable_irq();
+    }
+
+    fn disable_irq(&mut self) {
+        self.disk.lock().disable_irq();
+    }
+
+    fn is_irq_enabled(&self) -> bool {
+        self.disk.lock().is_irq_enabled()
+    }
+
+    fn handle_irq(&mut self) -> Event {
+        self.disk.lock().handle_irq()
+    }
+}
+
+impl<T: EraseInterface> EraseInterface for PartitionDevice<T> {
+    fn erase_config(&self) -> EraseConfig {
+        let config = self.disk.lock().erase_config();
+        within(config, self.partition.start as usize)
+    }
+
+    fn create_erase_queue(&mut self) -> Option<Box<dyn EraseQueue>> {
+        let inner = self.disk.lock().create_erase_queue()?;
+        Some(Box::new(self.wrap(inner)?))
+    }
+}
+
+impl<T: FlushInterface> FlushInterface for PartitionDevice<T> {
+    fn cache_config(&self) -> CacheConfig {
+        self.disk.lock().cache_config()
+    }
+
+    fn create_flush_queue(&mut self) -> Option<Box<dyn FlushQueue>> {
+        let inner = self.disk.lock().create_flush_queue()?;
+        Some(Box::new(self.wrap(inner)?))
+    }
+}
+
+struct PartitionQueue<Q: ?Sized> {
+    inner: Box<Q>,
+    start: usize,
+    blocks: usize,
+}
+
+impl<Q: IQueue + ?Sized> PartitionQueue<Q> {
+    fn check_range(&self, block_id: usize, blocks: usize) -> Result<(), BlkError> {
+        let end = block_id.checked_add(blocks);
+        if end.is_none_or(|end| end > self.blocks) {
+            return Err(BlkError::InvalidBlockIndex(block_id));
+        }
+        Ok(())
+    }
+}
+
+impl<Q: IQueue + ?Sized> IQueue for PartitionQueue<Q> {
+    fn id(&self) -> usize {
+        self.inner.id()
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.blocks
+    }
+
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn buff_config(&self) -> BuffConfig {
+        self.inner.buff_config()
+    }
+
+    fn submit_request(&mut self, mut request: Request<'_>) -> Result<RequestId, BlkError> {
+        let len = match &request.kind {
+            RequestKind::Read(buffer) => buffer.len(),
+            RequestKind::Write(buffer) => buffer.len(),
+        };
+        self.check_range(request.block_id, len.div_ceil(BLOCK_SIZE))?;
+
+        request.block_id += self.start;
+        self.inner.submit_request(request)
+    }
+
+    fn poll_request(&mut self, request: RequestId) -> Result<(), BlkError> {
+        self.inner.poll_request(request)
+    }
+}
+
+impl EraseQueue for PartitionQueue<dyn EraseQueue> {
+    fn erase_config(&self) -> EraseConfig {
+        within(self.inner.erase_config(), self.start)
+    }
+
+    fn submit_erase(
+        &mut self,
+        block_id: usize,
+        blocks: usize,
+        kind: EraseKind,
+    ) -> Result<RequestId, BlkError> {
+        self.check_range(block_id, blocks)?;
+        self.erase_config().check(block_id, blocks, kind)?;
+        self.inner.submit_erase(block_id + self.start, blocks, kind)
+    }
+}
+
+/// The disk's `config` for a partition starting at block `start`.
+fn within(mut config: EraseConfig, start: usize) -> EraseConfig {
+    // 分区起点不在擦除组边界上时，分区内无法按组对齐
+    if !start.is_multiple_of(config.group_blocks) {
+        config.erase = false;
+        config.secure_erase = false;
+    }
+    config
+}
+
+impl FlushQueue for PartitionQueue<dyn FlushQueue> {
+    fn cache_config(&self) -> CacheConfig {
+        self.inner.cache_config()
+    }
+
+    fn submit_flush(&mut self) -> Result<RequestId, BlkError> {
+        self.inner.submit_flush()
+    }
+
+    fn submit_write_fua(&mut self, block_id: usize, buffer: &[u8]) -> Result<RequestId, BlkError> {
+        self.check_range(block_id, buffer.len().div_ceil(BLOCK_SIZE))?;
+        self.inner.submit_write_fua(block_id + self.start, buffer)
+    }
+}
@@ -0,0 +1,145 @@
+use alloc::{string::String, vec, vec::Vec};
+use log::warn;
+
+use super::{BLOCK_SIZE, Guid, PartError, Partition, PartitionKind, le_u32, le_u64};
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const MIN_HEADER_SIZE: usize = 92;
+const MIN_ENTRY_SIZE: usize = 128;
+/// 条目数组的上限，避免损坏的头部导致巨大的分配
+const MAX_TABLE_BYTES: usize = 1 << 20;
+
+struct Header {
+    first_usable: u64,
+    last_usable: u64,
+    entries_lba: u64,
+    num_entries: usize,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+pub(super) fn read<E>(
+    num_blocks: u64,
+    read: &mut impl FnMut(u64, &mut [u8]) -> Result<(), E>,
+) -> Result<Vec<Partition>, PartError<E>> {
+    match read_at(1, num_blocks, read) {
+        Err(PartError::Corrupt(why)) if num_blocks > 1 => {
+            warn!("primary GPT invalid ({why}), trying backup");
+            read_at(num_blocks - 1, num_blocks, read)
+        }
+        res => res,
+    }
+}
+
+fn read_at<E>(
+    lba: u64,
+    num_blocks: u64,
+    read: &mut impl FnMut(u64, &mut [u8]) -> Result<(), E>,
+) -> Result<Vec<Partition>, PartError<E>> {
+    let mut sector = [0u8; BLOCK_SIZE];
+    read(lba, &mut sector).map_err(PartError::Io)?;
+    let header = parse_header(&mut sector, lba)?;
+
+    if header.last_usable >= num_blocks || header.first_usable > header.last_usable {
+        return Err(PartError::Corrupt("usable range beyond end of disk"));
+    }
+
+    let table_bytes = header.num_entries * header.entry_size;
+    let mut table = vec![0u8; table_bytes.div_ceil(BLOCK_SIZE) * BLOCK_SIZE];
+    read(header.entries_lba, &mut table).map_err(PartError::Io)?;
+    if crc32(&table[..table_bytes]) != header.entries_crc {
+        return Err(PartError::Corrupt("entry array CRC mismatch"));
+    }
+
+    let mut partitions = Vec::new();
+    for (i, raw) in table[..table_bytes]
+        .chunks_exact(header.entry_size)
+        .enumerate()
+    {
+        let type_guid = guid(&raw[0..16]);
+        if type_guid.is_zero() {
+            continue;
+        }
+        let start = le_u64(raw, 32);
+        let end = le_u64(raw, 40);
+        if start < header.first_usable || end > header.last_usable || start > end {
+            return Err(PartError::Corrupt("partition outside usable range"));
+        }
+        partitions.push(Partition {
+            index: i + 1,
+            name: name(&raw[56..128]),
+            kind: PartitionKind::Gpt {
+                type_guid,
+                unique_guid: guid(&raw[16..32]),
+            },
+            start,
+            blocks: end - start + 1,
+        });
+    }
+
+    Ok(partitions)
+}
+
+fn parse_header<E>(sector: &mut [u8; BLOCK_SIZE], lba: u64) -> Result<Header, PartError<E>> {
+    if &sector[..8] != SIGNATURE {
+        return Err(PartError::Corrupt("missing GPT signature"));
+    }
+    let size = le_u32(sector, 12) as usize;
+    if !(MIN_HEADER_SIZE..=BLOCK_SIZE).contains(&size) {
+        return Err(PartError::Corrupt("bad GPT header size"));
+    }
+    // 头部 CRC 计算时该字段按 0 处理
+    let crc = le_u32(sector, 16);
+    sector[16..20].fill(0);
+    if crc32(&sector[..size]) != crc {
+        return Err(PartError::Corrupt("header CRC mismatch"));
+    }
+    if le_u64(sector, 24) != lba {
+        return Err(PartError::Corrupt("header at unexpected LBA"));
+    }
+
+    let num_entries = le_u32(sector, 80) as usize;
+    let entry_size = le_u32(sector, 84) as usize;
+    if entry_size < MIN_ENTRY_SIZE
+        || !entry_size.is_multiple_of(8)
+        || num_entries.saturating_mul(entry_size) > MAX_TABLE_BYTES
+    {
+        return Err(PartError::Corrupt("bad entry array geometry"));
+    }
+
+    Ok(Header {
+        first_usable: le_u64(sector, 40),
+        last_usable: le_u64(sector, 48),
+        entries_lba: le_u64(sector, 72),
+        num_entries,
+        entry_size,
+        entries_crc: le_u32(sector, 88),
+    })
+}
+
+fn guid(raw: &[u8]) -> Guid {
+    Guid(raw.try_into().unwrap())
+}
+
+/// Decodes the UTF-16LE, NUL padded partition name.
+fn name(raw: &[u8]) -> String {
+    let units = raw
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0);
+    char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// CRC-32 (IEEE 802.3), as used by the GPT header and entry array.
+pub(super) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
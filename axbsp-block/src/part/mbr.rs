@@ -0,0 +1,117 @@
+use alloc::{string::String, vec::Vec};
+
+use super::{BLOCK_SIZE, PartError, Partition, PartitionKind, le_u32};
+
+pub(super) const GPT_PROTECTIVE: u8 = 0xEE;
+
+/// CHS, LBA and Linux extended partition types.
+const EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+
+const TABLE_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+
+/// 防止损坏的 EBR 链形成环
+const MAX_LOGICAL: usize = 128;
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Entry {
+    pub os_type: u8,
+    pub start: u64,
+    pub blocks: u64,
+}
+
+/// Parses the four entries of an MBR or EBR, `None` without the `55 AA`
+/// signature.
+pub(super) fn primary_entries(sector: &[u8; BLOCK_SIZE]) -> Option<[Entry; 4]> {
+    if sector[510] != 0x55 || sector[511] != 0xAA {
+        return None;
+    }
+    Some(core::array::from_fn(|i| {
+        let raw = &sector[TABLE_OFFSET + i * ENTRY_SIZE..][..ENTRY_SIZE];
+        Entry {
+            os_type: raw[4],
+            start: le_u32(raw, 8) as u64,
+            blocks: le_u32(raw, 12) as u64,
+        }
+    }))
+}
+
+pub(super) fn read<E>(
+    entries: &[Entry; 4],
+    num_blocks: u64,
+    read: &mut impl FnMut(u64, &mut [u8]) -> Result<(), E>,
+) -> Result<Vec<Partition>, PartError<E>> {
+    let mut partitions = Vec::new();
+
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.os_type == 0 || entry.blocks == 0 {
+            continue;
+        }
+        if EXTENDED.contains(&entry.os_type) {
+            read_logical(entry.start, num_blocks, read, &mut partitions)?;
+            continue;
+        }
+        partitions.push(partition(
+            i + 1,
+            entry.os_type,
+            entry.start,
+            entry.blocks,
+            num_blocks,
+        )?);
+    }
+
+    Ok(partitions)
+}
+
+/// Walks the EBR chain of the extended partition starting at `base`.
+fn read_logical<E>(
+    base: u64,
+    num_blocks: u64,
+    read: &mut impl FnMut(u64, &mut [u8]) -> Result<(), E>,
+    partitions: &mut Vec<Partition>,
+) -> Result<(), PartError<E>> {
+    let mut ebr = base;
+    let mut sector = [0u8; BLOCK_SIZE];
+
+    for index in 5..5 + MAX_LOGICAL {
+        read(ebr, &mut sector).map_err(PartError::Io)?;
+        let [logical, next, ..] =
+            primary_entries(&sector).ok_or(PartError::Corrupt("bad EBR signature"))?;
+
+        // 逻辑分区相对当前 EBR，下一个 EBR 相对扩展分区起始
+        if logical.os_type != 0 && logical.blocks != 0 {
+            partitions.push(partition(
+                index,
+                logical.os_type,
+                ebr + logical.start,
+                logical.blocks,
+                num_blocks,
+            )?);
+        }
+        if next.os_type == 0 {
+            return Ok(());
+        }
+        ebr = base + next.start;
+    }
+
+    Err(PartError::Corrupt("too many logical partitions"))
+}
+
+fn partition<E>(
+    index: usize,
+    os_type: u8,
+    start: u64,
+    blocks: u64,
+    num_blocks: u64,
+) -> Result<Partition, PartError<E>> {
+    if start + blocks > num_blocks {
+        return Err(PartError::Corrupt("partition beyond end of disk"));
+    }
+    Ok(Partition {
+        index,
+        name: String::new(),
+        kind: PartitionKind::Mbr { os_type },
+        start,
+        blocks,
+    })
+}
@@ -0,0 +1,308 @@
+//! Recovery from CRC and timeout errors of SD/MMC transfers.
+//!
+//! After such an error [`Recovery::recover`] aborts the transfer, resets the
+//! host's CMD and DATA lines and checks that the card still answers. Errors
+//! that keep coming step the bus down to slower [`BusMode`]s, and once the
+//! slowest one fails too the request is failed instead of retried.
+
+use core::fmt;
+
+use log::{debug, warn};
+
+/// Failures in a row before the bus is slowed down.
+const FAILURES_PER_MODE: u32 = 3;
+
+/// Bus timings, slowest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BusSpeed {
+    /// Default speed: 25 MHz for SD, 26 MHz for MMC.
+    Legacy,
+    /// SD high speed or UHS SDR25; on MMC, high speed timing at 26 MHz.
+    Sdr25,
+    /// MMC high speed at 52 MHz, or SD UHS SDR50.
+    Hs,
+    /// MMC DDR52, or SD UHS DDR50.
+    Ddr52,
+    /// MMC HS200, or SD UHS SDR104.
+    Hs200,
+    /// MMC HS400, with or without enhanced strobe.
+    Hs400,
+}
+
+/// Timing and data width of an SD/MMC bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusMode {
+    pub speed: BusSpeed,
+    /// 1, 4 or 8 data lines.
+    pub width: u8,
+}
+
+impl BusMode {
+    /// The next mode to fall back to: the timing steps down HS400 → HS200
+    /// → HS → SDR25 → legacy first, then the width 8 → 4 → 1 bits. `None`
+    /// at 1-bit legacy.
+    pub fn slower(self) -> Option<BusMode> {
+        let speed = match self.speed {
+            BusSpeed::Hs400 => BusSpeed::Hs200,
+            BusSpeed::Hs200 | BusSpeed::Ddr52 => BusSpeed::Hs,
+            BusSpeed::Hs => BusSpeed::Sdr25,
+            BusSpeed::Sdr25 => BusSpeed::Legacy,
+            BusSpeed::Legacy => {
+                let width = match self.width {
+                    8 => 4,
+                    4 => 1,
+                    _ => return None,
+                };
+                return Some(BusMode { width, ..self });
+            }
+        };
+        Some(BusMode { speed, ..self })
+    }
+}
+
+impl fmt::Display for BusMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let speed = match self.speed {
+            BusSpeed::Legacy => "legacy",
+            BusSpeed::Sdr25 => "SDR25",
+            BusSpeed::Hs => "HS",
+            BusSpeed::Ddr52 => "DDR52",
+            BusSpeed::Hs200 => "HS200",
+            BusSpeed::Hs400 => "HS400",
+        };
+        write!(f, "{} {}-bit", speed, self.width)
+    }
+}
+
+/// The recovery steps, implemented by each host controller driver.
+///
+/// Called with the host locked and no transfer in flight.
+pub trait RecoveryHost {
+    type Error: fmt::Debug;
+
+    /// Sends `STOP_TRANSMISSION` (CMD12), in case the card is still in a
+    /// data transfer.
+    fn abort(&mut self) -> Result<(), Self::Error>;
+
+    /// Software reset of the host's CMD and DATA lines.
+    fn reset_lines(&mut self);
+
+    /// Sends `SEND_STATUS` (CMD13) and returns the card status.
+    fn send_status(&mut self) -> Result<u32, Self::Error>;
+
+    /// Switches the card and the host to `mode`, one [`BusMode::slower`]
+    /// step below the current one.
+    fn set_bus_mode(&mut self, mode: BusMode) -> Result<(), Self::Error>;
+}
+
+/// Transfers kept failing at the slowest bus mode.
+#[derive(Debug, Clone, Copy)]
+pub struct RecoveryFailed {
+    pub mode: BusMode,
+}
+
+impl fmt::Display for RecoveryFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Transfers keep failing at {}", self.mode)
+    }
+}
+
+impl core::error::Error for RecoveryFailed {}
+
+/// Recovery state of one card, shared by all of its queues.
+///
+/// Failures are counted until a transfer succeeds, so a request that is
+/// resubmitted after [`rdif_block::BlkError::Retry`] keeps its count.
+#[derive(Debug)]
+pub struct Recovery {
+    /// Device name for the log.
+    name: &'static str,
+    mode: BusMode,
+    /// 当前模式下连续失败的次数
+    failures: u32,
+}
+
+impl Recovery {
+    /// Starts out at `mode`, the one the card was initialized in.
+    pub fn new(name: &'static str, mode: BusMode) -> Self {
+        Recovery {
+            name,
+            mode,
+            failures: 0,
+        }
+    }
+
+    pub fn mode(&self) -> BusMode {
+        self.mode
+    }
+
+    /// Records a successful transfer.
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Records a transfer that failed with a CRC or timeout error and
+    /// brings the bus back to a usable state, slowing it down after
+    /// repeated failures. `Ok` means the request should be retried.
+    pub fn recover<H: RecoveryHost>(&mut self, host: &mut H) -> Result<(), RecoveryFailed> {
+        self.failures += 1;
+
+        // 卡可能已经回到 transfer 状态，此时 CMD12 失败无关紧要
+        if let Err(err) = host.abort() {
+            debug!("{}: CMD12: {:?}", self.name, err);
+        }
+        host.reset_lines();
+        match host.send_status() {
+            Ok(status) => debug!("{}: card status {:#x} after reset", self.name, status),
+            Err(err) => warn!("{}: card does not answer CMD13: {:?}", self.name, err),
+        }
+
+        if self.failures < FAILURES_PER_MODE {
+            return Ok(());
+        }
+        self.failures = 0;
+
+        let Some(mode) = self.mode.slower() else {
+            warn!("{}: transfers keep failing at {}", self.name, self.mode);
+            return Err(RecoveryFailed { mode: self.mode });
+        };
+        warn!(
+            "{}: {} failures in a row, bus {} -> {}",
+            self.name, FAILURES_PER_MODE, self.mode, mode
+        );
+        // 切换失败时卡和主机的状态不确定，下次继续往下降
+        if let Err(err) = host.set_bus_mode(mode) {
+            warn!("{}: switching to {} failed: {:?}", self.name, mode, err);
+        }
+        self.mode = mode;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// Records what [`Recovery`] asks of the host.
+    #[derive(Default)]
+    struct FakeHost {
+        aborts: u32,
+        resets: u32,
+        status_reads: u32,
+        modes: Vec<BusMode>,
+        /// Makes `set_bus_mode` fail.
+        switch_fails: bool,
+    }
+
+    impl RecoveryHost for FakeHost {
+        type Error = ();
+
+        fn abort(&mut self) -> Result<(), ()> {
+            self.aborts += 1;
+            // 卡已回到 transfer 状态时 CMD12 会失败
+            Err(())
+        }
+
+        fn reset_lines(&mut self) {
+            self.resets += 1;
+        }
+
+        fn send_status(&mut self) -> Result<u32, ()> {
+            self.status_reads += 1;
+            Ok(0x900)
+        }
+
+        fn set_bus_mode(&mut self, mode: BusMode) -> Result<(), ()> {
+            self.modes.push(mode);
+            if self.switch_fails { Err(()) } else { Ok(()) }
+        }
+    }
+
+    const HS400: BusMode = BusMode {
+        speed: BusSpeed::Hs400,
+        width: 8,
+    };
+
+    fn mode(speed: BusSpeed, width: u8) -> BusMode {
+        BusMode { speed, width }
+    }
+
+    #[test]
+    fn every_failure_aborts_and_resets() {
+        let mut host = FakeHost::default();
+        let mut recovery = Recovery::new("test", HS400);
+
+        for _ in 1..FAILURES_PER_MODE {
+            assert!(recovery.recover(&mut host).is_ok());
+        }
+        assert_eq!(host.aborts, FAILURES_PER_MODE - 1);
+        assert_eq!(host.resets, FAILURES_PER_MODE - 1);
+        assert_eq!(host.status_reads, FAILURES_PER_MODE - 1);
+        assert!(host.modes.is_empty());
+        assert_eq!(recovery.mode(), HS400);
+    }
+
+    #[test]
+    fn success_clears_the_count() {
+        let mut host = FakeHost::default();
+        let mut recovery = Recovery::new("test", HS400);
+
+        for _ in 0..3 {
+            for _ in 1..FAILURES_PER_MODE {
+                recovery.recover(&mut host).unwrap();
+            }
+            recovery.succeeded();
+        }
+        assert!(host.modes.is_empty());
+        assert_eq!(recovery.mode(), HS400);
+    }
+
+    #[test]
+    fn steps_down_timing_then_width_then_fails() {
+        let mut host = FakeHost::default();
+        let mut recovery = Recovery::new("test", HS400);
+
+        let expected = [
+            mode(BusSpeed::Hs200, 8),
+            mode(BusSpeed::Hs, 8),
+            mode(BusSpeed::Sdr25, 8),
+            mode(BusSpeed::Legacy, 8),
+            mode(BusSpeed::Legacy, 4),
+            mode(BusSpeed::Legacy, 1),
+        ];
+        for (step, &next) in expected.iter().enumerate() {
+            for _ in 0..FAILURES_PER_MODE {
+                assert!(recovery.recover(&mut host).is_ok(), "step {}", step);
+            }
+            assert_eq!(recovery.mode(), next);
+        }
+        assert_eq!(host.modes, expected);
+
+        for _ in 1..FAILURES_PER_MODE {
+            assert!(recovery.recover(&mut host).is_ok());
+        }
+        let failed = recovery.recover(&mut host).unwrap_err();
+        assert_eq!(failed.mode, mode(BusSpeed::Legacy, 1));
+        assert_eq!(host.modes.len(), expected.len());
+    }
+
+    #[test]
+    fn failed_switch_still_steps_down() {
+        let mut host = FakeHost {
+            switch_fails: true,
+            ..Default::default()
+        };
+        let mut recovery = Recovery::new("test", mode(BusSpeed::Ddr52, 8));
+
+        for _ in 0..FAILURES_PER_MODE * 2 {
+            recovery.recover(&mut host).unwrap();
+        }
+        assert_eq!(
+            host.modes,
+            [mode(BusSpeed::Hs, 8), mode(BusSpeed::Sdr25, 8)]
+        );
+        assert_eq!(recovery.mode(), mode(BusSpeed::Sdr25, 8));
+    }
+}
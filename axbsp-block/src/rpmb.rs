@@ -0,0 +1,845 @@
+//! eMMC RPMB (replay protected memory block) frames and the JEDEC request
+//! sequences.
+//!
+//! [`Rpmb`] builds and checks the 512-byte data frames; moving them to and
+//! from the RPMB partition is left to an [`RpmbTransport`] of the host
+//! driver, and the MAC to an [`Hmac`] implementation, so the protocol runs
+//! on the host against recorded frames and known HMAC vectors as well.
+
+use alloc::{vec, vec::Vec};
+use core::fmt;
+
+/// Size of one data frame, sent and received as one 512-byte block.
+pub const FRAME_SIZE: usize = 512;
+/// Data carried by one frame. RPMB addresses count these half sectors.
+pub const DATA_SIZE: usize = 256;
+pub const KEY_SIZE: usize = 32;
+pub const MAC_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 16;
+
+const MAC_OFFSET: usize = 196;
+const DATA_OFFSET: usize = 228;
+const NONCE_OFFSET: usize = 484;
+const COUNTER_OFFSET: usize = 500;
+const ADDRESS_OFFSET: usize = 504;
+const BLOCK_COUNT_OFFSET: usize = 506;
+const RESULT_OFFSET: usize = 508;
+const TYPE_OFFSET: usize = 510;
+
+/// 结果码的 bit 7 表示写计数器已到上限
+const RESULT_COUNTER_EXPIRED: u16 = 0x80;
+
+/// Request types of the `Req/Resp` field; responses carry the request type
+/// in the high byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum RequestType {
+    ProgramKey = 1,
+    ReadCounter = 2,
+    Write = 3,
+    Read = 4,
+    ReadResult = 5,
+}
+
+impl RequestType {
+    fn response(self) -> u16 {
+        (self as u16) << 8
+    }
+}
+
+/// Operation result reported by the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpResult {
+    Ok,
+    GeneralFailure,
+    AuthFailure,
+    CounterFailure,
+    AddressFailure,
+    WriteFailure,
+    ReadFailure,
+    KeyNotProgrammed,
+    Unknown(u16),
+}
+
+impl OpResult {
+    fn from_raw(raw: u16) -> Self {
+        match raw & !RESULT_COUNTER_EXPIRED {
+            0 => OpResult::Ok,
+            1 => OpResult::GeneralFailure,
+            2 => OpResult::AuthFailure,
+            3 => OpResult::CounterFailure,
+            4 => OpResult::AddressFailure,
+            5 => OpResult::WriteFailure,
+            6 => OpResult::ReadFailure,
+            7 => OpResult::KeyNotProgrammed,
+            other => OpResult::Unknown(other),
+        }
+    }
+}
+
+/// One RPMB data frame. Multi-byte fields are big-endian.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Frame(pub [u8; FRAME_SIZE]);
+
+impl Default for Frame {
+    fn default() -> Self {
+        Frame([0; FRAME_SIZE])
+    }
+}
+
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Frame")
+            .field("type", &format_args!("{:#06x}", self.req_resp()))
+            .field("result", &format_args!("{:#06x}", self.result()))
+            .field("counter", &self.counter())
+            .field("address", &self.address())
+            .field("block_count", &self.block_count())
+            .finish()
+    }
+}
+
+impl Frame {
+    pub fn request(kind: RequestType) -> Self {
+        let mut frame = Frame::default();
+        frame.set_u16(TYPE_OFFSET, kind as u16);
+        frame
+    }
+
+    pub fn req_resp(&self) -> u16 {
+        self.u16(TYPE_OFFSET)
+    }
+
+    /// Raw result field, including the counter-expired bit.
+    pub fn result(&self) -> u16 {
+        self.u16(RESULT_OFFSET)
+    }
+
+    pub fn counter(&self) -> u32 {
+        u32::from_be_bytes(self.0[COUNTER_OFFSET..][..4].try_into().unwrap())
+    }
+
+    pub fn set_counter(&mut self, counter: u32) {
+        self.0[COUNTER_OFFSET..][..4].copy_from_slice(&counter.to_be_bytes());
+    }
+
+    pub fn address(&self) -> u16 {
+        self.u16(ADDRESS_OFFSET)
+    }
+
+    pub fn set_address(&mut self, address: u16) {
+        self.set_u16(ADDRESS_OFFSET, address);
+    }
+
+    pub fn block_count(&self) -> u16 {
+        self.u16(BLOCK_COUNT_OFFSET)
+    }
+
+    pub fn set_block_count(&mut self, blocks: u16) {
+        self.set_u16(BLOCK_COUNT_OFFSET, blocks);
+    }
+
+    /// Key/MAC field: the key when programming it, the MAC otherwise.
+    pub fn mac(&self) -> &[u8; MAC_SIZE] {
+        self.0[MAC_OFFSET..][..MAC_SIZE].try_into().unwrap()
+    }
+
+    pub fn set_mac(&mut self, mac: &[u8; MAC_SIZE]) {
+        self.0[MAC_OFFSET..][..MAC_SIZE].copy_from_slice(mac);
+    }
+
+    pub fn data(&self) -> &[u8; DATA_SIZE] {
+        self.0[DATA_OFFSET..][..DATA_SIZE].try_into().unwrap()
+    }
+
+    pub fn set_data(&mut self, data: &[u8]) {
+        self.0[DATA_OFFSET..][..DATA_SIZE].copy_from_slice(data);
+    }
+
+    pub fn nonce(&self) -> &[u8; NONCE_SIZE] {
+        self.0[NONCE_OFFSET..][..NONCE_SIZE].try_into().unwrap()
+    }
+
+    pub fn set_nonce(&mut self, nonce: &[u8; NONCE_SIZE]) {
+        self.0[NONCE_OFFSET..][..NONCE_SIZE].copy_from_slice(nonce);
+    }
+
+    /// The bytes the MAC covers: everything from the data field on.
+    pub fn mac_input(&self) -> &[u8] {
+        &self.0[DATA_OFFSET..]
+    }
+
+    fn u16(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.0[offset], self.0[offset + 1]])
+    }
+
+    fn set_u16(&mut self, offset: usize, value: u16) {
+        self.0[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+    }
+}
+
+/// HMAC-SHA256, supplied by the user of [`Rpmb`].
+pub trait Hmac {
+    fn hmac_sha256(&self, key: &[u8; KEY_SIZE], message: &[u8]) -> [u8; MAC_SIZE];
+}
+
+/// Moves frames to and from the RPMB partition of a device.
+pub trait RpmbTransport {
+    type Error: fmt::Debug;
+
+    /// Writes `frames` with one multi-block write, as a reliable write when
+    /// `reliable` is set (`SET_BLOCK_COUNT` bit 31).
+    fn send(&mut self, frames: &[Frame], reliable: bool) -> Result<(), Self::Error>;
+
+    /// Reads `frames.len()` response frames with one multi-block read.
+    fn receive(&mut self, frames: &mut [Frame]) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug)]
+pub enum RpmbError<E> {
+    /// The transport failed.
+    Io(E),
+    /// The device reported a failed operation.
+    Result(OpResult),
+    /// The write counter has reached its maximum; the partition is now
+    /// read-only.
+    CounterExpired,
+    /// The response frame was not the expected one.
+    Response { expected: u16, actual: u16 },
+    /// The response MAC does not match, or echoes another request.
+    Authentication,
+    /// Buffer not a whole number of [`DATA_SIZE`] frames, or empty.
+    InvalidLength(usize),
+}
+
+impl<E: fmt::Debug> fmt::Display for RpmbError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpmbError::Io(err) => write!(f, "RPMB transfer failed: {err:?}"),
+            RpmbError::Result(result) => write!(f, "RPMB operation failed: {result:?}"),
+            RpmbError::CounterExpired => write!(f, "RPMB write counter expired"),
+            RpmbError::Response { expected, actual } => {
+                write!(f, "RPMB response {actual:#06x}, expected {expected:#06x}")
+            }
+            RpmbError::Authentication => write!(f, "RPMB response authentication failed"),
+            RpmbError::InvalidLength(len) => {
+                write!(
+                    f,
+                    "RPMB buffer of {len} bytes is not a whole number of frames"
+                )
+            }
+        }
+    }
+}
+
+impl<E: fmt::Debug> core::error::Error for RpmbError<E> {}
+
+/// RPMB operations with the authentication key `key`.
+///
+/// Nonces for reads come from the caller, who has the random number
+/// source. Authenticated writes send one frame each, the size every device
+/// accepts.
+pub struct Rpmb<T, H> {
+    transport: T,
+    hmac: H,
+    key: [u8; KEY_SIZE],
+}
+
+impl<T: RpmbTransport, H: Hmac> Rpmb<T, H> {
+    pub fn new(transport: T, hmac: H, key: [u8; KEY_SIZE]) -> Self {
+        Rpmb {
+            transport,
+            hmac,
+            key,
+        }
+    }
+
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Programs the authentication key. This can be done once in the
+    /// lifetime of the device.
+    pub fn program_key(&mut self) -> Result<(), RpmbError<T::Error>> {
+        let mut request = Frame::request(RequestType::ProgramKey);
+        request.set_mac(&self.key);
+        self.transport
+            .send(core::slice::from_ref(&request), true)
+            .map_err(RpmbError::Io)?;
+
+        // 密钥编程的响应不带 MAC
+        let response = self.read_result()?;
+        check_response(&response, RequestType::ProgramKey)
+    }
+
+    /// Reads the write counter.
+    pub fn read_counter(&mut self, nonce: &[u8; NONCE_SIZE]) -> Result<u32, RpmbError<T::Error>> {
+        let mut request = Frame::request(RequestType::ReadCounter);
+        request.set_nonce(nonce);
+        self.transport
+            .send(core::slice::from_ref(&request), false)
+            .map_err(RpmbError::Io)?;
+
+        let mut response = Frame::default();
+        self.transport
+            .receive(core::slice::from_mut(&mut response))
+            .map_err(RpmbError::Io)?;
+        check_response(&response, RequestType::ReadCounter)?;
+        self.verify(core::slice::from_ref(&response))?;
+        if response.nonce() != nonce {
+            return Err(RpmbError::Authentication);
+        }
+        Ok(response.counter())
+    }
+
+    /// Authenticated read of `buf.len() / DATA_SIZE` frames from `address`.
+    pub fn read(
+        &mut self,
+        address: u16,
+        nonce: &[u8; NONCE_SIZE],
+        buf: &mut [u8],
+    ) -> Result<(), RpmbError<T::Error>> {
+        let blocks = frame_count(buf.len())?;
+
+        let mut request = Frame::request(RequestType::Read);
+        request.set_nonce(nonce);
+        request.set_address(address);
+        self.transport
+            .send(core::slice::from_ref(&request), false)
+            .map_err(RpmbError::Io)?;
+
+        let mut responses = vec![Frame::default(); blocks];
+        self.transport
+            .receive(&mut responses)
+            .map_err(RpmbError::Io)?;
+
+        let last = responses.last().unwrap();
+        check_response(last, RequestType::Read)?;
+        self.verify(&responses)?;
+        if last.nonce() != nonce || last.address() != address {
+            return Err(RpmbError::Authentication);
+        }
+
+        for (chunk, frame) in buf.chunks_exact_mut(DATA_SIZE).zip(&responses) {
+            chunk.copy_from_slice(frame.data());
+        }
+        Ok(())
+    }
+
+    /// Authenticated write of `data`, a whole number of frames, starting at
+    /// `address`. `nonce` is used for reading the write counter first.
+    /// Returns the write counter afterwards.
+    pub fn write(
+        &mut self,
+        address: u16,
+        nonce: &[u8; NONCE_SIZE],
+        data: &[u8],
+    ) -> Result<u32, RpmbError<T::Error>> {
+        frame_count(data.len())?;
+        let mut counter = self.read_counter(nonce)?;
+
+        for (i, chunk) in data.chunks_exact(DATA_SIZE).enumerate() {
+            let address = address.wrapping_add(i as u16);
+
+            let mut request = Frame::request(RequestType::Write);
+            request.set_data(chunk);
+            request.set_counter(counter);
+            request.set_address(address);
+            request.set_block_count(1);
+            request.set_mac(&self.mac(core::slice::from_ref(&request)));
+            self.transport
+                .send(core::slice::from_ref(&request), true)
+                .map_err(RpmbError::Io)?;
+
+            let response = self.read_result()?;
+            check_response(&response, RequestType::Write)?;
+            self.verify(core::slice::from_ref(&response))?;
+            // 计数器必须恰好加一，否则响应可能是重放的
+            if response.counter() != counter.wrapping_add(1) || response.address() != address {
+                return Err(RpmbError::Authentication);
+            }
+            counter = response.counter();
+        }
+
+        Ok(counter)
+    }
+
+    /// Asks for and reads the result of the preceding write.
+    fn read_result(&mut self) -> Result<Frame, RpmbError<T::Error>> {
+        let request = Frame::request(RequestType::ReadResult);
+        self.transport
+            .send(core::slice::from_ref(&request), false)
+            .map_err(RpmbError::Io)?;
+
+        let mut response = Frame::default();
+        self.transport
+            .receive(core::slice::from_mut(&mut response))
+            .map_err(RpmbError::Io)?;
+        Ok(response)
+    }
+
+    /// MAC over `frames`; it goes into, and is checked against, the last
+    /// one.
+    fn mac(&self, frames: &[Frame]) -> [u8; MAC_SIZE] {
+        let message: Vec<u8> = frames
+            .iter()
+            .flat_map(|frame| frame.mac_input())
+            .copied()
+            .collect();
+        self.hmac.hmac_sha256(&self.key, &message)
+    }
+
+    fn verify(&self, frames: &[Frame]) -> Result<(), RpmbError<T::Error>> {
+        let expected = self.mac(frames);
+        let actual = frames.last().unwrap().mac();
+        // 按位累积比较，避免泄露不匹配的位置
+        let diff = expected
+            .iter()
+            .zip(actual)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+        if diff != 0 {
+            return Err(RpmbError::Authentication);
+        }
+        Ok(())
+    }
+}
+
+/// Checks the type and result fields of a response to `request`.
+fn check_response<E>(response: &Frame, request: RequestType) -> Result<(), RpmbError<E>> {
+    let expected = request.response();
+    if response.req_resp() != expected {
+        return Err(RpmbError::Response {
+            expected,
+            actual: response.req_resp(),
+        });
+    }
+    // 计数器到上限后读操作仍然成功，只有失败时才报告
+    let raw = response.result();
+    match OpResult::from_raw(raw) {
+        OpResult::Ok => Ok(()),
+        _ if raw & RESULT_COUNTER_EXPIRED != 0 => Err(RpmbError::CounterExpired),
+        result => Err(RpmbError::Result(result)),
+    }
+}
+
+fn frame_count<E>(len: usize) -> Result<usize, RpmbError<E>> {
+    if len == 0 || !len.is_multiple_of(DATA_SIZE) || len / DATA_SIZE > u16::MAX as usize {
+        return Err(RpmbError::InvalidLength(len));
+    }
+    Ok(len / DATA_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    const KEY: [u8; KEY_SIZE] = [0x5A; KEY_SIZE];
+    const NONCE: [u8; NONCE_SIZE] = [0x11; NONCE_SIZE];
+
+    /// Plain HMAC-SHA256 for the tests, checked against RFC 4231.
+    struct Sha256Hmac;
+
+    fn sha256(message: &[u8]) -> [u8; 32] {
+        const K: [u32; 64] = [
+            0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+            0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+            0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+            0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+            0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+            0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+            0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+            0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+            0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+            0xc67178f2,
+        ];
+        let mut h: [u32; 8] = [
+            0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+            0x5be0cd19,
+        ];
+
+        let mut padded = message.to_vec();
+        padded.push(0x80);
+        while padded.len() % 64 != 56 {
+            padded.push(0);
+        }
+        padded.extend_from_slice(&((message.len() as u64) * 8).to_be_bytes());
+
+        for chunk in padded.chunks_exact(64) {
+            let mut w = [0u32; 64];
+            for (i, word) in chunk.chunks_exact(4).enumerate() {
+                w[i] = u32::from_be_bytes(word.try_into().unwrap());
+            }
+            for i in 16..64 {
+                let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+                let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+                w[i] = w[i - 16]
+                    .wrapping_add(s0)
+                    .wrapping_add(w[i - 7])
+                    .wrapping_add(s1);
+            }
+
+            let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+            for i in 0..64 {
+                let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+                let ch = (e & f) ^ (!e & g);
+                let t1 = hh
+                    .wrapping_add(s1)
+                    .wrapping_add(ch)
+                    .wrapping_add(K[i])
+                    .wrapping_add(w[i]);
+                let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+                let maj = (a & b) ^ (a & c) ^ (b & c);
+                let t2 = s0.wrapping_add(maj);
+                hh = g;
+                g = f;
+                f = e;
+                e = d.wrapping_add(t1);
+                d = c;
+                c = b;
+                b = a;
+                a = t1.wrapping_add(t2);
+            }
+            for (state, value) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+                *state = state.wrapping_add(value);
+            }
+        }
+
+        let mut digest = [0u8; 32];
+        for (out, word) in digest.chunks_exact_mut(4).zip(h) {
+            out.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+        let mut block = [0u8; 64];
+        if key.len() > 64 {
+            block[..32].copy_from_slice(&sha256(key));
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+        let pad = |byte: u8| block.iter().map(move |b| b ^ byte);
+
+        let inner: Vec<u8> = pad(0x36).chain(message.iter().copied()).collect();
+        let outer: Vec<u8> = pad(0x5c).chain(sha256(&inner)).collect();
+        sha256(&outer)
+    }
+
+    impl Hmac for Sha256Hmac {
+        fn hmac_sha256(&self, key: &[u8; KEY_SIZE], message: &[u8]) -> [u8; MAC_SIZE] {
+            hmac(key, message)
+        }
+    }
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn hmac_rfc4231_vectors() {
+        assert_eq!(
+            hmac(&[0x0b; 20], b"Hi There").to_vec(),
+            hex("b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7")
+        );
+        assert_eq!(
+            hmac(b"Jefe", b"what do ya want for nothing?").to_vec(),
+            hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+        );
+        // 密钥长于分组时先做摘要
+        assert_eq!(
+            hmac(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )
+            .to_vec(),
+            hex("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54")
+        );
+    }
+
+    #[test]
+    fn frame_layout() {
+        let mut frame = Frame::request(RequestType::Write);
+        frame.set_counter(0x0102_0304);
+        frame.set_address(0x0506);
+        frame.set_block_count(0x0708);
+        frame.set_mac(&[0xAA; MAC_SIZE]);
+        frame.set_data(&[0xBB; DATA_SIZE]);
+        frame.set_nonce(&[0xCC; NONCE_SIZE]);
+
+        // JEDEC 帧格式：stuff bytes 196 字节，其后各字段均为大端
+        let raw = &frame.0;
+        assert!(raw[..196].iter().all(|&b| b == 0));
+        assert!(raw[196..228].iter().all(|&b| b == 0xAA));
+        assert!(raw[228..484].iter().all(|&b| b == 0xBB));
+        assert!(raw[484..500].iter().all(|&b| b == 0xCC));
+        assert_eq!(raw[500..], [1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 3]);
+
+        assert_eq!(frame.counter(), 0x0102_0304);
+        assert_eq!(frame.address(), 0x0506);
+        assert_eq!(frame.block_count(), 0x0708);
+        assert_eq!(frame.req_resp(), RequestType::Write as u16);
+        assert_eq!(frame.mac(), &[0xAA; MAC_SIZE]);
+        assert_eq!(frame.data(), &[0xBB; DATA_SIZE]);
+        assert_eq!(frame.nonce(), &[0xCC; NONCE_SIZE]);
+        assert_eq!(frame.mac_input().len(), FRAME_SIZE - 228);
+        assert_eq!(frame.mac_input()[0], 0xBB);
+    }
+
+    /// An RPMB partition that follows the JEDEC sequences, with knobs for
+    /// misbehaving.
+    #[derive(Default)]
+    struct FakeDevice {
+        key: Option<[u8; KEY_SIZE]>,
+        counter: u32,
+        data: Vec<[u8; DATA_SIZE]>,
+        /// Result of the last write, returned for `ReadResult`.
+        result: Option<Frame>,
+        /// Frames the next `receive` returns.
+        responses: Vec<Frame>,
+        /// Applied to every response frame.
+        tamper: Option<fn(&mut Frame)>,
+        sent: Vec<(RequestType, bool)>,
+    }
+
+    impl FakeDevice {
+        fn with_key() -> Self {
+            FakeDevice {
+                key: Some(KEY),
+                counter: 7,
+                data: vec![[0; DATA_SIZE]; 4],
+                ..Default::default()
+            }
+        }
+
+        fn sign(&self, frames: &mut [Frame]) {
+            let message: Vec<u8> = frames.iter().flat_map(|f| f.mac_input()).copied().collect();
+            let mac = hmac(&self.key.unwrap(), &message);
+            frames.last_mut().unwrap().set_mac(&mac);
+        }
+
+        fn response(kind: RequestType, result: u16) -> Frame {
+            let mut frame = Frame::default();
+            frame.set_u16(TYPE_OFFSET, kind.response());
+            frame.set_u16(RESULT_OFFSET, result);
+            frame
+        }
+    }
+
+    impl RpmbTransport for FakeDevice {
+        type Error = ();
+
+        fn send(&mut self, frames: &[Frame], reliable: bool) -> Result<(), ()> {
+            let request = &frames[0];
+            let kind = match request.req_resp() {
+                1 => RequestType::ProgramKey,
+                2 => RequestType::ReadCounter,
+                3 => RequestType::Write,
+                4 => RequestType::Read,
+                5 => RequestType::ReadResult,
+                _ => return Err(()),
+            };
+            self.sent.push((kind, reliable));
+
+            match kind {
+                RequestType::ProgramKey => {
+                    let result = match self.key {
+                        Some(_) => 1,
+                        None => 0,
+                    };
+                    self.key.get_or_insert(*request.mac());
+                    self.result = Some(Self::response(kind, result));
+                }
+                RequestType::ReadCounter => {
+                    let mut response = Self::response(kind, 0);
+                    response.set_counter(self.counter);
+                    response.set_nonce(request.nonce());
+                    self.sign(core::slice::from_mut(&mut response));
+                    self.responses = vec![response];
+                }
+                RequestType::Write => {
+                    let mut expected = frames.to_vec();
+                    self.sign(&mut expected);
+                    let result = if expected.last().unwrap().mac() != frames[0].mac() {
+                        2
+                    } else if request.counter() != self.counter {
+                        3
+                    } else {
+                        self.data[request.address() as usize] = *request.data();
+                        self.counter += 1;
+                        0
+                    };
+                    let mut response = Self::response(kind, result);
+                    response.set_counter(self.counter);
+                    response.set_address(request.address());
+                    self.sign(core::slice::from_mut(&mut response));
+                    self.result = Some(response);
+                }
+                RequestType::Read => {
+                    let mut responses = Vec::new();
+                    let address = request.address() as usize;
+                    for data in &self.data[address..] {
+                        let mut response = Self::response(kind, 0);
+                        response.set_data(data);
+                        response.set_nonce(request.nonce());
+                        response.set_address(request.address());
+                        responses.push(response);
+                    }
+                    self.responses = responses;
+                }
+                RequestType::ReadResult => {
+                    self.responses = self.result.take().into_iter().collect();
+                }
+            }
+            Ok(())
+        }
+
+        fn receive(&mut self, frames: &mut [Frame]) -> Result<(), ()> {
+            if frames.len() > self.responses.len() {
+                return Err(());
+            }
+            // 读数据时卡按主机读取的块数返回，MAC 覆盖实际返回的各帧
+            let mut responses = core::mem::take(&mut self.responses);
+            responses.truncate(frames.len());
+            if responses[0].req_resp() == RequestType::Read.response() {
+                self.sign(&mut responses);
+            }
+            for (frame, mut response) in frames.iter_mut().zip(responses) {
+                if let Some(tamper) = self.tamper {
+                    tamper(&mut response);
+                }
+                *frame = response;
+            }
+            Ok(())
+        }
+    }
+
+    fn rpmb(device: FakeDevice) -> Rpmb<FakeDevice, Sha256Hmac> {
+        Rpmb::new(device, Sha256Hmac, KEY)
+    }
+
+    #[test]
+    fn program_key() {
+        let mut rpmb = rpmb(FakeDevice::default());
+        rpmb.program_key().unwrap();
+        assert_eq!(rpmb.transport().key, Some(KEY));
+        assert_eq!(
+            rpmb.transport().sent,
+            [
+                (RequestType::ProgramKey, true),
+                (RequestType::ReadResult, false)
+            ]
+        );
+
+        // 第二次编程被卡拒绝
+        let err = rpmb.program_key().unwrap_err();
+        assert!(matches!(err, RpmbError::Result(OpResult::GeneralFailure)));
+    }
+
+    #[test]
+    fn read_counter() {
+        let mut rpmb = rpmb(FakeDevice::with_key());
+        assert_eq!(rpmb.read_counter(&NONCE).unwrap(), 7);
+    }
+
+    #[test]
+    fn read_counter_rejects_replayed_nonce() {
+        let mut device = FakeDevice::with_key();
+        device.tamper = Some(|frame| frame.set_nonce(&[0; NONCE_SIZE]));
+        let err = rpmb(device).read_counter(&NONCE).unwrap_err();
+        assert!(matches!(err, RpmbError::Authentication));
+    }
+
+    #[test]
+    fn read_counter_rejects_bad_mac() {
+        let mut device = FakeDevice::with_key();
+        device.tamper = Some(|frame| frame.0[DATA_OFFSET] ^= 1);
+        let err = rpmb(device).read_counter(&NONCE).unwrap_err();
+        assert!(matches!(err, RpmbError::Authentication));
+    }
+
+    #[test]
+    fn write_then_read() {
+        let mut rpmb = rpmb(FakeDevice::with_key());
+        let data: Vec<u8> = (0..2 * DATA_SIZE).map(|i| i as u8).collect();
+        assert_eq!(rpmb.write(1, &NONCE, &data).unwrap(), 9);
+        assert_eq!(rpmb.transport().counter, 9);
+
+        let mut buf = vec![0u8; 2 * DATA_SIZE];
+        rpmb.read(1, &NONCE, &mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn write_rejects_counter_that_did_not_advance() {
+        let mut device = FakeDevice::with_key();
+        device.tamper = Some(|frame| {
+            if frame.req_resp() == RequestType::Write.response() {
+                frame.set_counter(frame.counter() - 1);
+            }
+        });
+        let err = rpmb(device).write(0, &NONCE, &[0; DATA_SIZE]).unwrap_err();
+        assert!(matches!(err, RpmbError::Authentication));
+    }
+
+    #[test]
+    fn write_with_wrong_key_fails_authentication() {
+        let mut device = FakeDevice::with_key();
+        device.key = Some([0; KEY_SIZE]);
+        // 计数器读取的 MAC 也用另一把密钥，先于写入失败
+        let err = rpmb(device).write(0, &NONCE, &[0; DATA_SIZE]).unwrap_err();
+        assert!(matches!(err, RpmbError::Authentication));
+    }
+
+    #[test]
+    fn result_codes() {
+        let ok = FakeDevice::response(RequestType::Write, 0);
+        assert!(check_response::<()>(&ok, RequestType::Write).is_ok());
+
+        // 计数器到上限但操作成功时不算错误
+        let expired_ok = FakeDevice::response(RequestType::Read, RESULT_COUNTER_EXPIRED);
+        assert!(check_response::<()>(&expired_ok, RequestType::Read).is_ok());
+
+        let expired = FakeDevice::response(RequestType::Write, RESULT_COUNTER_EXPIRED | 5);
+        assert!(matches!(
+            check_response::<()>(&expired, RequestType::Write),
+            Err(RpmbError::CounterExpired)
+        ));
+
+        let failed = FakeDevice::response(RequestType::Write, 3);
+        assert!(matches!(
+            check_response::<()>(&failed, RequestType::Write),
+            Err(RpmbError::Result(OpResult::CounterFailure))
+        ));
+
+        let unknown = FakeDevice::response(RequestType::Write, 0x42);
+        assert!(matches!(
+            check_response::<()>(&unknown, RequestType::Write),
+            Err(RpmbError::Result(OpResult::Unknown(0x42)))
+        ));
+
+        let wrong_type = FakeDevice::response(RequestType::Read, 0);
+        assert!(matches!(
+            check_response::<()>(&wrong_type, RequestType::Write),
+            Err(RpmbError::Response {
+                expected: 0x0300,
+                actual: 0x0400
+            })
+        ));
+    }
+
+    #[test]
+    fn invalid_lengths() {
+        let mut rpmb = rpmb(FakeDevice::with_key());
+        for len in [0, 100, DATA_SIZE + 1] {
+            let mut buf = vec![0u8; len];
+            let err = rpmb.read(0, &NONCE, &mut buf).unwrap_err();
+            assert!(matches!(err, RpmbError::InvalidLength(l) if l == len));
+        }
+        assert!(rpmb.transport().sent.is_empty());
+    }
+}
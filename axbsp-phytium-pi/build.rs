@@ -0,0 +1,5 @@
+include!("../axbsp-block/build/config.rs");
+
+fn main() {
+    emit_block_partition();
+}
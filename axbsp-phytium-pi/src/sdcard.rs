@@ -16,15 +16,21 @@ pub use phytium_mci::{Kernel, set_impl};
 use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use log::trace;
 
+use axbsp_block::{
+    EraseConfig, EraseInterface, EraseKind, EraseQueue, Layout, Partition, PartitionDevice,
+    Selector,
+};
 use rdif_block::{BlkError, IQueue, Interface, Request, RequestId};
-use rdrive::{DriverGeneric, KError};
+use rdrive::{DeviceId, DriverGeneric, KError};
 
 use spin::Mutex;
 
 // pub use dma_api::{Direction, Impl as DmaImpl};
 // pub use dma_api::set_impl as set_dma_impl;
 
-const OFFSET: usize = 0x400_0000;
+/// Device to register first, from `block-partition` in `axconfig.toml`; see
+/// [`Selector`].
+const PARTITION: &str = env!("AXBSP_BLOCK_PARTITION");
 const BLOCK_SIZE: usize = 512;
 
 pub struct KernelImpl;
@@ -87,14 +93,90 @@ fn probe_sdcard(info: FdtInfo<'_>, plat_dev: PlatformDevice) -> Result<(), OnPro
     info!("MCI reg mapped at {:p}", mci_reg);
 
     let sdcard = SdCardDriver::new(mci_reg, iopad);
-    let dev = rdif_block::Block::new(sdcard);
-    plat_dev.register(dev);
+    let layout = read_layout(&sdcard.sd_card)?;
+
+    // 分区设备共用一个整卡设备；配置选中的设备占用探测得到的设备槽位，
+    // 其余的注册为同一节点的兄弟设备
+    let disk = Arc::new(Mutex::new(sdcard.clone()));
+    let partition = |part: &Partition| {
+        info!("registering partition {part}");
+        PartitionDevice::new(Arc::clone(&disk), part.clone())
+    };
+    for part in layout.others() {
+        register(crate::sibling_device(&plat_dev), partition(part));
+    }
+    match &layout.selected {
+        Some(part) => {
+            register(crate::sibling_device(&plat_dev), sdcard);
+            register(plat_dev, partition(part));
+        }
+        None => register(plat_dev, sdcard),
+    }
 
     debug!("phytium block device registered successfully");
 
     Ok(())
 }
 
+/// Reads the partition table and resolves the configured [`PARTITION`].
+fn read_layout(sd_card: &Mutex<Box<SdCard>>) -> Result<Layout, OnProbeError> {
+    let num_blocks = sd_card.lock().block_count() as u64;
+    let layout = axbsp_block::layout(num_blocks, Selector::parse(PARTITION), |lba, buf| {
+        // 与队列一致，逐块读取
+        for (i, block) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            let mut temp_buf: Vec<u32> = Vec::with_capacity(BLOCK_SIZE / 4);
+            sd_card
+                .lock()
+                .read_blocks(&mut temp_buf, (lba as usize + i) as u32, 1)?;
+            for (dst, word) in block.chunks_exact_mut(4).zip(&temp_buf) {
+                dst.copy_from_slice(&word.to_ne_bytes());
+            }
+        }
+        Ok::<_, MCIHostError>(())
+    })
+    .map_err(|err| OnProbeError::other(alloc::format!("partition {PARTITION:?}: {err}")))?;
+
+    for part in &layout.partitions {
+        info!("partition {part}");
+    }
+    Ok(layout)
+}
+
+/// Every registered SD card device, by rdrive device ID, for the erase
+/// requests `rdif_block::Block` does not forward.
+static DEVICES: Mutex<Vec<(DeviceId, Box<dyn EraseInterface + Send>)>> = Mutex::new(Vec::new());
+
+/// Registers `dev` as the block device of `plat_dev` and keeps a handle for
+/// [`erase_config`] and [`erase_queue`].
+fn register<T: EraseInterface + Send + Clone + 'static>(plat_dev: PlatformDevice, dev: T) {
+    let id = plat_dev.descriptor.device_id;
+    info!("device {:?} erase {:?}", id, dev.erase_config());
+    DEVICES.lock().push((id, Box::new(dev.clone())));
+    plat_dev.register(rdif_block::Block::new(dev));
+}
+
+/// What the SD card device `id` can erase, DISCARD included. `None` if `id`
+/// is not one of its devices.
+pub fn erase_config(id: DeviceId) -> Option<EraseConfig> {
+    let devices = DEVICES.lock();
+    let (_, dev) = devices.iter().find(|(dev_id, _)| *dev_id == id)?;
+    Some(dev.erase_config())
+}
+
+/// A queue of the SD card device `id` that also takes erase requests,
+/// `None` if `id` is not one of its devices.
+pub fn erase_queue(id: DeviceId) -> Option<Box<dyn EraseQueue>> {
+    let mut devices = DEVICES.lock();
+    let (_, dev) = devices.iter_mut().find(|(dev_id, _)| *dev_id == id)?;
+    dev.create_erase_queue()
+}
+
+/// Clones are further devices on the same card.
+///
+/// Unlike the eMMC driver it has no identity or health queries: the pinned
+/// `phytium-mci` keeps the CID and CSD it reads to itself and sends no
+/// `SD_STATUS` (ACMD13), and SD cards have no standard wear report anyway.
+#[derive(Clone)]
 pub struct SdCardDriver {
     sd_card: Arc<Mutex<Box<SdCard>>>,
 }
@@ -122,11 +204,17 @@ impl DriverGeneric for SdCardDriver {
     }
 }
 
+impl SdCardDriver {
+    fn new_queue(&self) -> SdCardQueue {
+        SdCardQueue {
+            sd_card: Arc::clone(&self.sd_card),
+        }
+    }
+}
+
 impl Interface for SdCardDriver {
     fn create_queue(&mut self) -> Option<Box<dyn IQueue>> {
-        Some(Box::new(SdCardQueue {
-            sd_card: Arc::clone(&self.sd_card),
-        }))
+        Some(Box::new(self.new_queue()))
     }
 
     fn enable_irq(&mut self) {
@@ -146,6 +234,19 @@ impl Interface for SdCardDriver {
     }
 }
 
+impl EraseInterface for SdCardDriver {
+    fn erase_config(&self) -> EraseConfig {
+        EraseConfig::default()
+    }
+
+    fn create_erase_queue(&mut self) -> Option<Box<dyn EraseQueue>> {
+        Some(Box::new(self.new_queue()))
+    }
+}
+
+/// Bus errors are passed on as [`BlkError::Retry`] without running
+/// `axbsp_block::Recovery`: the pinned `phytium-mci` exposes no abort, host
+/// reset or bus mode switch to recover with.
 pub struct SdCardQueue {
     sd_card: Arc<Mutex<Box<SdCard>>>,
 }
@@ -174,7 +275,7 @@ impl IQueue for SdCardQueue {
     }
 
     fn submit_request(&mut self, request: Request<'_>) -> Result<RequestId, BlkError> {
-        let actual_block_id = request.block_id + OFFSET / 512;
+        let actual_block_id = request.block_id;
 
         match request.kind {
             rdif_block::RequestKind::Read(mut buffer) => {
@@ -188,7 +289,7 @@ impl IQueue for SdCardQueue {
                 self.sd_card
                     .lock()
                     .read_blocks(&mut temp_buf, actual_block_id as u32, 1)
-                    .map_err(|err| map_mci_error_to_blk_error(err))?;
+                    .map_err(map_mci_error_to_blk_error)?;
 
                 let copy_len = cmp::min(temp_buf.len(), aligned_buf.len());
                 aligned_buf[..copy_len].copy_from_slice(&temp_buf[..copy_len]);
@@ -206,7 +307,7 @@ impl IQueue for SdCardQueue {
                 self.sd_card
                     .lock()
                     .write_blocks(&mut write_buf, actual_block_id as u32, 1)
-                    .map_err(|err| map_mci_error_to_blk_error(err))?;
+                    .map_err(map_mci_error_to_blk_error)?;
 
                 Ok(RequestId::new(0))
             }
@@ -221,6 +322,25 @@ impl IQueue for SdCardQueue {
     }
 }
 
+/// Nothing is supported: the pinned `phytium-mci` only reads and writes
+/// blocks and sends no `ERASE_WR_BLK_START`/`END` or `ERASE` (CMD32/33/38)
+/// for us. Once it does, [`EraseConfig::from_sd_csd`] gives the units.
+impl EraseQueue for SdCardQueue {
+    fn erase_config(&self) -> EraseConfig {
+        EraseConfig::default()
+    }
+
+    fn submit_erase(
+        &mut self,
+        block_id: usize,
+        blocks: usize,
+        kind: EraseKind,
+    ) -> Result<RequestId, BlkError> {
+        self.erase_config().check(block_id, blocks, kind)?;
+        Err(BlkError::NotSupported)
+    }
+}
+
 impl SdCardQueue {
     fn validate_buffer(buffer: &[u8]) -> Result<(), BlkError> {
         if buffer.len() < BLOCK_SIZE {
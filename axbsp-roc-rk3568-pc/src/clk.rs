@@ -2,28 +2,219 @@ extern crate alloc;
 
 use axklib::mem::iomap;
 use rdif_clk::{ClockId, Interface};
-use rdrive::{DriverGeneric, KError};
-use rk3568_clk::CRU;
-use rk3568_clk::cru_clksel_con28_bits::*;
+use rdrive::fdt::Phandle;
+use rdrive::{DriverGeneric, KError, get_list};
 
 use rdrive::{PlatformDevice, probe::OnProbeError};
 use rdrive::{module_driver, register::FdtInfo};
 
-/// 频率常量
-const MHZ: u32 = 1_000_000;
-const KHZ: u32 = 1_000;
+use crate::sibling_device;
 
+pub mod cru;
+pub mod dt;
+mod pll;
+mod pmu;
+pub mod pmucru;
+mod regs;
+pub mod reset;
+pub mod tree;
+
+pub use pmu::PmuClkDriver;
+
+use regs::Regs;
+use reset::ResetController;
+use tree::{Clk, ClkState, ClkTree};
+
+use alloc::{format, string::String, vec::Vec};
 use core::convert::Into;
+use core::ops::{Deref, DerefMut};
 use core::result::Result::{self, *};
 use log::{debug, info, warn};
 
-pub struct ClkDriver(CRU);
+/// Clock tree and soft-reset lines of one Rockchip clock unit.
+pub struct Cru {
+    tree: ClkTree,
+    resets: ResetController,
+    /// Phandle of the device tree node, used to match consumer `clocks`.
+    phandle: Option<Phandle>,
+}
+
+impl Cru {
+    fn new(
+        regs: Regs,
+        clks: &'static [Clk],
+        critical: &'static [usize],
+        softrst_con: usize,
+        softrst_count: usize,
+        phandle: Option<Phandle>,
+    ) -> Self {
+        Cru {
+            tree: ClkTree::new(regs, clks, critical),
+            resets: ResetController::new(regs, softrst_con, softrst_count, phandle),
+            phandle,
+        }
+    }
+
+    pub fn phandle(&self) -> Option<Phandle> {
+        self.phandle
+    }
+
+    /// Soft-reset lines of the clock unit, addressed by dt-binding reset ID.
+    ///
+    /// Consumers usually go through the [`ResetController`] device
+    /// registered next to the clock driver, see [`reset::reset`].
+    pub fn resets(&self) -> &ResetController {
+        &self.resets
+    }
 
-pub const EMMC_CLK_ID: usize = 0x7c;
+    pub fn get_rate(&self, id: ClockId) -> Result<u64, KError> {
+        self.tree.rate(id.into())
+    }
+
+    /// Returns the closest rate at or below `rate` that clock `id` can run at.
+    pub fn round_rate(&self, id: ClockId, rate: u64) -> Result<u64, KError> {
+        self.tree.round_rate(id.into(), rate)
+    }
+
+    /// Sets clock `id` to the closest achievable rate at or below `rate` and
+    /// returns the rate actually chosen.
+    pub fn set_rounded_rate(&mut self, id: ClockId, rate: u64) -> Result<u64, KError> {
+        let rate = self.tree.set_rate(id.into(), rate)?;
+        info!("Clock {:?} set to {} Hz", id, rate);
+        Ok(rate)
+    }
+
+    /// Switches clock `id` to `parent`, which must be one of its mux inputs.
+    pub fn set_parent(&mut self, id: ClockId, parent: ClockId) -> Result<(), KError> {
+        self.tree.set_parent(id.into(), parent.into())?;
+        info!("Clock {:?} reparented to {:?}", id, parent);
+        Ok(())
+    }
+
+    /// Takes a reference on clock `id`, ungating it and its parents on first
+    /// use.
+    pub fn enable(&mut self, id: ClockId) -> Result<(), KError> {
+        self.tree.enable(id.into())
+    }
+
+    /// Takes the permanent reference on each critical clock of the unit.
+    /// Called once at probe, before any consumer can drop the last
+    /// reference on a clock they share.
+    fn enable_critical(&mut self) -> Result<(), KError> {
+        self.tree.enable_critical()
+    }
+
+    /// Drops a reference on clock `id`, gating it once it is unused.
+    pub fn disable(&mut self, id: ClockId) -> Result<(), KError> {
+        self.tree.disable(id.into())
+    }
+
+    /// Returns whether the gate of clock `id` is open.
+    pub fn is_enabled(&self, id: ClockId) -> Result<bool, KError> {
+        self.tree.is_enabled(id.into())
+    }
+
+    /// Returns the state of every clock of the unit.
+    pub fn summary(&self) -> Vec<ClkState> {
+        self.tree.summary()
+    }
+
+    /// Logs [`Cru::summary`] as a table, children indented below their
+    /// parent, like Linux's `clk_summary`.
+    pub fn dump_summary(&self) {
+        for line in self.summary_lines() {
+            info!("{}", line);
+        }
+    }
+
+    fn summary_lines(&self) -> Vec<String> {
+        let summary = self.summary();
+        let mut lines = alloc::vec![format!(
+            "{:<36} {:>6} {:>8} {:>6} {:>12}",
+            "clock", "id", "enables", "gate", "rate"
+        )];
+        for root in summary.iter().filter(|clk| clk.parent.is_none()) {
+            dump_clk(&summary, root, 0, &mut lines);
+        }
+        lines
+    }
+
+    /// Returns the number of outstanding [`Cru::enable`] calls on `id`.
+    pub fn enable_count(&self, id: ClockId) -> Result<u32, KError> {
+        self.tree.find(id.into())?;
+        Ok(self.tree.enable_count(id.into()))
+    }
+
+    /// Lets this clock unit and `peer` read each other's PLLs.
+    fn link(&mut self, peer: &mut Cru) {
+        self.tree.set_peer(peer.tree.regs());
+        peer.tree.set_peer(self.tree.regs());
+    }
+}
+
+/// Links `cru` with the sibling clock unit `T` if that one is registered
+/// already, so whichever of CRU and PMUCRU probes second links both.
+fn link_peer<T: DriverGeneric + DerefMut<Target = Cru>>(cru: &mut Cru) {
+    for dev in get_list::<T>() {
+        match dt::lock(&dev) {
+            Ok(mut peer) => cru.link(&mut peer),
+            Err(err) => warn!("Sibling clock unit busy, PLLs not linked: {:?}", err),
+        }
+    }
+}
+
+fn dump_clk(summary: &[ClkState], clk: &ClkState, depth: usize, lines: &mut Vec<String>) {
+    let gate = match clk.gated {
+        Some(true) => "off",
+        Some(false) => "on",
+        None => "-",
+    };
+    lines.push(format!(
+        "{:indent$}{:<width$} {:>#6x} {:>8} {:>6} {:>12}",
+        "",
+        clk.name,
+        clk.id,
+        clk.enable_count,
+        gate,
+        clk.rate,
+        indent = depth * 2,
+        width = 36usize.saturating_sub(depth * 2),
+    ));
+    for child in summary.iter().filter(|child| child.parent == Some(clk.id)) {
+        dump_clk(summary, child, depth + 1, lines);
+    }
+}
+
+/// RK3568 CRU: clock provider and, through [`Cru::resets`], the reset
+/// controller of the same register block.
+pub struct ClkDriver(Cru);
+
+pub const EMMC_CLK_ID: usize = cru::CCLK_EMMC;
 
 impl ClkDriver {
-    pub fn new(cru_address: u64) -> Self {
-        ClkDriver(CRU::new(cru_address as *mut _))
+    pub fn new(cru_address: u64, phandle: Option<Phandle>) -> Self {
+        ClkDriver(Cru::new(
+            Regs::new(cru_address as usize),
+            cru::CLKS,
+            cru::CRITICAL,
+            cru::SOFTRST_CON,
+            cru::SOFTRST_CON_COUNT,
+            phandle,
+        ))
+    }
+}
+
+impl Deref for ClkDriver {
+    type Target = Cru;
+
+    fn deref(&self) -> &Cru {
+        &self.0
+    }
+}
+
+impl DerefMut for ClkDriver {
+    fn deref_mut(&mut self) -> &mut Cru {
+        &mut self.0
     }
 }
 
@@ -43,40 +234,11 @@ impl Interface for ClkDriver {
     }
 
     fn get_rate(&self, id: ClockId) -> Result<u64, KError> {
-        let rate = match id.into() {
-            EMMC_CLK_ID => {
-                let con = self.0.cru_clksel_get_cclk_emmc();
-                con >> CRU_CLKSEL_CCLK_EMMC_POS
-            }
-            _ => {
-                warn!("Unsupported clock ID: {:?}", id);
-                Err(KError::InvalidArg { name: "clock_id" })?
-            }
-        };
-        Ok(rate as u64)
+        self.0.get_rate(id)
     }
 
     fn set_rate(&mut self, id: ClockId, rate: u64) -> Result<(), KError> {
-        match id.into() {
-            EMMC_CLK_ID => {
-                info!("Setting eMMC clock to {} Hz", rate);
-                let src_clk = match rate as u32 {
-                    r if r == 24 * MHZ => CRU_CLKSEL_CCLK_EMMC_XIN_SOC0_MUX,
-                    r if r == 52 * MHZ || r == 50 * MHZ => CRU_CLKSEL_CCLK_EMMC_CPL_DIV_50M,
-                    r if r == 100 * MHZ => CRU_CLKSEL_CCLK_EMMC_CPL_DIV_100M,
-                    r if r == 150 * MHZ => CRU_CLKSEL_CCLK_EMMC_GPL_DIV_150M,
-                    r if r == 200 * MHZ => CRU_CLKSEL_CCLK_EMMC_GPL_DIV_200M,
-                    r if r == 400 * KHZ || r == 375 * KHZ => CRU_CLKSEL_CCLK_EMMC_SOC0_375K,
-                    _ => panic!("Unsupported eMMC clock rate: {} Hz", rate),
-                };
-                self.0.cru_clksel_set_cclk_emmc(src_clk);
-            }
-            _ => {
-                warn!("Unsupported clock ID: {:?}", id);
-                return Err(KError::InvalidArg { name: "clock_id" });
-            }
-        }
-        Ok(())
+        self.set_rounded_rate(id, rate).map(|_| ())
     }
 }
 
@@ -95,6 +257,25 @@ module_driver!(
 fn probe_cru(info: FdtInfo<'_>, plat_dev: PlatformDevice) -> Result<(), OnProbeError> {
     info!("Probing Rockchip RK3568 Clock...");
 
+    let cru_address = iomap_node(&info, "CRU")?;
+
+    let mut clk = ClkDriver::new(cru_address, info.node.phandle());
+    link_peer::<PmuClkDriver>(&mut clk);
+    if let Err(err) = clk.enable_critical() {
+        warn!("CRU: critical clocks not held: {:?}", err);
+    }
+    if let Err(err) = dt::apply_assigned_clocks(&info.node, Some(&mut *clk)) {
+        warn!("CRU: assigned clocks not fully applied: {:?}", err);
+    }
+
+    sibling_device(&plat_dev).register(clk.resets().clone());
+    plat_dev.register(clk);
+
+    Ok(())
+}
+
+/// Maps the first `reg` entry of a clock unit node.
+fn iomap_node(info: &FdtInfo<'_>, what: &str) -> Result<u64, OnProbeError> {
     let cru_reg = info
         .node
         .reg()
@@ -105,7 +286,8 @@ fn probe_cru(info: FdtInfo<'_>, plat_dev: PlatformDevice) -> Result<(), OnProbeE
         )))?;
 
     info!(
-        "CRU reg: addr={:#x}, size={:#x}",
+        "{} reg: addr={:#x}, size={:#x}",
+        what,
         cru_reg.address as usize,
         cru_reg.size.unwrap_or(0)
     );
@@ -118,9 +300,85 @@ fn probe_cru(info: FdtInfo<'_>, plat_dev: PlatformDevice) -> Result<(), OnProbeE
 
     let cru_address = cru_reg_base.as_ptr() as u64;
 
-    debug!("cru address: {:#x}", cru_address);
+    debug!("{} address: {:#x}", what, cru_address);
 
-    plat_dev.register(ClkDriver::new(cru_address));
+    Ok(cru_address)
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regs::FakeRegs;
+
+    fn booted(fake: &mut FakeRegs) -> Cru {
+        let mut cru = Cru::new(
+            fake.regs(),
+            cru::CLKS,
+            cru::CRITICAL,
+            cru::SOFTRST_CON,
+            30,
+            None,
+        );
+        cru.tree = cru::tests::booted(fake);
+        cru
+    }
+
+    #[test]
+    fn summary_reads_registers() {
+        let mut fake = FakeRegs::new();
+        let mut cru = booted(&mut fake);
+        let gate = cru.tree.find(cru::HCLK_EMMC).unwrap().gate.unwrap();
+        fake.regs().write_field(gate.con, gate.bit, 1, 1);
+        cru.enable(cru::CCLK_EMMC.into()).unwrap();
+        cru.set_rounded_rate(cru::CCLK_EMMC.into(), 52_000_000)
+            .unwrap();
+
+        let summary = cru.summary();
+        assert_eq!(summary.len(), cru::CLKS.len());
+        assert!(summary.iter().zip(cru::CLKS).all(|(s, clk)| s.id == clk.id));
+
+        let state = |id| summary.iter().find(|s| s.id == id).unwrap();
+        let emmc = state(cru::CCLK_EMMC);
+        assert_eq!(emmc.name, "cclk_emmc");
+        assert_eq!(emmc.rate, 50_000_000);
+        assert_eq!(emmc.enable_count, 1);
+        assert_eq!(emmc.gated, Some(false));
+        assert_eq!(state(emmc.parent.unwrap()).name, "clk_cpll_div_50m");
+        assert_eq!(state(emmc.parent.unwrap()).enable_count, 1);
+        assert_eq!(state(cru::HCLK_EMMC).gated, Some(true));
+        assert_eq!(state(cru::PLL_GPLL).rate, 1_188_000_000);
+        assert_eq!(state(cru::PLL_GPLL).gated, None);
+    }
+
+    #[test]
+    fn dump_lists_every_clock_below_its_parent() {
+        let mut fake = FakeRegs::new();
+        let cru = booted(&mut fake);
+        let lines = cru.summary_lines();
+
+        assert!(lines[0].starts_with("clock"));
+        assert_eq!(lines.len(), cru::CLKS.len() + 1);
+        for clk in cru::CLKS {
+            let count = lines
+                .iter()
+                .filter(|line| line.split_whitespace().next() == Some(clk.name))
+                .count();
+            assert_eq!(count, 1, "{}", clk.name);
+        }
+
+        let line = |name: &str| {
+            lines
+                .iter()
+                .position(|line| line.trim_start().starts_with(&format!("{} ", name)))
+                .unwrap()
+        };
+        let indent = |index: usize| lines[index].len() - lines[index].trim_start().len();
+        let (gpll, tap, emmc) = (line("gpll"), line("clk_gpll_div_200m"), line("bclk_emmc"));
+        assert!(gpll < tap);
+        assert_eq!(indent(line("xin24m")), 0);
+        assert_eq!(indent(gpll), 2);
+        assert_eq!(indent(tap), 4);
+        assert_eq!(indent(emmc), 6);
+        assert!(lines[emmc].contains("0x7b"));
+    }
 }
@@ -0,0 +1,1202 @@
+use super::pll::Pll;
+use super::pmucru;
+use super::tree::{Clk, Field, Gate};
+
+const MODE_CON: usize = 0xc0;
+
+/// Offset of `SOFTRST_CON00` and number of `SOFTRST_CON` registers.
+pub(super) const SOFTRST_CON: usize = 0x400;
+pub(super) const SOFTRST_CON_COUNT: usize = 30;
+
+const fn pll_con(index: usize) -> usize {
+    index * 4
+}
+
+const fn clksel_con(index: usize) -> usize {
+    0x100 + index * 4
+}
+
+const fn clkgate_con(index: usize) -> usize {
+    0x300 + index * 4
+}
+
+const fn sel(con: usize, shift: u32, width: u32) -> Option<Field> {
+    Some(Field {
+        con: clksel_con(con),
+        shift,
+        width,
+    })
+}
+
+const fn gate(con: usize, bit: u32) -> Gate {
+    Gate {
+        con: clkgate_con(con),
+        bit,
+    }
+}
+
+/// GPLL and CPLL, which the PMUCRU reads as well.
+pub(super) const GPLL: Pll = Pll {
+    con: pll_con(16),
+    mode_con: MODE_CON,
+    mode_shift: 6,
+};
+pub(super) const CPLL: Pll = Pll {
+    con: pll_con(24),
+    mode_con: MODE_CON,
+    mode_shift: 4,
+};
+
+pub const PLL_APLL: usize = 1;
+pub const PLL_DPLL: usize = 2;
+pub const PLL_CPLL: usize = 3;
+pub const PLL_GPLL: usize = 4;
+pub const PLL_VPLL: usize = 5;
+pub const PLL_NPLL: usize = 6;
+pub const CPLL_333M: usize = 9;
+pub const USB480M: usize = 11;
+
+pub const ACLK_EMMC: usize = 121;
+pub const HCLK_EMMC: usize = 122;
+pub const BCLK_EMMC: usize = 123;
+pub const CCLK_EMMC: usize = 124;
+pub const TCLK_EMMC: usize = 125;
+pub const ACLK_PIPE: usize = 126;
+pub const PCLK_PIPE: usize = 127;
+pub const ACLK_PCIE20_MST: usize = 129;
+pub const ACLK_PCIE20_SLV: usize = 130;
+pub const ACLK_PCIE20_DBI: usize = 131;
+pub const PCLK_PCIE20: usize = 132;
+pub const CLK_PCIE20_AUX_NDFT: usize = 133;
+pub const ACLK_PCIE30X1_MST: usize = 136;
+pub const ACLK_PCIE30X1_SLV: usize = 137;
+pub const ACLK_PCIE30X1_DBI: usize = 138;
+pub const PCLK_PCIE30X1: usize = 139;
+pub const CLK_PCIE30X1_AUX_NDFT: usize = 140;
+pub const ACLK_PCIE30X2_MST: usize = 143;
+pub const ACLK_PCIE30X2_SLV: usize = 144;
+pub const ACLK_PCIE30X2_DBI: usize = 145;
+pub const PCLK_PCIE30X2: usize = 146;
+pub const CLK_PCIE30X2_AUX_NDFT: usize = 147;
+
+pub const ACLK_PHP: usize = 173;
+pub const HCLK_PHP: usize = 174;
+pub const PCLK_PHP: usize = 175;
+pub const HCLK_SDMMC0: usize = 176;
+pub const CLK_SDMMC0: usize = 177;
+pub const HCLK_SDMMC1: usize = 178;
+pub const CLK_SDMMC1: usize = 179;
+pub const ACLK_GMAC0: usize = 180;
+pub const PCLK_GMAC0: usize = 181;
+pub const CLK_MAC0_2TOP: usize = 182;
+pub const ACLK_USB: usize = 186;
+pub const HCLK_USB: usize = 187;
+pub const PCLK_USB: usize = 188;
+pub const HCLK_SDMMC2: usize = 193;
+pub const CLK_SDMMC2: usize = 194;
+pub const ACLK_GMAC1: usize = 195;
+pub const PCLK_GMAC1: usize = 196;
+pub const CLK_MAC1_2TOP: usize = 197;
+
+pub const ACLK_BUS: usize = 255;
+pub const PCLK_BUS: usize = 256;
+pub const PCLK_UART1: usize = 289;
+pub const CLK_UART1_SRC: usize = 290;
+pub const CLK_UART1_FRAC: usize = 291;
+pub const SCLK_UART1: usize = 292;
+pub const PCLK_UART2: usize = 293;
+pub const CLK_UART2_SRC: usize = 294;
+pub const CLK_UART2_FRAC: usize = 295;
+pub const SCLK_UART2: usize = 296;
+pub const PCLK_UART3: usize = 297;
+pub const CLK_UART3_SRC: usize = 298;
+pub const CLK_UART3_FRAC: usize = 299;
+pub const SCLK_UART3: usize = 300;
+pub const PCLK_UART4: usize = 301;
+pub const CLK_UART4_SRC: usize = 302;
+pub const CLK_UART4_FRAC: usize = 303;
+pub const SCLK_UART4: usize = 304;
+pub const PCLK_UART5: usize = 305;
+pub const CLK_UART5_SRC: usize = 306;
+pub const CLK_UART5_FRAC: usize = 307;
+pub const SCLK_UART5: usize = 308;
+pub const PCLK_UART6: usize = 309;
+pub const CLK_UART6_SRC: usize = 310;
+pub const CLK_UART6_FRAC: usize = 311;
+pub const SCLK_UART6: usize = 312;
+pub const PCLK_UART7: usize = 313;
+pub const CLK_UART7_SRC: usize = 314;
+pub const CLK_UART7_FRAC: usize = 315;
+pub const SCLK_UART7: usize = 316;
+pub const PCLK_UART8: usize = 317;
+pub const CLK_UART8_SRC: usize = 318;
+pub const CLK_UART8_FRAC: usize = 319;
+pub const SCLK_UART8: usize = 320;
+pub const PCLK_UART9: usize = 321;
+pub const CLK_UART9_SRC: usize = 322;
+pub const CLK_UART9_FRAC: usize = 323;
+pub const SCLK_UART9: usize = 324;
+pub const CLK_I2C: usize = 325;
+pub const PCLK_I2C1: usize = 326;
+pub const CLK_I2C1: usize = 327;
+pub const PCLK_I2C2: usize = 328;
+pub const CLK_I2C2: usize = 329;
+pub const PCLK_I2C3: usize = 330;
+pub const CLK_I2C3: usize = 331;
+pub const PCLK_I2C4: usize = 332;
+pub const CLK_I2C4: usize = 333;
+pub const PCLK_I2C5: usize = 334;
+pub const CLK_I2C5: usize = 335;
+pub const PCLK_SPI0: usize = 336;
+pub const CLK_SPI0: usize = 337;
+pub const PCLK_SPI1: usize = 338;
+pub const CLK_SPI1: usize = 339;
+pub const PCLK_SPI2: usize = 340;
+pub const CLK_SPI2: usize = 341;
+pub const PCLK_SPI3: usize = 342;
+pub const CLK_SPI3: usize = 343;
+
+/// First ID used for clocks that have no dt-binding ID, such as the
+/// oscillator and the fixed PLL taps.
+pub const INTERNAL: usize = 0x1000;
+
+const XIN24M: usize = INTERNAL;
+const PPLL: usize = INTERNAL + 1;
+const GPLL_400M: usize = INTERNAL + 2;
+const GPLL_300M: usize = INTERNAL + 3;
+const GPLL_200M: usize = INTERNAL + 4;
+const GPLL_150M: usize = INTERNAL + 5;
+const GPLL_100M: usize = INTERNAL + 6;
+const GPLL_75M: usize = INTERNAL + 7;
+const GPLL_20M: usize = INTERNAL + 8;
+const CPLL_500M: usize = INTERNAL + 9;
+const CPLL_250M: usize = INTERNAL + 10;
+const CPLL_125M: usize = INTERNAL + 11;
+const CPLL_100M: usize = INTERNAL + 12;
+const CPLL_62P5M: usize = INTERNAL + 13;
+const CPLL_50M: usize = INTERNAL + 14;
+const CPLL_25M: usize = INTERNAL + 15;
+const OSC0_DIV_750K: usize = INTERNAL + 16;
+const OSC0_DIV_375K: usize = INTERNAL + 17;
+const HCLK_NVM_ROOT: usize = INTERNAL + 18;
+const ACLK_NVM_ROOT: usize = INTERNAL + 19;
+const SCLK_UART1_MUX: usize = INTERNAL + 20;
+const SCLK_UART2_MUX: usize = INTERNAL + 21;
+const SCLK_UART3_MUX: usize = INTERNAL + 22;
+const SCLK_UART4_MUX: usize = INTERNAL + 23;
+const SCLK_UART5_MUX: usize = INTERNAL + 24;
+const SCLK_UART6_MUX: usize = INTERNAL + 25;
+const SCLK_UART7_MUX: usize = INTERNAL + 26;
+const SCLK_UART8_MUX: usize = INTERNAL + 27;
+const SCLK_UART9_MUX: usize = INTERNAL + 28;
+
+/// RK3568 CRU clock tree. Public IDs follow the `rk3568-cru.h` dt-binding
+/// used by the board device trees (`CCLK_EMMC` = 124).
+///
+/// The tree covers the PLLs, their fixed taps and the branches of the
+/// peripherals boards use with this BSP: storage, PCIe, GMAC, USB, UART,
+/// I2C and SPI. The rest of the binding is out of scope on purpose. The
+/// core and DDR clocks belong to the firmware, and no driver here runs the
+/// VOP, GPU, NPU, VPU, audio (I2S/PDM/SPDIF), PWM or TSADC/SARADC. Their
+/// IDs fail with [`rdrive::KError::InvalidArg`], and their registers keep
+/// the bootloader's settings. The taps and bus roots that feed them are in
+/// [`CRITICAL`], so they are never gated.
+pub(super) static CLKS: &[Clk] = &[
+    Clk::fixed(XIN24M, "xin24m", 24_000_000),
+    Clk::fixed(USB480M, "usb480m", 480_000_000),
+    Clk::peer(PPLL, "ppll", &[XIN24M], pmucru::PPLL, 200_000_000),
+    Clk::pll(
+        PLL_APLL,
+        "apll",
+        &[XIN24M],
+        Pll {
+            con: pll_con(0),
+            mode_con: MODE_CON,
+            mode_shift: 0,
+        },
+    ),
+    Clk::pll(
+        PLL_DPLL,
+        "dpll",
+        &[XIN24M],
+        Pll {
+            con: pll_con(8),
+            mode_con: MODE_CON,
+            mode_shift: 2,
+        },
+    ),
+    Clk::pll(PLL_CPLL, "cpll", &[XIN24M], CPLL),
+    Clk::pll(PLL_GPLL, "gpll", &[XIN24M], GPLL),
+    Clk::pll(
+        PLL_NPLL,
+        "npll",
+        &[XIN24M],
+        Pll {
+            con: pll_con(32),
+            mode_con: MODE_CON,
+            mode_shift: 10,
+        },
+    ),
+    Clk::pll(
+        PLL_VPLL,
+        "vpll",
+        &[XIN24M],
+        Pll {
+            con: pll_con(40),
+            mode_con: MODE_CON,
+            mode_shift: 12,
+        },
+    ),
+    // 固定分频档位
+    Clk::composite(
+        GPLL_400M,
+        "clk_gpll_div_400m",
+        &[PLL_GPLL],
+        None,
+        sel(75, 0, 5),
+        Some(gate(35, 0)),
+    ),
+    Clk::composite(
+        GPLL_300M,
+        "clk_gpll_div_300m",
+        &[PLL_GPLL],
+        None,
+        sel(75, 8, 5),
+        Some(gate(35, 1)),
+    ),
+    Clk::composite(
+        GPLL_200M,
+        "clk_gpll_div_200m",
+        &[PLL_GPLL],
+        None,
+        sel(76, 0, 5),
+        Some(gate(35, 2)),
+    ),
+    Clk::composite(
+        GPLL_150M,
+        "clk_gpll_div_150m",
+        &[PLL_GPLL],
+        None,
+        sel(76, 8, 5),
+        Some(gate(35, 3)),
+    ),
+    Clk::composite(
+        GPLL_100M,
+        "clk_gpll_div_100m",
+        &[PLL_GPLL],
+        None,
+        sel(77, 0, 5),
+        Some(gate(35, 4)),
+    ),
+    Clk::composite(
+        GPLL_75M,
+        "clk_gpll_div_75m",
+        &[PLL_GPLL],
+        None,
+        sel(77, 8, 5),
+        Some(gate(35, 5)),
+    ),
+    Clk::composite(
+        GPLL_20M,
+        "clk_gpll_div_20m",
+        &[PLL_GPLL],
+        None,
+        sel(78, 8, 6),
+        Some(gate(35, 6)),
+    ),
+    Clk::composite(
+        CPLL_500M,
+        "clk_cpll_div_500m",
+        &[PLL_CPLL],
+        None,
+        sel(78, 0, 5),
+        Some(gate(35, 7)),
+    ),
+    Clk::composite(
+        CPLL_333M,
+        "clk_cpll_div_333m",
+        &[PLL_CPLL],
+        None,
+        sel(79, 0, 5),
+        Some(gate(35, 8)),
+    ),
+    Clk::composite(
+        CPLL_250M,
+        "clk_cpll_div_250m",
+        &[PLL_CPLL],
+        None,
+        sel(79, 8, 5),
+        Some(gate(35, 9)),
+    ),
+    Clk::composite(
+        CPLL_125M,
+        "clk_cpll_div_125m",
+        &[PLL_CPLL],
+        None,
+        sel(80, 0, 5),
+        Some(gate(35, 10)),
+    ),
+    Clk::composite(
+        CPLL_100M,
+        "clk_cpll_div_100m",
+        &[PLL_CPLL],
+        None,
+        sel(82, 0, 5),
+        Some(gate(35, 11)),
+    ),
+    Clk::composite(
+        CPLL_62P5M,
+        "clk_cpll_div_62P5m",
+        &[PLL_CPLL],
+        None,
+        sel(80, 8, 5),
+        Some(gate(35, 12)),
+    ),
+    Clk::composite(
+        CPLL_50M,
+        "clk_cpll_div_50m",
+        &[PLL_CPLL],
+        None,
+        sel(81, 0, 5),
+        Some(gate(35, 13)),
+    ),
+    Clk::composite(
+        CPLL_25M,
+        "clk_cpll_div_25m",
+        &[PLL_CPLL],
+        None,
+        sel(81, 8, 6),
+        Some(gate(35, 14)),
+    ),
+    Clk::composite(
+        OSC0_DIV_750K,
+        "clk_osc0_div_750k",
+        &[XIN24M],
+        None,
+        sel(82, 8, 6),
+        Some(gate(35, 15)),
+    ),
+    Clk::factor(OSC0_DIV_375K, "clk_osc0_div_375k", &[OSC0_DIV_750K], 1, 2),
+    // PD_NVM
+    Clk::composite(
+        HCLK_NVM_ROOT,
+        "hclk_nvm_root",
+        &[GPLL_200M, GPLL_150M, GPLL_100M, XIN24M],
+        sel(31, 0, 2),
+        None,
+        Some(gate(8, 0)),
+    ),
+    Clk::composite(
+        ACLK_NVM_ROOT,
+        "aclk_nvm_root",
+        &[GPLL_300M, GPLL_200M, GPLL_100M, XIN24M],
+        sel(31, 6, 2),
+        None,
+        Some(gate(8, 1)),
+    ),
+    Clk::gate(ACLK_EMMC, "aclk_emmc", &[ACLK_NVM_ROOT], gate(9, 0)),
+    Clk::gate(HCLK_EMMC, "hclk_emmc", &[HCLK_NVM_ROOT], gate(9, 1)),
+    Clk::composite(
+        BCLK_EMMC,
+        "bclk_emmc",
+        &[GPLL_200M, GPLL_150M, CPLL_125M],
+        sel(28, 8, 2),
+        None,
+        Some(gate(9, 2)),
+    ),
+    Clk::composite(
+        CCLK_EMMC,
+        "cclk_emmc",
+        &[
+            XIN24M,
+            GPLL_200M,
+            GPLL_150M,
+            CPLL_100M,
+            CPLL_50M,
+            OSC0_DIV_375K,
+        ],
+        sel(28, 12, 3),
+        None,
+        Some(gate(9, 3)),
+    ),
+    Clk::gate(TCLK_EMMC, "tclk_emmc", &[XIN24M], gate(9, 4)),
+    // PD_PIPE
+    Clk::composite(
+        ACLK_PIPE,
+        "aclk_pipe",
+        &[GPLL_400M, GPLL_300M, GPLL_200M, XIN24M],
+        sel(29, 0, 2),
+        None,
+        Some(gate(19, 0)),
+    ),
+    Clk::composite(
+        PCLK_PIPE,
+        "pclk_pipe",
+        &[ACLK_PIPE],
+        None,
+        sel(29, 4, 4),
+        Some(gate(19, 1)),
+    ),
+    Clk::gate(
+        ACLK_PCIE20_MST,
+        "aclk_pcie20_mst",
+        &[ACLK_PIPE],
+        gate(20, 0),
+    ),
+    Clk::gate(
+        ACLK_PCIE20_SLV,
+        "aclk_pcie20_slv",
+        &[ACLK_PIPE],
+        gate(20, 1),
+    ),
+    Clk::gate(
+        ACLK_PCIE20_DBI,
+        "aclk_pcie20_dbi",
+        &[ACLK_PIPE],
+        gate(20, 2),
+    ),
+    Clk::gate(PCLK_PCIE20, "pclk_pcie20", &[PCLK_PIPE], gate(20, 3)),
+    Clk::gate(
+        CLK_PCIE20_AUX_NDFT,
+        "clk_pcie20_aux_ndft",
+        &[XIN24M],
+        gate(20, 4),
+    ),
+    Clk::gate(
+        ACLK_PCIE30X1_MST,
+        "aclk_pcie30x1_mst",
+        &[ACLK_PIPE],
+        gate(20, 8),
+    ),
+    Clk::gate(
+        ACLK_PCIE30X1_SLV,
+        "aclk_pcie30x1_slv",
+        &[ACLK_PIPE],
+        gate(20, 9),
+    ),
+    Clk::gate(
+        ACLK_PCIE30X1_DBI,
+        "aclk_pcie30x1_dbi",
+        &[ACLK_PIPE],
+        gate(20, 10),
+    ),
+    Clk::gate(PCLK_PCIE30X1, "pclk_pcie30x1", &[PCLK_PIPE], gate(20, 11)),
+    Clk::gate(
+        CLK_PCIE30X1_AUX_NDFT,
+        "clk_pcie30x1_aux_ndft",
+        &[XIN24M],
+        gate(20, 12),
+    ),
+    Clk::gate(
+        ACLK_PCIE30X2_MST,
+        "aclk_pcie30x2_mst",
+        &[ACLK_PIPE],
+        gate(21, 0),
+    ),
+    Clk::gate(
+        ACLK_PCIE30X2_SLV,
+        "aclk_pcie30x2_slv",
+        &[ACLK_PIPE],
+        gate(21, 1),
+    ),
+    Clk::gate(
+        ACLK_PCIE30X2_DBI,
+        "aclk_pcie30x2_dbi",
+        &[ACLK_PIPE],
+        gate(21, 2),
+    ),
+    Clk::gate(PCLK_PCIE30X2, "pclk_pcie30x2", &[PCLK_PIPE], gate(21, 3)),
+    Clk::gate(
+        CLK_PCIE30X2_AUX_NDFT,
+        "clk_pcie30x2_aux_ndft",
+        &[XIN24M],
+        gate(21, 4),
+    ),
+    // PD_PHP
+    Clk::composite(
+        ACLK_PHP,
+        "aclk_php",
+        &[GPLL_300M, GPLL_200M, GPLL_100M, XIN24M],
+        sel(30, 0, 2),
+        None,
+        Some(gate(14, 12)),
+    ),
+    Clk::composite(
+        HCLK_PHP,
+        "hclk_php",
+        &[GPLL_200M, GPLL_150M, GPLL_100M, XIN24M],
+        sel(30, 2, 2),
+        None,
+        Some(gate(14, 13)),
+    ),
+    Clk::composite(
+        PCLK_PHP,
+        "pclk_php",
+        &[ACLK_PHP],
+        None,
+        sel(30, 4, 4),
+        Some(gate(14, 14)),
+    ),
+    Clk::gate(HCLK_SDMMC0, "hclk_sdmmc0", &[HCLK_PHP], gate(15, 0)),
+    Clk::composite(
+        CLK_SDMMC0,
+        "clk_sdmmc0",
+        &[
+            XIN24M,
+            GPLL_400M,
+            GPLL_300M,
+            CPLL_100M,
+            CPLL_50M,
+            OSC0_DIV_750K,
+        ],
+        sel(30, 8, 3),
+        None,
+        Some(gate(15, 1)),
+    ),
+    Clk::gate(HCLK_SDMMC1, "hclk_sdmmc1", &[HCLK_PHP], gate(15, 2)),
+    Clk::composite(
+        CLK_SDMMC1,
+        "clk_sdmmc1",
+        &[
+            XIN24M,
+            GPLL_400M,
+            GPLL_300M,
+            CPLL_100M,
+            CPLL_50M,
+            OSC0_DIV_750K,
+        ],
+        sel(30, 12, 3),
+        None,
+        Some(gate(15, 3)),
+    ),
+    Clk::gate(ACLK_GMAC0, "aclk_gmac0", &[ACLK_PHP], gate(15, 5)),
+    Clk::gate(PCLK_GMAC0, "pclk_gmac0", &[PCLK_PHP], gate(15, 6)),
+    Clk::composite(
+        CLK_MAC0_2TOP,
+        "clk_mac0_2top",
+        &[CPLL_125M, CPLL_50M, CPLL_25M, PPLL],
+        sel(31, 8, 2),
+        None,
+        Some(gate(15, 7)),
+    ),
+    // PD_USB
+    Clk::composite(
+        ACLK_USB,
+        "aclk_usb",
+        &[GPLL_300M, GPLL_200M, GPLL_100M, XIN24M],
+        sel(32, 0, 2),
+        None,
+        Some(gate(16, 0)),
+    ),
+    Clk::composite(
+        HCLK_USB,
+        "hclk_usb",
+        &[GPLL_150M, GPLL_100M, GPLL_75M, XIN24M],
+        sel(32, 2, 2),
+        None,
+        Some(gate(16, 1)),
+    ),
+    Clk::composite(
+        PCLK_USB,
+        "pclk_usb",
+        &[ACLK_USB],
+        None,
+        sel(32, 4, 4),
+        Some(gate(16, 2)),
+    ),
+    Clk::gate(HCLK_SDMMC2, "hclk_sdmmc2", &[HCLK_USB], gate(17, 0)),
+    Clk::composite(
+        CLK_SDMMC2,
+        "clk_sdmmc2",
+        &[
+            XIN24M,
+            GPLL_400M,
+            GPLL_300M,
+            CPLL_100M,
+            CPLL_50M,
+            OSC0_DIV_750K,
+        ],
+        sel(32, 8, 3),
+        None,
+        Some(gate(17, 1)),
+    ),
+    Clk::gate(ACLK_GMAC1, "aclk_gmac1", &[ACLK_USB], gate(17, 5)),
+    Clk::gate(PCLK_GMAC1, "pclk_gmac1", &[PCLK_USB], gate(17, 6)),
+    Clk::composite(
+        CLK_MAC1_2TOP,
+        "clk_mac1_2top",
+        &[CPLL_125M, CPLL_50M, CPLL_25M, PPLL],
+        sel(33, 8, 2),
+        None,
+        Some(gate(17, 7)),
+    ),
+    // PD_BUS
+    Clk::composite(
+        ACLK_BUS,
+        "aclk_bus",
+        &[GPLL_200M, GPLL_150M, GPLL_100M, XIN24M],
+        sel(50, 0, 2),
+        None,
+        Some(gate(10, 0)),
+    ),
+    Clk::composite(
+        PCLK_BUS,
+        "pclk_bus",
+        &[GPLL_100M, GPLL_75M, CPLL_50M, XIN24M],
+        sel(50, 4, 2),
+        None,
+        Some(gate(10, 1)),
+    ),
+    Clk::composite(
+        CLK_UART1_SRC,
+        "clk_uart1_src",
+        &[PLL_GPLL, PLL_CPLL, USB480M],
+        sel(52, 8, 2),
+        sel(52, 0, 7),
+        Some(gate(11, 0)),
+    ),
+    Clk::frac(
+        CLK_UART1_FRAC,
+        "clk_uart1_frac",
+        &[CLK_UART1_SRC],
+        clksel_con(53),
+        Some(gate(11, 1)),
+    ),
+    Clk::composite(
+        SCLK_UART1_MUX,
+        "sclk_uart1_mux",
+        &[CLK_UART1_SRC, CLK_UART1_FRAC, XIN24M],
+        sel(52, 12, 2),
+        None,
+        None,
+    ),
+    Clk::gate(SCLK_UART1, "sclk_uart1", &[SCLK_UART1_MUX], gate(11, 2)),
+    Clk::gate(PCLK_UART1, "pclk_uart1", &[PCLK_BUS], gate(11, 3)),
+    Clk::composite(
+        CLK_UART2_SRC,
+        "clk_uart2_src",
+        &[PLL_GPLL, PLL_CPLL, USB480M],
+        sel(54, 8, 2),
+        sel(54, 0, 7),
+        Some(gate(11, 4)),
+    ),
+    Clk::frac(
+        CLK_UART2_FRAC,
+        "clk_uart2_frac",
+        &[CLK_UART2_SRC],
+        clksel_con(55),
+        Some(gate(11, 5)),
+    ),
+    Clk::composite(
+        SCLK_UART2_MUX,
+        "sclk_uart2_mux",
+        &[CLK_UART2_SRC, CLK_UART2_FRAC, XIN24M],
+        sel(54, 12, 2),
+        None,
+        None,
+    ),
+    Clk::gate(SCLK_UART2, "sclk_uart2", &[SCLK_UART2_MUX], gate(11, 6)),
+    Clk::gate(PCLK_UART2, "pclk_uart2", &[PCLK_BUS], gate(11, 7)),
+    Clk::composite(
+        CLK_UART3_SRC,
+        "clk_uart3_src",
+        &[PLL_GPLL, PLL_CPLL, USB480M],
+        sel(56, 8, 2),
+        sel(56, 0, 7),
+        Some(gate(11, 8)),
+    ),
+    Clk::frac(
+        CLK_UART3_FRAC,
+        "clk_uart3_frac",
+        &[CLK_UART3_SRC],
+        clksel_con(57),
+        Some(gate(11, 9)),
+    ),
+    Clk::composite(
+        SCLK_UART3_MUX,
+        "sclk_uart3_mux",
+        &[CLK_UART3_SRC, CLK_UART3_FRAC, XIN24M],
+        sel(56, 12, 2),
+        None,
+        None,
+    ),
+    Clk::gate(SCLK_UART3, "sclk_uart3", &[SCLK_UART3_MUX], gate(11, 10)),
+    Clk::gate(PCLK_UART3, "pclk_uart3", &[PCLK_BUS], gate(11, 11)),
+    Clk::composite(
+        CLK_UART4_SRC,
+        "clk_uart4_src",
+        &[PLL_GPLL, PLL_CPLL, USB480M],
+        sel(58, 8, 2),
+        sel(58, 0, 7),
+        Some(gate(11, 12)),
+    ),
+    Clk::frac(
+        CLK_UART4_FRAC,
+        "clk_uart4_frac",
+        &[CLK_UART4_SRC],
+        clksel_con(59),
+        Some(gate(11, 13)),
+    ),
+    Clk::composite(
+        SCLK_UART4_MUX,
+        "sclk_uart4_mux",
+        &[CLK_UART4_SRC, CLK_UART4_FRAC, XIN24M],
+        sel(58, 12, 2),
+        None,
+        None,
+    ),
+    Clk::gate(SCLK_UART4, "sclk_uart4", &[SCLK_UART4_MUX], gate(11, 14)),
+    Clk::gate(PCLK_UART4, "pclk_uart4", &[PCLK_BUS], gate(11, 15)),
+    Clk::composite(
+        CLK_UART5_SRC,
+        "clk_uart5_src",
+        &[PLL_GPLL, PLL_CPLL, USB480M],
+        sel(60, 8, 2),
+        sel(60, 0, 7),
+        Some(gate(12, 0)),
+    ),
+    Clk::frac(
+        CLK_UART5_FRAC,
+        "clk_uart5_frac",
+        &[CLK_UART5_SRC],
+        clksel_con(61),
+        Some(gate(12, 1)),
+    ),
+    Clk::composite(
+        SCLK_UART5_MUX,
+        "sclk_uart5_mux",
+        &[CLK_UART5_SRC, CLK_UART5_FRAC, XIN24M],
+        sel(60, 12, 2),
+        None,
+        None,
+    ),
+    Clk::gate(SCLK_UART5, "sclk_uart5", &[SCLK_UART5_MUX], gate(12, 2)),
+    Clk::gate(PCLK_UART5, "pclk_uart5", &[PCLK_BUS], gate(12, 3)),
+    Clk::composite(
+        CLK_UART6_SRC,
+        "clk_uart6_src",
+        &[PLL_GPLL, PLL_CPLL, USB480M],
+        sel(62, 8, 2),
+        sel(62, 0, 7),
+        Some(gate(12, 4)),
+    ),
+    Clk::frac(
+        CLK_UART6_FRAC,
+        "clk_uart6_frac",
+        &[CLK_UART6_SRC],
+        clksel_con(63),
+        Some(gate(12, 5)),
+    ),
+    Clk::composite(
+        SCLK_UART6_MUX,
+        "sclk_uart6_mux",
+        &[CLK_UART6_SRC, CLK_UART6_FRAC, XIN24M],
+        sel(62, 12, 2),
+        None,
+        None,
+    ),
+    Clk::gate(SCLK_UART6, "sclk_uart6", &[SCLK_UART6_MUX], gate(12, 6)),
+    Clk::gate(PCLK_UART6, "pclk_uart6", &[PCLK_BUS], gate(12, 7)),
+    Clk::composite(
+        CLK_UART7_SRC,
+        "clk_uart7_src",
+        &[PLL_GPLL, PLL_CPLL, USB480M],
+        sel(64, 8, 2),
+        sel(64, 0, 7),
+        Some(gate(12, 8)),
+    ),
+    Clk::frac(
+        CLK_UART7_FRAC,
+        "clk_uart7_frac",
+        &[CLK_UART7_SRC],
+        clksel_con(65),
+        Some(gate(12, 9)),
+    ),
+    Clk::composite(
+        SCLK_UART7_MUX,
+        "sclk_uart7_mux",
+        &[CLK_UART7_SRC, CLK_UART7_FRAC, XIN24M],
+        sel(64, 12, 2),
+        None,
+        None,
+    ),
+    Clk::gate(SCLK_UART7, "sclk_uart7", &[SCLK_UART7_MUX], gate(12, 10)),
+    Clk::gate(PCLK_UART7, "pclk_uart7", &[PCLK_BUS], gate(12, 11)),
+    Clk::composite(
+        CLK_UART8_SRC,
+        "clk_uart8_src",
+        &[PLL_GPLL, PLL_CPLL, USB480M],
+        sel(66, 8, 2),
+        sel(66, 0, 7),
+        Some(gate(12, 12)),
+    ),
+    Clk::frac(
+        CLK_UART8_FRAC,
+        "clk_uart8_frac",
+        &[CLK_UART8_SRC],
+        clksel_con(67),
+        Some(gate(12, 13)),
+    ),
+    Clk::composite(
+        SCLK_UART8_MUX,
+        "sclk_uart8_mux",
+        &[CLK_UART8_SRC, CLK_UART8_FRAC, XIN24M],
+        sel(66, 12, 2),
+        None,
+        None,
+    ),
+    Clk::gate(SCLK_UART8, "sclk_uart8", &[SCLK_UART8_MUX], gate(12, 14)),
+    Clk::gate(PCLK_UART8, "pclk_uart8", &[PCLK_BUS], gate(12, 15)),
+    Clk::composite(
+        CLK_UART9_SRC,
+        "clk_uart9_src",
+        &[PLL_GPLL, PLL_CPLL, USB480M],
+        sel(68, 8, 2),
+        sel(68, 0, 7),
+        Some(gate(13, 0)),
+    ),
+    Clk::frac(
+        CLK_UART9_FRAC,
+        "clk_uart9_frac",
+        &[CLK_UART9_SRC],
+        clksel_con(69),
+        Some(gate(13, 1)),
+    ),
+    Clk::composite(
+        SCLK_UART9_MUX,
+        "sclk_uart9_mux",
+        &[CLK_UART9_SRC, CLK_UART9_FRAC, XIN24M],
+        sel(68, 12, 2),
+        None,
+        None,
+    ),
+    Clk::gate(SCLK_UART9, "sclk_uart9", &[SCLK_UART9_MUX], gate(13, 2)),
+    Clk::gate(PCLK_UART9, "pclk_uart9", &[PCLK_BUS], gate(13, 3)),
+    Clk::composite(
+        CLK_I2C,
+        "clk_i2c",
+        &[GPLL_200M, GPLL_100M, XIN24M, CPLL_100M],
+        sel(71, 8, 2),
+        None,
+        Some(gate(32, 10)),
+    ),
+    Clk::gate(PCLK_I2C1, "pclk_i2c1", &[PCLK_BUS], gate(14, 0)),
+    Clk::gate(CLK_I2C1, "clk_i2c1", &[CLK_I2C], gate(14, 1)),
+    Clk::gate(PCLK_I2C2, "pclk_i2c2", &[PCLK_BUS], gate(14, 2)),
+    Clk::gate(CLK_I2C2, "clk_i2c2", &[CLK_I2C], gate(14, 3)),
+    Clk::gate(PCLK_I2C3, "pclk_i2c3", &[PCLK_BUS], gate(14, 4)),
+    Clk::gate(CLK_I2C3, "clk_i2c3", &[CLK_I2C], gate(14, 5)),
+    Clk::gate(PCLK_I2C4, "pclk_i2c4", &[PCLK_BUS], gate(14, 6)),
+    Clk::gate(CLK_I2C4, "clk_i2c4", &[CLK_I2C], gate(14, 7)),
+    Clk::gate(PCLK_I2C5, "pclk_i2c5", &[PCLK_BUS], gate(14, 8)),
+    Clk::gate(CLK_I2C5, "clk_i2c5", &[CLK_I2C], gate(14, 9)),
+    Clk::gate(PCLK_SPI0, "pclk_spi0", &[PCLK_BUS], gate(13, 4)),
+    Clk::composite(
+        CLK_SPI0,
+        "clk_spi0",
+        &[GPLL_200M, XIN24M, CPLL_100M],
+        sel(72, 0, 2),
+        None,
+        Some(gate(13, 5)),
+    ),
+    Clk::gate(PCLK_SPI1, "pclk_spi1", &[PCLK_BUS], gate(13, 6)),
+    Clk::composite(
+        CLK_SPI1,
+        "clk_spi1",
+        &[GPLL_200M, XIN24M, CPLL_100M],
+        sel(72, 2, 2),
+        None,
+        Some(gate(13, 7)),
+    ),
+    Clk::gate(PCLK_SPI2, "pclk_spi2", &[PCLK_BUS], gate(13, 8)),
+    Clk::composite(
+        CLK_SPI2,
+        "clk_spi2",
+        &[GPLL_200M, XIN24M, CPLL_100M],
+        sel(72, 4, 2),
+        None,
+        Some(gate(13, 9)),
+    ),
+    Clk::gate(PCLK_SPI3, "pclk_spi3", &[PCLK_BUS], gate(13, 10)),
+    Clk::composite(
+        CLK_SPI3,
+        "clk_spi3",
+        &[GPLL_200M, XIN24M, CPLL_100M],
+        sel(72, 6, 2),
+        None,
+        Some(gate(13, 11)),
+    ),
+];
+
+/// Clocks that are never gated: the bus roots the rest of the SoC hangs
+/// off, and the PLL taps that also feed clocks this table does not model
+/// (`aclk_top_*`, VOP, GPU, ...) and which therefore never take a
+/// reference of their own.
+pub(super) static CRITICAL: &[usize] = &[
+    ACLK_BUS,
+    PCLK_BUS,
+    HCLK_PHP,
+    PCLK_PHP,
+    HCLK_USB,
+    GPLL_400M,
+    GPLL_300M,
+    GPLL_200M,
+    GPLL_150M,
+    GPLL_100M,
+    GPLL_75M,
+    GPLL_20M,
+    CPLL_500M,
+    CPLL_333M,
+    CPLL_250M,
+    CPLL_125M,
+    CPLL_100M,
+    CPLL_62P5M,
+    CPLL_50M,
+    CPLL_25M,
+    OSC0_DIV_750K,
+];
+
+#[cfg(test)]
+pub(in crate::clk) mod tests {
+    use super::*;
+    use crate::clk::pll::{self, PllConfig};
+    use crate::clk::regs::FakeRegs;
+    use crate::clk::tree::{ClkTree, Kind, tests::*};
+
+    /// PLL rates left by the RK3568 bootloader.
+    const PLLS: &[(usize, u64)] = &[
+        (PLL_APLL, 816_000_000),
+        (PLL_DPLL, 528_000_000),
+        (PLL_CPLL, 1_000_000_000),
+        (PLL_GPLL, 1_188_000_000),
+        (PLL_NPLL, 1_200_000_000),
+        (PLL_VPLL, 594_000_000),
+    ];
+
+    /// Fixed taps and their nominal rates.
+    const TAPS: &[(usize, u64)] = &[
+        (GPLL_400M, 400_000_000),
+        (GPLL_300M, 300_000_000),
+        (GPLL_200M, 200_000_000),
+        (GPLL_150M, 150_000_000),
+        (GPLL_100M, 100_000_000),
+        (GPLL_75M, 75_000_000),
+        (GPLL_20M, 20_000_000),
+        (CPLL_500M, 500_000_000),
+        // 1 GHz / 3 向上取整，否则会落到 / 4
+        (CPLL_333M, 333_333_334),
+        (CPLL_250M, 250_000_000),
+        (CPLL_125M, 125_000_000),
+        (CPLL_100M, 100_000_000),
+        (CPLL_62P5M, 62_500_000),
+        (CPLL_50M, 50_000_000),
+        (CPLL_25M, 25_000_000),
+        (OSC0_DIV_750K, 750_000),
+    ];
+
+    /// CRU tree on `fake`, with the PLLs and taps at their boot rates.
+    pub fn booted(fake: &mut FakeRegs) -> ClkTree {
+        let regs = fake.regs();
+        let mut tree = ClkTree::new(regs, CLKS, CRITICAL);
+        for &(id, rate) in PLLS {
+            let Kind::Pll(pll) = tree.find(id).unwrap().kind else {
+                unreachable!()
+            };
+            pll::preset(&regs, &pll, &PllConfig::calc(24_000_000, rate).unwrap());
+        }
+        for &(id, rate) in TAPS {
+            tree.set_rate(id, rate).unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn boot_rates() {
+        let mut fake = FakeRegs::new();
+        let tree = booted(&mut fake);
+
+        for &(id, rate) in PLLS {
+            assert_eq!(tree.rate(id).unwrap(), rate);
+        }
+        assert_eq!(tree.rate(GPLL_400M).unwrap(), 396_000_000);
+        assert_eq!(tree.rate(GPLL_200M).unwrap(), 198_000_000);
+        assert_eq!(tree.rate(CPLL_333M).unwrap(), 333_333_333);
+        assert_eq!(tree.rate(CPLL_125M).unwrap(), 125_000_000);
+        assert_eq!(tree.rate(OSC0_DIV_375K).unwrap(), 375_000);
+    }
+
+    #[test]
+    fn fields_disjoint() {
+        check_fields_disjoint(CLKS);
+    }
+
+    #[test]
+    fn muxes() {
+        let mut fake = FakeRegs::new();
+        check_muxes(&booted(&mut fake));
+    }
+
+    #[test]
+    fn dividers() {
+        let mut fake = FakeRegs::new();
+        check_dividers(&booted(&mut fake));
+    }
+
+    #[test]
+    fn set_rate() {
+        let mut fake = FakeRegs::new();
+        check_set_rate(&mut booted(&mut fake));
+    }
+
+    #[test]
+    fn emmc_rates() {
+        let mut fake = FakeRegs::new();
+        let mut tree = booted(&mut fake);
+
+        assert_eq!(tree.set_rate(CCLK_EMMC, 400_000).unwrap(), 375_000);
+        assert_eq!(
+            tree.parent(tree.find(CCLK_EMMC).unwrap()),
+            Some(OSC0_DIV_375K)
+        );
+        assert_eq!(tree.set_rate(CCLK_EMMC, 200_000_000).unwrap(), 198_000_000);
+        assert_eq!(tree.set_rate(CCLK_EMMC, 52_000_000).unwrap(), 50_000_000);
+        assert_eq!(tree.rate(CCLK_EMMC).unwrap(), 50_000_000);
+        assert!(tree.set_rate(CCLK_EMMC, 300_000).is_err());
+    }
+
+    #[test]
+    fn gate_programs_parent() {
+        let mut fake = FakeRegs::new();
+        let mut tree = booted(&mut fake);
+
+        assert_eq!(tree.set_rate(ACLK_EMMC, 250_000_000).unwrap(), 198_000_000);
+        assert_eq!(tree.rate(ACLK_NVM_ROOT).unwrap(), 198_000_000);
+        assert_eq!(tree.set_rate(ACLK_EMMC, 300_000_000).unwrap(), 297_000_000);
+        assert_eq!(tree.rate(ACLK_EMMC).unwrap(), 297_000_000);
+
+        // aclk_emmc 自己的引用不算共享
+        tree.enable(ACLK_EMMC).unwrap();
+        assert_eq!(tree.set_rate(ACLK_EMMC, 250_000_000).unwrap(), 198_000_000);
+        tree.disable(ACLK_EMMC).unwrap();
+    }
+
+    #[test]
+    fn gate_keeps_shared_parent() {
+        let mut fake = FakeRegs::new();
+        let mut tree = booted(&mut fake);
+        tree.set_rate(ACLK_NVM_ROOT, 300_000_000).unwrap();
+        tree.enable(ACLK_EMMC).unwrap();
+        tree.enable(ACLK_NVM_ROOT).unwrap();
+
+        assert!(tree.set_rate(ACLK_EMMC, 200_000_000).is_err());
+        assert_eq!(tree.rate(ACLK_NVM_ROOT).unwrap(), 297_000_000);
+        // 父时钟已经是这个频率时照样接受
+        assert_eq!(tree.set_rate(ACLK_EMMC, 300_000_000).unwrap(), 297_000_000);
+
+        tree.enable_critical().unwrap();
+        let bus = tree.rate(PCLK_BUS).unwrap();
+        assert!(tree.set_rate(PCLK_UART2, bus / 2).is_err());
+        assert_eq!(tree.rate(PCLK_BUS).unwrap(), bus);
+    }
+
+    #[test]
+    fn uart_frac() {
+        let mut fake = FakeRegs::new();
+        let mut tree = booted(&mut fake);
+
+        // 115200 baud 需要 16 倍采样时钟，即 1.8432 MHz
+        tree.set_parent(SCLK_UART2_MUX, CLK_UART2_FRAC).unwrap();
+        assert_eq!(
+            tree.set_rate(CLK_UART2_SRC, 100_000_000).unwrap(),
+            100_000_000
+        );
+        assert_eq!(tree.set_rate(CLK_UART2_FRAC, 1_843_200).unwrap(), 1_843_200);
+        assert_eq!(tree.rate(SCLK_UART2).unwrap(), 1_843_200);
+        assert!(tree.set_rate(CLK_UART2_FRAC, 100_000_000).is_err());
+    }
+
+    #[test]
+    fn pll_rate() {
+        let mut fake = FakeRegs::new();
+        let mut tree = booted(&mut fake);
+
+        assert_eq!(tree.round_rate(PLL_VPLL, 148_500_000).unwrap(), 148_500_000);
+        assert_eq!(tree.set_rate(PLL_VPLL, 148_500_000).unwrap(), 148_500_000);
+        assert_eq!(tree.rate(PLL_VPLL).unwrap(), 148_500_000);
+        assert!(tree.set_rate(PLL_VPLL, 1_000).is_err());
+    }
+
+    fn gated(tree: &ClkTree, id: usize) -> bool {
+        !tree.is_enabled(id).unwrap()
+    }
+
+    #[test]
+    fn disable_gates_unused_clocks() {
+        let mut fake = FakeRegs::new();
+        let mut tree = booted(&mut fake);
+        // bootloader 留着 eMMC 时钟，不应影响引用计数
+        for id in [ACLK_EMMC, CCLK_EMMC, ACLK_NVM_ROOT, GPLL_300M] {
+            assert!(!gated(&tree, id), "{:#x}", id);
+        }
+
+        tree.enable(ACLK_EMMC).unwrap();
+        tree.enable(CCLK_EMMC).unwrap();
+        assert_eq!(tree.enable_count(ACLK_NVM_ROOT), 1);
+        assert_eq!(tree.enable_count(GPLL_300M), 1);
+
+        tree.disable(ACLK_EMMC).unwrap();
+        tree.disable(CCLK_EMMC).unwrap();
+        for id in [ACLK_EMMC, CCLK_EMMC, ACLK_NVM_ROOT, GPLL_300M] {
+            assert!(gated(&tree, id), "{:#x}", id);
+            assert_eq!(tree.enable_count(id), 0);
+        }
+        assert!(tree.disable(ACLK_EMMC).is_err());
+    }
+
+    #[test]
+    fn critical_clocks_stay_open() {
+        let mut fake = FakeRegs::new();
+        let mut tree = booted(&mut fake);
+        tree.enable_critical().unwrap();
+        for &id in CRITICAL {
+            assert!(!gated(&tree, id), "{:#x}", id);
+            assert!(tree.enable_count(id) >= 1, "{:#x}", id);
+        }
+
+        tree.enable(ACLK_EMMC).unwrap();
+        tree.disable(ACLK_EMMC).unwrap();
+        assert!(gated(&tree, ACLK_EMMC));
+        assert!(gated(&tree, ACLK_NVM_ROOT));
+        assert!(!gated(&tree, GPLL_300M));
+        assert!(tree.enable_count(GPLL_300M) >= 1);
+    }
+
+    #[test]
+    fn ppll_from_pmucru() {
+        let mut fake = FakeRegs::new();
+        let mut pmu = FakeRegs::new();
+        let mut tree = booted(&mut fake);
+
+        tree.set_parent(CLK_MAC0_2TOP, PPLL).unwrap();
+        assert_eq!(tree.rate(CLK_MAC0_2TOP).unwrap(), 200_000_000);
+
+        let regs = pmu.regs();
+        let config = PllConfig::calc(24_000_000, 100_000_000).unwrap();
+        pll::preset(&regs, &pmucru::PPLL, &config);
+        tree.set_peer(regs);
+        assert_eq!(tree.rate(CLK_MAC0_2TOP).unwrap(), 100_000_000);
+        // PPLL 只能由 PMUCRU 调整
+        assert!(tree.set_rate(PPLL, 50_000_000).is_err());
+    }
+
+    #[test]
+    fn fixed_clocks_keep_rate() {
+        let mut fake = FakeRegs::new();
+        let mut tree = booted(&mut fake);
+
+        assert_eq!(tree.round_rate(XIN24M, 25_000_000).unwrap(), 24_000_000);
+        assert_eq!(tree.set_rate(USB480M, 480_000_000).unwrap(), 480_000_000);
+        assert!(tree.round_rate(XIN24M, 12_000_000).is_err());
+        assert_eq!(tree.round_rate(OSC0_DIV_375K, 400_000).unwrap(), 375_000);
+    }
+}
@@ -0,0 +1,258 @@
+extern crate alloc;
+
+use alloc::{format, vec::Vec};
+use core::time::Duration;
+
+use axklib::time::busy_wait;
+use log::warn;
+use rdrive::fdt::{Node, Phandle};
+use rdrive::{Device, DeviceGuard, DriverGeneric, KError, get_list, probe::OnProbeError};
+
+use super::{ClkDriver, Cru, PmuClkDriver};
+
+/// How long [`lock`] waits for a device that is in use elsewhere.
+const LOCK_POLL: Duration = Duration::from_micros(10);
+const LOCK_ATTEMPTS: u32 = 1000;
+
+/// A registered clock unit.
+#[derive(Clone)]
+pub enum ClkProvider {
+    Cru(Device<ClkDriver>),
+    Pmu(Device<PmuClkDriver>),
+}
+
+impl ClkProvider {
+    /// Finds the registered CRU or PMUCRU whose node has `phandle`.
+    pub fn find(phandle: Phandle) -> Result<Option<Self>, KError> {
+        if let Some(dev) = find_provider(phandle, |clk: &ClkDriver| clk.phandle())? {
+            return Ok(Some(ClkProvider::Cru(dev)));
+        }
+        Ok(find_provider(phandle, |clk: &PmuClkDriver| clk.phandle())?.map(ClkProvider::Pmu))
+    }
+
+    /// Runs `f` on the clock unit, waiting while another user holds it.
+    pub fn with<T>(&self, f: impl FnOnce(&mut Cru) -> Result<T, KError>) -> Result<T, KError> {
+        match self {
+            ClkProvider::Cru(dev) => {
+                let mut clk = lock(dev)?;
+                f(&mut clk)
+            }
+            ClkProvider::Pmu(dev) => {
+                let mut clk = lock(dev)?;
+                f(&mut clk)
+            }
+        }
+    }
+}
+
+/// A clock specifier of a consumer node, resolved to the registered CRU or
+/// PMUCRU.
+#[derive(Clone)]
+pub struct ClkRef {
+    pub provider: ClkProvider,
+    pub id: usize,
+}
+
+fn cells(node: &Node<'_>, name: &str) -> Vec<u32> {
+    node.find_property(name)
+        .map(|prop| {
+            prop.raw_value()
+                .chunks_exact(4)
+                .map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Splits property `name` into `(phandle, clock ID)` pairs.
+///
+/// Rockchip clock units use `#clock-cells = <1>`, so every specifier is
+/// two cells. A lone zero cell is an empty placeholder and yields `None`.
+pub fn specifiers(node: &Node<'_>, name: &str) -> Vec<Option<(Phandle, usize)>> {
+    let cells = cells(node, name);
+    let mut specs = Vec::new();
+    let mut iter = cells.into_iter();
+
+    while let Some(phandle) = iter.next() {
+        if phandle == 0 {
+            specs.push(None);
+            continue;
+        }
+        let Some(id) = iter.next() else {
+            warn!("[{}] {}: truncated specifier", node.name(), name);
+            break;
+        };
+        specs.push(Some((Phandle::from(phandle), id as usize)));
+    }
+
+    specs
+}
+
+/// Locks `dev`, waiting for a while if another user holds it.
+pub(crate) fn lock<T: DriverGeneric>(dev: &Device<T>) -> Result<DeviceGuard<T>, KError> {
+    let mut attempts = 0;
+    loop {
+        match dev.lock() {
+            Ok(guard) => return Ok(guard),
+            Err(err) => {
+                attempts += 1;
+                if attempts >= LOCK_ATTEMPTS {
+                    warn!("Device still busy: {:?}", err);
+                    return Err(KError::Unknown("device busy"));
+                }
+                busy_wait(LOCK_POLL);
+            }
+        }
+    }
+}
+
+/// Finds the registered device of type `T` whose node has `phandle`.
+///
+/// Devices in use are waited for, so a busy provider is not mistaken for a
+/// missing one.
+pub(crate) fn find_provider<T: DriverGeneric>(
+    phandle: Phandle,
+    phandle_of: impl Fn(&T) -> Option<Phandle>,
+) -> Result<Option<Device<T>>, KError> {
+    for dev in get_list::<T>() {
+        if phandle_of(&*lock(&dev)?) == Some(phandle) {
+            return Ok(Some(dev));
+        }
+    }
+    Ok(None)
+}
+
+/// Index of the entry `name` in the string list property `names`, e.g.
+/// `clock-names`.
+pub fn name_index(node: &Node<'_>, names: &str, name: &str) -> Option<usize> {
+    node.find_property(names).and_then(|names| {
+        names
+            .raw_value()
+            .split(|&b| b == 0)
+            .position(|entry| entry == name.as_bytes())
+    })
+}
+
+fn resolve(node: &Node<'_>, (phandle, id): (Phandle, usize)) -> Result<ClkRef, OnProbeError> {
+    let provider = ClkProvider::find(phandle)
+        .map_err(|err| {
+            OnProbeError::other(format!(
+                "[{}] clock provider {:?}: {:?}",
+                node.name(),
+                phandle,
+                err
+            ))
+        })?
+        .ok_or_else(|| {
+            OnProbeError::other(format!(
+                "[{}] clock provider {:?} not found",
+                node.name(),
+                phandle
+            ))
+        })?;
+    Ok(ClkRef { provider, id })
+}
+
+/// Resolves every entry of the `clocks` property of `node`.
+pub fn clocks(node: &Node<'_>) -> Result<Vec<ClkRef>, OnProbeError> {
+    specifiers(node, "clocks")
+        .into_iter()
+        .flatten()
+        .map(|spec| resolve(node, spec))
+        .collect()
+}
+
+/// Resolves the `clocks` entry that `clock-names` calls `name`.
+pub fn clock(node: &Node<'_>, name: &str) -> Result<ClkRef, OnProbeError> {
+    let index = name_index(node, "clock-names", name).ok_or_else(|| {
+        OnProbeError::other(format!("[{}] has no \"{}\" clock", node.name(), name))
+    })?;
+
+    let spec = specifiers(node, "clocks")
+        .get(index)
+        .copied()
+        .flatten()
+        .ok_or_else(|| {
+            OnProbeError::other(format!("[{}] clocks has no entry {}", node.name(), index))
+        })?;
+
+    resolve(node, spec)
+}
+
+/// Runs `f` on the clock unit with `phandle`: `local` if it matches, else a
+/// registered CRU or PMUCRU found through [`ClkProvider::find`].
+fn with_cru<T>(
+    phandle: Phandle,
+    local: &mut Option<&mut Cru>,
+    f: impl FnOnce(&mut Cru) -> Result<T, KError>,
+) -> Result<T, KError> {
+    if let Some(cru) = local.as_deref_mut()
+        && cru.phandle() == Some(phandle)
+    {
+        return f(cru);
+    }
+
+    if let Some(provider) = ClkProvider::find(phandle)? {
+        return provider.with(f);
+    }
+
+    warn!("Clock provider {:?} not registered", phandle);
+    Err(KError::InvalidArg {
+        name: "clock_provider",
+    })
+}
+
+/// Applies the `assigned-clocks`, `assigned-clock-parents` and
+/// `assigned-clock-rates` properties of `node`: all parents first, then all
+/// rates, as Linux does.
+///
+/// A clock unit applying its own node passes itself as `local`, since it is
+/// not registered yet. Every entry is attempted; the first error is returned.
+pub fn apply_assigned_clocks(node: &Node<'_>, mut local: Option<&mut Cru>) -> Result<(), KError> {
+    let clocks = specifiers(node, "assigned-clocks");
+    if clocks.is_empty() {
+        return Ok(());
+    }
+    let parents = specifiers(node, "assigned-clock-parents");
+    let rates = cells(node, "assigned-clock-rates");
+    let mut result = Ok(());
+
+    for (index, &clock) in clocks.iter().enumerate() {
+        let (Some((phandle, id)), Some(&Some((parent_phandle, parent)))) =
+            (clock, parents.get(index))
+        else {
+            continue;
+        };
+
+        // 父时钟只能在同一个时钟单元内切换
+        let applied = if parent_phandle != phandle {
+            warn!(
+                "[{}] clock {} parent in another clock unit",
+                node.name(),
+                id
+            );
+            Err(KError::InvalidArg { name: "parent" })
+        } else {
+            with_cru(phandle, &mut local, |cru| {
+                cru.set_parent(id.into(), parent.into())
+            })
+        };
+        result = result.and(applied);
+    }
+
+    for (index, &clock) in clocks.iter().enumerate() {
+        let (Some((phandle, id)), Some(&rate)) = (clock, rates.get(index)) else {
+            continue;
+        };
+        if rate == 0 {
+            continue;
+        }
+
+        let applied = with_cru(phandle, &mut local, |cru| {
+            cru.set_rounded_rate(id.into(), rate as u64).map(|_| ())
+        });
+        result = result.and(applied);
+    }
+
+    result
+}
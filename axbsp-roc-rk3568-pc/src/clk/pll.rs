@@ -0,0 +1,313 @@
+use core::time::Duration;
+
+use axklib::time::busy_wait;
+use log::warn;
+use rdrive::KError;
+
+use super::regs::Regs;
+
+/// Frequency of the 32.768 kHz clock used in PLL deep-slow mode.
+pub const DEEP_SLOW_HZ: u64 = 32_768;
+
+const PLL_CON0: usize = 0x0;
+const PLL_CON1: usize = 0x4;
+const PLL_CON2: usize = 0x8;
+
+const CON0_BYPASS_SHIFT: u32 = 15;
+const CON0_POSTDIV1_SHIFT: u32 = 12;
+const CON0_FBDIV_SHIFT: u32 = 0;
+const CON1_PWRDOWN_SHIFT: u32 = 13;
+const CON1_DSMPD_SHIFT: u32 = 12;
+const CON1_LOCK_SHIFT: u32 = 10;
+const CON1_POSTDIV2_SHIFT: u32 = 6;
+const CON1_REFDIV_SHIFT: u32 = 0;
+const CON2_FRAC_SHIFT: u32 = 0;
+
+const MODE_SLOW: u32 = 0;
+const MODE_NORMAL: u32 = 1;
+const MODE_DEEP_SLOW: u32 = 2;
+
+// 以下范围取自 RK3568 TRM 的 PLL 章节
+const FREF_MIN_HZ: u64 = 1_000_000;
+const FREF_MAX_HZ: u64 = 800_000_000;
+const VCO_MIN_HZ: u64 = 800_000_000;
+const VCO_MAX_HZ: u64 = 3_200_000_000;
+const FBDIV_INT: (u64, u64) = (16, 2500);
+const FBDIV_FRAC: (u64, u64) = (20, 500);
+const REFDIV_MAX: u32 = 63;
+const POSTDIV_MAX: u32 = 7;
+
+/// Polling interval and attempts while waiting for a PLL to lock.
+const LOCK_POLL: Duration = Duration::from_micros(1);
+const LOCK_ATTEMPTS: u32 = 1000;
+
+/// A Rockchip PLL: its five `PLL_CON` registers and its field in the
+/// `MODE_CON00` register of the same clock unit.
+#[derive(Debug, Clone, Copy)]
+pub struct Pll {
+    pub con: usize,
+    pub mode_con: usize,
+    pub mode_shift: u32,
+}
+
+/// Divider settings of a PLL as programmed in its `PLL_CON` registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PllConfig {
+    pub refdiv: u32,
+    pub fbdiv: u32,
+    pub postdiv1: u32,
+    pub postdiv2: u32,
+    /// Fractional part of the feedback divider, in units of 2^-24.
+    pub frac: u32,
+    /// `true` when the delta-sigma modulator is off (integer mode).
+    pub dsmpd: bool,
+}
+
+impl PllConfig {
+    pub fn read(regs: &Regs, pll: &Pll) -> Self {
+        PllConfig {
+            refdiv: regs.field(pll.con + PLL_CON1, CON1_REFDIV_SHIFT, 6),
+            fbdiv: regs.field(pll.con + PLL_CON0, CON0_FBDIV_SHIFT, 12),
+            postdiv1: regs.field(pll.con + PLL_CON0, CON0_POSTDIV1_SHIFT, 3),
+            postdiv2: regs.field(pll.con + PLL_CON1, CON1_POSTDIV2_SHIFT, 3),
+            frac: regs.field(pll.con + PLL_CON2, CON2_FRAC_SHIFT, 24),
+            dsmpd: regs.field(pll.con + PLL_CON1, CON1_DSMPD_SHIFT, 1) != 0,
+        }
+    }
+
+    /// Output frequency for the reference clock `parent`.
+    ///
+    /// FOUT = parent / refdiv * (fbdiv + frac / 2^24) / postdiv1 / postdiv2
+    pub fn rate(&self, parent: u64) -> u64 {
+        let refdiv = self.refdiv.max(1) as u64;
+        let postdiv = (self.postdiv1.max(1) * self.postdiv2.max(1)) as u64;
+
+        let mut vco = parent * self.fbdiv as u64;
+        if !self.dsmpd {
+            vco += (parent * self.frac as u64) >> 24;
+        }
+
+        vco / refdiv / postdiv
+    }
+
+    /// Finds the divider settings whose output is closest to `rate` without
+    /// exceeding it, preferring integer mode on ties.
+    pub fn calc(parent: u64, rate: u64) -> Option<Self> {
+        let mut best: Option<(u64, PllConfig)> = None;
+
+        for refdiv in 1..=REFDIV_MAX {
+            let fref = parent / refdiv as u64;
+            if !(FREF_MIN_HZ..=FREF_MAX_HZ).contains(&fref) {
+                continue;
+            }
+
+            for postdiv1 in 1..=POSTDIV_MAX {
+                for postdiv2 in 1..=postdiv1 {
+                    let vco = rate * (postdiv1 * postdiv2) as u64;
+                    if !(VCO_MIN_HZ..=VCO_MAX_HZ).contains(&vco) {
+                        continue;
+                    }
+
+                    // fbdiv + frac / 2^24 = vco * refdiv / parent，向下取整
+                    let scaled = ((vco * refdiv as u64) as u128) << 24;
+                    let fb = (scaled / parent as u128) as u64;
+                    let (fbdiv, frac) = (fb >> 24, (fb & 0xff_ffff) as u32);
+
+                    let (min, max) = if frac == 0 { FBDIV_INT } else { FBDIV_FRAC };
+                    if !(min..=max).contains(&fbdiv) {
+                        continue;
+                    }
+
+                    let config = PllConfig {
+                        refdiv,
+                        fbdiv: fbdiv as u32,
+                        postdiv1,
+                        postdiv2,
+                        frac,
+                        dsmpd: frac == 0,
+                    };
+                    let achieved = config.rate(parent);
+                    if achieved > rate {
+                        continue;
+                    }
+
+                    let better = best.as_ref().is_none_or(|(rate, current)| {
+                        achieved > *rate || (achieved == *rate && config.dsmpd && !current.dsmpd)
+                    });
+                    if better {
+                        best = Some((achieved, config));
+                    }
+                }
+            }
+        }
+
+        best.map(|(_, config)| config)
+    }
+
+    fn write(&self, regs: &Regs, pll: &Pll) {
+        regs.write_field(pll.con + PLL_CON0, CON0_BYPASS_SHIFT, 1, 0);
+        regs.write_field(pll.con + PLL_CON0, CON0_POSTDIV1_SHIFT, 3, self.postdiv1);
+        regs.write_field(pll.con + PLL_CON0, CON0_FBDIV_SHIFT, 12, self.fbdiv);
+        regs.write_field(pll.con + PLL_CON1, CON1_DSMPD_SHIFT, 1, self.dsmpd as u32);
+        regs.write_field(pll.con + PLL_CON1, CON1_POSTDIV2_SHIFT, 3, self.postdiv2);
+        regs.write_field(pll.con + PLL_CON1, CON1_REFDIV_SHIFT, 6, self.refdiv);
+
+        if !self.dsmpd {
+            // CON2 没有写掩码，需要读改写
+            let con2 = regs.read(pll.con + PLL_CON2) & !(0xff_ffff << CON2_FRAC_SHIFT);
+            regs.write(pll.con + PLL_CON2, con2 | (self.frac << CON2_FRAC_SHIFT));
+        }
+    }
+}
+
+/// Current output frequency of `pll`, given its reference clock `parent`.
+pub fn rate(regs: &Regs, pll: &Pll, parent: u64) -> u64 {
+    match regs.field(pll.mode_con, pll.mode_shift, 2) {
+        MODE_SLOW => parent,
+        MODE_NORMAL => {
+            if regs.field(pll.con + PLL_CON0, CON0_BYPASS_SHIFT, 1) != 0 {
+                parent
+            } else {
+                PllConfig::read(regs, pll).rate(parent)
+            }
+        }
+        MODE_DEEP_SLOW => DEEP_SLOW_HZ,
+        _ => 0,
+    }
+}
+
+/// Reprograms `pll` with `config`, given its reference clock `parent`.
+///
+/// Nothing is touched when the PLL already runs at the rate of `config`, so
+/// clocks in use do not glitch. Otherwise the PLL output is switched to the
+/// oscillator while the dividers change and only switched back once the PLL
+/// reports lock. On a lock timeout the previous dividers and mode are
+/// restored; if the PLL does not lock on those either, it is left in slow
+/// mode.
+pub fn set_rate(regs: &Regs, pll: &Pll, parent: u64, config: &PllConfig) -> Result<(), KError> {
+    let mode = regs.field(pll.mode_con, pll.mode_shift, 2);
+    let old = PllConfig::read(regs, pll);
+    let bypassed = regs.field(pll.con + PLL_CON0, CON0_BYPASS_SHIFT, 1) != 0;
+    if mode == MODE_NORMAL
+        && !bypassed
+        && (old == *config || old.rate(parent) == config.rate(parent))
+    {
+        return Ok(());
+    }
+
+    regs.write_field(pll.mode_con, pll.mode_shift, 2, MODE_SLOW);
+    if let Err(err) = relock(regs, pll, config) {
+        warn!("PLL at {:#x} failed to lock: {:?}", pll.con, config);
+        match relock(regs, pll, &old) {
+            Ok(()) => regs.write_field(pll.mode_con, pll.mode_shift, 2, mode),
+            Err(_) => warn!("PLL at {:#x} left in slow mode: {:?}", pll.con, old),
+        }
+        return Err(err);
+    }
+
+    regs.write_field(pll.mode_con, pll.mode_shift, 2, MODE_NORMAL);
+    Ok(())
+}
+
+/// Powers `pll` down, writes `config` and waits for the PLL to lock again.
+/// The PLL has to be in slow mode.
+fn relock(regs: &Regs, pll: &Pll, config: &PllConfig) -> Result<(), KError> {
+    regs.write_field(pll.con + PLL_CON1, CON1_PWRDOWN_SHIFT, 1, 1);
+    config.write(regs, pll);
+    regs.write_field(pll.con + PLL_CON1, CON1_PWRDOWN_SHIFT, 1, 0);
+
+    let mut attempts = 0;
+    while regs.field(pll.con + PLL_CON1, CON1_LOCK_SHIFT, 1) == 0 {
+        attempts += 1;
+        if attempts >= LOCK_ATTEMPTS {
+            return Err(KError::Unknown("PLL lock timeout"));
+        }
+        busy_wait(LOCK_POLL);
+    }
+
+    Ok(())
+}
+
+/// Leaves `pll` locked at `config` in normal mode, the way the bootloader
+/// hands it over.
+#[cfg(test)]
+pub fn preset(regs: &Regs, pll: &Pll, config: &PllConfig) {
+    config.write(regs, pll);
+    regs.write_field(pll.con + PLL_CON1, CON1_LOCK_SHIFT, 1, 1);
+    regs.write_field(pll.mode_con, pll.mode_shift, 2, MODE_NORMAL);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clk::regs::FakeRegs;
+
+    const PLL: Pll = Pll {
+        con: 0x40,
+        mode_con: 0xc0,
+        mode_shift: 6,
+    };
+    const OSC: u64 = 24_000_000;
+
+    #[test]
+    fn calc_rates() {
+        let config = PllConfig::calc(OSC, 1_188_000_000).unwrap();
+        assert!(config.dsmpd);
+        assert_eq!(config.rate(OSC), 1_188_000_000);
+
+        // 其余频率允许少量误差，但不能超过目标
+        for rate in [148_500_000, 100_000_000, 1_000_000_001, 2_000_000_000] {
+            let config = PllConfig::calc(OSC, rate).unwrap();
+            assert!(config.rate(OSC) <= rate);
+            assert!(rate - config.rate(OSC) < 1_000, "{} Hz", rate);
+        }
+        assert_eq!(PllConfig::calc(OSC, 1_000), None);
+    }
+
+    #[test]
+    fn same_rate_is_kept() {
+        let mut fake = FakeRegs::new();
+        let regs = fake.regs();
+        let config = PllConfig::calc(OSC, 1_188_000_000).unwrap();
+        preset(&regs, &PLL, &config);
+        // 不再锁定：只要重新编程就会超时
+        regs.write_field(PLL.con + PLL_CON1, CON1_LOCK_SHIFT, 1, 0);
+
+        set_rate(&regs, &PLL, OSC, &config).unwrap();
+        let same = PllConfig {
+            refdiv: config.refdiv * 2,
+            fbdiv: config.fbdiv * 2,
+            ..config
+        };
+        set_rate(&regs, &PLL, OSC, &same).unwrap();
+        assert_eq!(PllConfig::read(&regs, &PLL), config);
+        assert_eq!(rate(&regs, &PLL, OSC), 1_188_000_000);
+    }
+
+    #[test]
+    fn new_rate_relocks() {
+        let mut fake = FakeRegs::new();
+        let regs = fake.regs();
+        preset(&regs, &PLL, &PllConfig::calc(OSC, 1_188_000_000).unwrap());
+
+        let config = PllConfig::calc(OSC, 1_000_000_000).unwrap();
+        set_rate(&regs, &PLL, OSC, &config).unwrap();
+        assert_eq!(PllConfig::read(&regs, &PLL), config);
+        assert_eq!(rate(&regs, &PLL, OSC), 1_000_000_000);
+    }
+
+    #[test]
+    fn lock_timeout_restores_dividers() {
+        let mut fake = FakeRegs::new();
+        let regs = fake.regs();
+        let old = PllConfig::calc(OSC, 1_188_000_000).unwrap();
+        preset(&regs, &PLL, &old);
+        regs.write_field(PLL.con + PLL_CON1, CON1_LOCK_SHIFT, 1, 0);
+
+        let config = PllConfig::calc(OSC, 1_000_000_000).unwrap();
+        assert!(set_rate(&regs, &PLL, OSC, &config).is_err());
+        assert_eq!(PllConfig::read(&regs, &PLL), old);
+        // 旧配置也锁不住，只能停在 slow 模式
+        assert_eq!(rate(&regs, &PLL, OSC), OSC);
+    }
+}
@@ -0,0 +1,99 @@
+use core::ops::{Deref, DerefMut};
+
+use log::{debug, info, warn};
+use rdif_clk::{ClockId, Interface};
+use rdrive::fdt::Phandle;
+use rdrive::{DriverGeneric, KError};
+use rdrive::{PlatformDevice, probe::OnProbeError};
+use rdrive::{module_driver, register::FdtInfo};
+
+use super::regs::Regs;
+use super::{ClkDriver, Cru, dt, iomap_node, link_peer, pmucru};
+use crate::sibling_device;
+
+/// RK3568 PMUCRU: the always-on clock unit owning PPLL/HPLL, the 32k clock,
+/// UART0, I2C0 and GPIO0. Clock IDs are those of the `pmucru` namespace.
+pub struct PmuClkDriver(Cru);
+
+impl PmuClkDriver {
+    pub fn new(pmucru_address: u64, phandle: Option<Phandle>) -> Self {
+        PmuClkDriver(Cru::new(
+            Regs::new(pmucru_address as usize),
+            pmucru::CLKS,
+            pmucru::CRITICAL,
+            pmucru::SOFTRST_CON,
+            pmucru::SOFTRST_CON_COUNT,
+            phandle,
+        ))
+    }
+}
+
+impl Deref for PmuClkDriver {
+    type Target = Cru;
+
+    fn deref(&self) -> &Cru {
+        &self.0
+    }
+}
+
+impl DerefMut for PmuClkDriver {
+    fn deref_mut(&mut self) -> &mut Cru {
+        &mut self.0
+    }
+}
+
+impl DriverGeneric for PmuClkDriver {
+    fn open(&mut self) -> Result<(), KError> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), KError> {
+        Ok(())
+    }
+}
+
+impl Interface for PmuClkDriver {
+    fn perper_enable(&mut self) {
+        debug!("perper_enable");
+    }
+
+    fn get_rate(&self, id: ClockId) -> Result<u64, KError> {
+        self.0.get_rate(id)
+    }
+
+    fn set_rate(&mut self, id: ClockId, rate: u64) -> Result<(), KError> {
+        self.set_rounded_rate(id, rate).map(|_| ())
+    }
+}
+
+module_driver!(
+    name: "Rockchip PMU Clock",
+    level: ProbeLevel::PostKernel,
+    priority: ProbePriority::CLK,
+    probe_kinds: &[
+        ProbeKind::Fdt {
+            compatibles: &["rockchip,rk3568-pmucru"],
+            on_probe: probe_pmucru
+        }
+    ],
+);
+
+fn probe_pmucru(info: FdtInfo<'_>, plat_dev: PlatformDevice) -> Result<(), OnProbeError> {
+    info!("Probing Rockchip RK3568 PMU Clock...");
+
+    let pmucru_address = iomap_node(&info, "PMUCRU")?;
+
+    let mut clk = PmuClkDriver::new(pmucru_address, info.node.phandle());
+    link_peer::<ClkDriver>(&mut clk);
+    if let Err(err) = clk.enable_critical() {
+        warn!("PMUCRU: critical clocks not held: {:?}", err);
+    }
+    if let Err(err) = dt::apply_assigned_clocks(&info.node, Some(&mut *clk)) {
+        warn!("PMUCRU: assigned clocks not fully applied: {:?}", err);
+    }
+
+    sibling_device(&plat_dev).register(clk.resets().clone());
+    plat_dev.register(clk);
+
+    Ok(())
+}
@@ -0,0 +1,267 @@
+use super::cru;
+use super::pll::Pll;
+use super::tree::{Clk, Field, Gate};
+
+const MODE_CON: usize = 0x80;
+
+/// Offset of `PMU_SOFTRST_CON00` and number of `PMU_SOFTRST_CON` registers.
+pub(super) const SOFTRST_CON: usize = 0x200;
+pub(super) const SOFTRST_CON_COUNT: usize = 2;
+
+const fn pll_con(index: usize) -> usize {
+    index * 4
+}
+
+const fn clksel_con(index: usize) -> usize {
+    0x100 + index * 4
+}
+
+const fn clkgate_con(index: usize) -> usize {
+    0x180 + index * 4
+}
+
+const fn sel(con: usize, shift: u32, width: u32) -> Option<Field> {
+    Some(Field {
+        con: clksel_con(con),
+        shift,
+        width,
+    })
+}
+
+const fn gate(con: usize, bit: u32) -> Gate {
+    Gate {
+        con: clkgate_con(con),
+        bit,
+    }
+}
+
+/// PPLL, which feeds the GMAC reference clocks of the CRU.
+pub(super) const PPLL: Pll = Pll {
+    con: pll_con(0),
+    mode_con: MODE_CON,
+    mode_shift: 0,
+};
+
+pub const PLL_PPLL: usize = 1;
+pub const PLL_HPLL: usize = 2;
+pub const XIN_OSC0_DIV: usize = 4;
+pub const CLK_RTC_32K: usize = 5;
+pub const CLK_PMU: usize = 6;
+pub const CLK_I2C0: usize = 7;
+pub const CLK_RTC32K_FRAC: usize = 8;
+pub const CLK_UART0_DIV: usize = 9;
+pub const CLK_UART0_FRAC: usize = 10;
+pub const SCLK_UART0: usize = 11;
+pub const DBCLK_GPIO0: usize = 12;
+pub const CLK_PWM0: usize = 13;
+
+pub const PCLK_PDPMU: usize = 42;
+pub const PCLK_PMU: usize = 43;
+pub const PCLK_UART0: usize = 44;
+pub const PCLK_I2C0: usize = 45;
+pub const PCLK_GPIO0: usize = 46;
+pub const PCLK_PWM0: usize = 48;
+pub const CLK_PDPMU: usize = 49;
+
+/// First ID used for clocks that have no dt-binding ID.
+pub const INTERNAL: usize = 0x1000;
+
+const XIN24M: usize = INTERNAL;
+const XIN32K: usize = INTERNAL + 1;
+const CLK_32K_PVTM: usize = INTERNAL + 2;
+const SCLK_UART0_MUX: usize = INTERNAL + 3;
+const GPLL: usize = INTERNAL + 4;
+const CPLL: usize = INTERNAL + 5;
+const USB480M: usize = INTERNAL + 6;
+
+/// RK3568 PMUCRU clock tree, the always-on domain. Public IDs follow the
+/// `pmucru` part of the `rk3568-cru.h` dt-binding.
+pub(super) static CLKS: &[Clk] = &[
+    Clk::fixed(XIN24M, "xin24m", 24_000_000),
+    Clk::fixed(XIN32K, "xin32k", 32_768),
+    Clk::fixed(CLK_32K_PVTM, "clk_32k_pvtm", 32_768),
+    Clk::peer(GPLL, "gpll", &[XIN24M], cru::GPLL, 1_188_000_000),
+    Clk::peer(CPLL, "cpll", &[XIN24M], cru::CPLL, 1_000_000_000),
+    // usb480m 来自 USB PHY，按其标称频率处理
+    Clk::fixed(USB480M, "usb480m", 480_000_000),
+    Clk::pll(PLL_PPLL, "ppll", &[XIN24M], PPLL),
+    Clk::pll(
+        PLL_HPLL,
+        "hpll",
+        &[XIN24M],
+        Pll {
+            con: pll_con(16),
+            mode_con: MODE_CON,
+            mode_shift: 2,
+        },
+    ),
+    Clk::composite(
+        XIN_OSC0_DIV,
+        "xin_osc0_div",
+        &[XIN24M],
+        None,
+        sel(0, 0, 5),
+        Some(gate(0, 0)),
+    ),
+    Clk::frac(
+        CLK_RTC32K_FRAC,
+        "clk_rtc32k_frac",
+        &[XIN24M],
+        clksel_con(1),
+        Some(gate(0, 1)),
+    ),
+    Clk::composite(
+        CLK_RTC_32K,
+        "clk_rtc_32k",
+        &[CLK_32K_PVTM, XIN32K, CLK_RTC32K_FRAC],
+        sel(0, 6, 2),
+        None,
+        None,
+    ),
+    Clk::composite(
+        CLK_PDPMU,
+        "clk_pdpmu",
+        &[PLL_PPLL, GPLL],
+        sel(2, 15, 1),
+        None,
+        None,
+    ),
+    Clk::composite(
+        PCLK_PDPMU,
+        "pclk_pdpmu",
+        &[CLK_PDPMU],
+        None,
+        sel(2, 0, 5),
+        Some(gate(0, 2)),
+    ),
+    Clk::gate(PCLK_PMU, "pclk_pmu", &[PCLK_PDPMU], gate(0, 6)),
+    Clk::gate(CLK_PMU, "clk_pmu", &[XIN24M], gate(0, 7)),
+    // I2C0，RK809 PMIC 挂在这条总线上
+    Clk::gate(PCLK_I2C0, "pclk_i2c0", &[PCLK_PDPMU], gate(1, 0)),
+    Clk::composite(
+        CLK_I2C0,
+        "clk_i2c0",
+        &[CLK_PDPMU],
+        None,
+        sel(3, 0, 7),
+        Some(gate(1, 1)),
+    ),
+    // UART0，调试串口
+    Clk::gate(PCLK_UART0, "pclk_uart0", &[PCLK_PDPMU], gate(1, 2)),
+    Clk::composite(
+        CLK_UART0_DIV,
+        "sclk_uart0_div",
+        &[PLL_PPLL, USB480M, CPLL, GPLL],
+        sel(4, 8, 2),
+        sel(4, 0, 7),
+        Some(gate(1, 3)),
+    ),
+    Clk::frac(
+        CLK_UART0_FRAC,
+        "sclk_uart0_frac",
+        &[CLK_UART0_DIV],
+        clksel_con(5),
+        Some(gate(1, 4)),
+    ),
+    Clk::composite(
+        SCLK_UART0_MUX,
+        "sclk_uart0_mux",
+        &[CLK_UART0_DIV, CLK_UART0_FRAC, XIN24M],
+        sel(4, 10, 2),
+        None,
+        None,
+    ),
+    Clk::gate(SCLK_UART0, "sclk_uart0", &[SCLK_UART0_MUX], gate(1, 5)),
+    Clk::gate(PCLK_PWM0, "pclk_pwm0", &[PCLK_PDPMU], gate(1, 6)),
+    Clk::composite(
+        CLK_PWM0,
+        "clk_pwm0",
+        &[XIN24M, CLK_PDPMU],
+        sel(6, 7, 1),
+        sel(6, 0, 7),
+        Some(gate(1, 7)),
+    ),
+    // GPIO0
+    Clk::gate(PCLK_GPIO0, "pclk_gpio0", &[PCLK_PDPMU], gate(1, 9)),
+    Clk::composite(
+        DBCLK_GPIO0,
+        "dbclk_gpio0",
+        &[XIN24M, CLK_RTC_32K],
+        sel(6, 15, 1),
+        None,
+        Some(gate(1, 10)),
+    ),
+];
+
+/// Clocks of the always-on domain that are never gated.
+pub(super) static CRITICAL: &[usize] = &[PCLK_PDPMU, PCLK_PMU, CLK_PMU];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clk::pll::{self, PllConfig};
+    use crate::clk::regs::FakeRegs;
+    use crate::clk::tree::{ClkTree, Kind, tests::*};
+
+    /// PMUCRU tree on `fake`, PPLL and HPLL at their boot rates.
+    fn booted(fake: &mut FakeRegs) -> ClkTree {
+        let regs = fake.regs();
+        let tree = ClkTree::new(regs, CLKS, CRITICAL);
+        for (id, rate) in [(PLL_PPLL, 200_000_000), (PLL_HPLL, 1_000_000_000)] {
+            let Kind::Pll(pll) = tree.find(id).unwrap().kind else {
+                unreachable!()
+            };
+            pll::preset(&regs, &pll, &PllConfig::calc(24_000_000, rate).unwrap());
+        }
+        tree
+    }
+
+    #[test]
+    fn fields_disjoint() {
+        check_fields_disjoint(CLKS);
+    }
+
+    #[test]
+    fn muxes() {
+        let mut fake = FakeRegs::new();
+        check_muxes(&booted(&mut fake));
+    }
+
+    #[test]
+    fn dividers() {
+        let mut fake = FakeRegs::new();
+        check_dividers(&booted(&mut fake));
+    }
+
+    #[test]
+    fn set_rate() {
+        let mut fake = FakeRegs::new();
+        check_set_rate(&mut booted(&mut fake));
+    }
+
+    #[test]
+    fn i2c0_follows_pdpmu() {
+        let mut fake = FakeRegs::new();
+        let mut tree = booted(&mut fake);
+
+        assert_eq!(tree.rate(CLK_PDPMU).unwrap(), 200_000_000);
+        assert_eq!(tree.set_rate(CLK_I2C0, 100_000_000).unwrap(), 100_000_000);
+        tree.set_parent(CLK_PDPMU, GPLL).unwrap();
+        assert_eq!(tree.rate(CLK_I2C0).unwrap(), 594_000_000);
+    }
+
+    #[test]
+    fn gpll_from_cru() {
+        let mut fake = FakeRegs::new();
+        let mut cru = FakeRegs::new();
+        let mut tree = booted(&mut fake);
+
+        let regs = cru.regs();
+        let config = PllConfig::calc(24_000_000, 1_200_000_000).unwrap();
+        pll::preset(&regs, &cru::GPLL, &config);
+        tree.set_peer(regs);
+        assert_eq!(tree.rate(GPLL).unwrap(), 1_200_000_000);
+        // CPLL 还在 slow 模式，输出 24 MHz
+        assert_eq!(tree.rate(CPLL).unwrap(), 24_000_000);
+    }
+}
@@ -0,0 +1,104 @@
+#[cfg(test)]
+extern crate alloc;
+
+/// 32-bit MMIO view of a Rockchip clock & reset unit.
+///
+/// Rockchip CRU registers carry a write-enable mask in their upper half-word:
+/// a bit of the lower half-word is only updated when the matching bit in the
+/// upper half-word is set, so fields can be changed without read-modify-write.
+#[derive(Clone, Copy)]
+pub struct Regs {
+    base: *mut u32,
+}
+
+unsafe impl Send for Regs {}
+unsafe impl Sync for Regs {}
+
+impl Regs {
+    pub const fn new(base: usize) -> Self {
+        Regs {
+            base: base as *mut u32,
+        }
+    }
+
+    pub fn read(&self, offset: usize) -> u32 {
+        unsafe { self.base.byte_add(offset).read_volatile() }
+    }
+
+    pub fn write(&self, offset: usize, value: u32) {
+        unsafe { self.base.byte_add(offset).write_volatile(value) }
+    }
+
+    /// Reads the `width`-bit field at `shift` of the register at `offset`.
+    pub fn field(&self, offset: usize, shift: u32, width: u32) -> u32 {
+        (self.read(offset) >> shift) & mask(width)
+    }
+
+    /// Updates the `width`-bit field at `shift` through the hiword write mask.
+    pub fn write_field(&self, offset: usize, shift: u32, width: u32, value: u32) {
+        let mask = mask(width) << shift;
+        debug_assert!(mask <= 0xffff, "field past the write-masked half-word");
+        self.write_masked(offset, (mask << 16) | ((value << shift) & mask));
+    }
+
+    /// Writes `value` to a register whose upper half-word masks the lower.
+    fn write_masked(&self, offset: usize, value: u32) {
+        #[cfg(not(test))]
+        self.write(offset, value);
+        #[cfg(test)]
+        FakeRegs::write_masked(self, offset, value);
+    }
+}
+
+/// Zeroed in-memory register block standing in for a clock unit in host
+/// tests. Writes through [`Regs::write_field`] get the hardware's hiword
+/// mask semantics, plain [`Regs::write`]s are stored as they are.
+#[cfg(test)]
+pub(crate) struct FakeRegs {
+    mem: alloc::vec::Vec<u32>,
+}
+
+#[cfg(test)]
+impl FakeRegs {
+    /// Covers register offsets `0..0x1000`, enough for the CRU and PMUCRU.
+    pub fn new() -> Self {
+        FakeRegs {
+            mem: alloc::vec![0; 0x400],
+        }
+    }
+
+    /// The block as seen by the drivers; only valid while `self` lives.
+    pub fn regs(&mut self) -> Regs {
+        Regs::new(self.mem.as_mut_ptr() as usize)
+    }
+
+    /// What the hardware does with a hiword-masked write: only the bits of
+    /// the lower half-word whose mask bit is set change.
+    fn write_masked(regs: &Regs, offset: usize, value: u32) {
+        let mask = value >> 16;
+        regs.write(offset, (regs.read(offset) & !mask) | (value & mask));
+    }
+}
+
+const fn mask(width: u32) -> u32 {
+    (1 << width) - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_field_only_touches_the_field() {
+        let mut fake = FakeRegs::new();
+        let regs = fake.regs();
+
+        regs.write(0x100, 0xffff_0000 | 0x1234);
+        regs.write_field(0x100, 4, 4, 0xa);
+        assert_eq!(regs.read(0x100), 0xffff_0000 | 0x12a4);
+        assert_eq!(regs.field(0x100, 4, 4), 0xa);
+        // 超出字段宽度的值被截掉，不会漏到相邻字段
+        regs.write_field(0x100, 0, 4, 0x1f);
+        assert_eq!(regs.read(0x100), 0xffff_0000 | 0x12af);
+    }
+}
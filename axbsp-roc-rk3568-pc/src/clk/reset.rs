@@ -0,0 +1,170 @@
+extern crate alloc;
+
+use alloc::{format, vec::Vec};
+use core::time::Duration;
+
+use axklib::time::busy_wait;
+use log::warn;
+use rdrive::fdt::{Node, Phandle};
+use rdrive::{Device, DriverGeneric, KError, probe::OnProbeError};
+
+use super::dt;
+use super::regs::Regs;
+
+/// How long [`ResetController::reset`] holds a line asserted.
+const PULSE: Duration = Duration::from_micros(10);
+
+/// Soft-reset lines of a Rockchip clock unit (`SOFTRST_CON` registers).
+///
+/// Reset IDs follow the dt-binding numbering: line `id` is bit `id % 16` of
+/// `SOFTRST_CON(id / 16)`, and a set bit holds the peripheral in reset.
+///
+/// Each clock unit registers a copy as its own rdrive device, found through
+/// the `resets` property of consumer nodes; it shares the registers of the
+/// clock unit.
+#[derive(Clone)]
+pub struct ResetController {
+    regs: Regs,
+    softrst_con: usize,
+    lines: usize,
+    /// Phandle of the clock unit node, which is also the reset provider.
+    phandle: Option<Phandle>,
+}
+
+impl ResetController {
+    pub(crate) const fn new(
+        regs: Regs,
+        softrst_con: usize,
+        con_count: usize,
+        phandle: Option<Phandle>,
+    ) -> Self {
+        ResetController {
+            regs,
+            softrst_con,
+            lines: con_count * 16,
+            phandle,
+        }
+    }
+
+    pub fn phandle(&self) -> Option<Phandle> {
+        self.phandle
+    }
+
+    fn line(&self, id: usize) -> Result<(usize, u32), KError> {
+        if id >= self.lines {
+            warn!("Unsupported reset ID: {}", id);
+            return Err(KError::InvalidArg { name: "reset_id" });
+        }
+        Ok((self.softrst_con + id / 16 * 4, (id % 16) as u32))
+    }
+
+    /// Holds the peripheral behind reset line `id` in reset.
+    pub fn assert(&self, id: usize) -> Result<(), KError> {
+        let (con, bit) = self.line(id)?;
+        self.regs.write_field(con, bit, 1, 1);
+        Ok(())
+    }
+
+    /// Releases reset line `id`.
+    pub fn deassert(&self, id: usize) -> Result<(), KError> {
+        let (con, bit) = self.line(id)?;
+        self.regs.write_field(con, bit, 1, 0);
+        Ok(())
+    }
+
+    pub fn is_asserted(&self, id: usize) -> Result<bool, KError> {
+        let (con, bit) = self.line(id)?;
+        Ok(self.regs.field(con, bit, 1) != 0)
+    }
+
+    /// Pulses reset line `id`: assert, wait briefly, deassert.
+    pub fn reset(&self, id: usize) -> Result<(), KError> {
+        self.assert(id)?;
+        busy_wait(PULSE);
+        self.deassert(id)
+    }
+}
+
+impl DriverGeneric for ResetController {
+    fn open(&mut self) -> Result<(), KError> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), KError> {
+        Ok(())
+    }
+}
+
+/// A reset specifier of a consumer node, resolved to the registered reset
+/// controller.
+#[derive(Clone)]
+pub struct ResetRef {
+    pub provider: Device<ResetController>,
+    pub id: usize,
+}
+
+fn resolve(node: &Node<'_>, (phandle, id): (Phandle, usize)) -> Result<ResetRef, OnProbeError> {
+    let provider = dt::find_provider(phandle, ResetController::phandle)
+        .map_err(|err| {
+            OnProbeError::other(format!(
+                "[{}] reset provider {:?}: {:?}",
+                node.name(),
+                phandle,
+                err
+            ))
+        })?
+        .ok_or_else(|| {
+            OnProbeError::other(format!(
+                "[{}] reset provider {:?} not found",
+                node.name(),
+                phandle
+            ))
+        })?;
+    Ok(ResetRef { provider, id })
+}
+
+/// Resolves every entry of the `resets` property of `node`.
+pub fn resets(node: &Node<'_>) -> Result<Vec<ResetRef>, OnProbeError> {
+    dt::specifiers(node, "resets")
+        .into_iter()
+        .flatten()
+        .map(|spec| resolve(node, spec))
+        .collect()
+}
+
+/// Resolves the `resets` entry that `reset-names` calls `name`.
+pub fn reset(node: &Node<'_>, name: &str) -> Result<ResetRef, OnProbeError> {
+    let index = dt::name_index(node, "reset-names", name).ok_or_else(|| {
+        OnProbeError::other(format!("[{}] has no \"{}\" reset", node.name(), name))
+    })?;
+
+    let spec = dt::specifiers(node, "resets")
+        .get(index)
+        .copied()
+        .flatten()
+        .ok_or_else(|| {
+            OnProbeError::other(format!("[{}] resets has no entry {}", node.name(), index))
+        })?;
+
+    resolve(node, spec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clk::regs::FakeRegs;
+
+    #[test]
+    fn lines_map_to_softrst_bits() {
+        let mut fake = FakeRegs::new();
+        let regs = fake.regs();
+        let resets = ResetController::new(regs, 0x400, 30, None);
+
+        resets.assert(0x1d3).unwrap();
+        assert!(resets.is_asserted(0x1d3).unwrap());
+        assert_eq!(regs.read(0x400 + 0x1d * 4), 1 << 3);
+        resets.reset(0x1d3).unwrap();
+        assert!(!resets.is_asserted(0x1d3).unwrap());
+        assert!(resets.assert(30 * 16).is_err());
+    }
+}
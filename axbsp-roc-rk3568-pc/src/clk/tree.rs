@@ -0,0 +1,787 @@
+extern crate alloc;
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use log::warn;
+use rdrive::KError;
+
+use super::pll::{self, Pll, PllConfig};
+use super::regs::Regs;
+
+/// A bit field inside a `CLKSEL_CON` register.
+#[derive(Debug, Clone, Copy)]
+pub struct Field {
+    pub con: usize,
+    pub shift: u32,
+    pub width: u32,
+}
+
+/// A gate bit inside a `CLKGATE_CON` register.
+///
+/// Rockchip gates are active-high: a set bit stops the clock.
+#[derive(Debug, Clone, Copy)]
+pub struct Gate {
+    pub con: usize,
+    pub bit: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    /// Rate fixed outside the clock unit, e.g. the 24 MHz oscillator.
+    Fixed(u64),
+    Pll(Pll),
+    /// PLL of the sibling clock unit (CRU and PMUCRU feed each other),
+    /// read through that unit's registers once [`ClkTree::set_peer`] has
+    /// them. Reads as `default` until then, and is only programmed by the
+    /// unit that owns it.
+    Peer {
+        pll: Pll,
+        default: u64,
+    },
+    /// `parent * mul / div` with constant factors.
+    Factor {
+        mul: u32,
+        div: u32,
+    },
+    /// Optional parent mux followed by an optional `parent / (div + 1)`
+    /// divider. With neither, the clock is a plain gate.
+    Composite {
+        mux: Option<Field>,
+        div: Option<Field>,
+    },
+    /// Fractional divider register: numerator in [31:16], denominator in
+    /// [15:0]. Unlike the other `CLKSEL_CON` registers it has no write mask.
+    Frac {
+        con: usize,
+    },
+}
+
+/// One node of the clock tree.
+#[derive(Debug, Clone, Copy)]
+pub struct Clk {
+    pub id: usize,
+    pub name: &'static str,
+    pub parents: &'static [usize],
+    pub kind: Kind,
+    pub gate: Option<Gate>,
+}
+
+impl Clk {
+    pub const fn fixed(id: usize, name: &'static str, rate: u64) -> Self {
+        Clk {
+            id,
+            name,
+            parents: &[],
+            kind: Kind::Fixed(rate),
+            gate: None,
+        }
+    }
+
+    pub const fn pll(id: usize, name: &'static str, parent: &'static [usize], pll: Pll) -> Self {
+        Clk {
+            id,
+            name,
+            parents: parent,
+            kind: Kind::Pll(pll),
+            gate: None,
+        }
+    }
+
+    pub const fn peer(
+        id: usize,
+        name: &'static str,
+        parent: &'static [usize],
+        pll: Pll,
+        default: u64,
+    ) -> Self {
+        Clk {
+            id,
+            name,
+            parents: parent,
+            kind: Kind::Peer { pll, default },
+            gate: None,
+        }
+    }
+
+    pub const fn factor(
+        id: usize,
+        name: &'static str,
+        parent: &'static [usize],
+        mul: u32,
+        div: u32,
+    ) -> Self {
+        Clk {
+            id,
+            name,
+            parents: parent,
+            kind: Kind::Factor { mul, div },
+            gate: None,
+        }
+    }
+
+    pub const fn composite(
+        id: usize,
+        name: &'static str,
+        parents: &'static [usize],
+        mux: Option<Field>,
+        div: Option<Field>,
+        gate: Option<Gate>,
+    ) -> Self {
+        Clk {
+            id,
+            name,
+            parents,
+            kind: Kind::Composite { mux, div },
+            gate,
+        }
+    }
+
+    pub const fn frac(
+        id: usize,
+        name: &'static str,
+        parent: &'static [usize],
+        con: usize,
+        gate: Option<Gate>,
+    ) -> Self {
+        Clk {
+            id,
+            name,
+            parents: parent,
+            kind: Kind::Frac { con },
+            gate,
+        }
+    }
+
+    pub const fn gate(id: usize, name: &'static str, parent: &'static [usize], gate: Gate) -> Self {
+        Self::composite(id, name, parent, None, None, Some(gate))
+    }
+}
+
+/// State of one clock as read back from the hardware.
+#[derive(Debug, Clone)]
+pub struct ClkState {
+    pub id: usize,
+    pub name: &'static str,
+    /// ID of the selected parent, `None` for root clocks.
+    pub parent: Option<usize>,
+    pub rate: u64,
+    pub enable_count: u32,
+    /// Gate state, `None` for clocks without a gate of their own.
+    pub gated: Option<bool>,
+}
+
+/// Clock tree of one clock unit, evaluated against its live registers.
+pub(crate) struct ClkTree {
+    regs: Regs,
+    clks: &'static [Clk],
+    /// Registers of the sibling clock unit, for [`Kind::Peer`] clocks.
+    peer: Option<Regs>,
+    /// Clocks that hold a reference for good once
+    /// [`ClkTree::enable_critical`] ran, see `CRITICAL` in the unit tables.
+    critical: &'static [usize],
+    /// Outstanding [`ClkTree::enable`] references per clock ID.
+    enable_counts: BTreeMap<usize, u32>,
+}
+
+impl ClkTree {
+    pub const fn new(regs: Regs, clks: &'static [Clk], critical: &'static [usize]) -> Self {
+        ClkTree {
+            regs,
+            clks,
+            peer: None,
+            critical,
+            enable_counts: BTreeMap::new(),
+        }
+    }
+
+    pub fn regs(&self) -> Regs {
+        self.regs
+    }
+
+    /// Hands over the registers of the sibling clock unit.
+    pub fn set_peer(&mut self, regs: Regs) {
+        self.peer = Some(regs);
+    }
+
+    pub fn find(&self, id: usize) -> Result<&'static Clk, KError> {
+        self.clks.iter().find(|clk| clk.id == id).ok_or_else(|| {
+            warn!("Clock ID {:#x} is not modelled", id);
+            KError::InvalidArg { name: "clock_id" }
+        })
+    }
+
+    /// Index of the currently selected parent in `clk.parents`.
+    pub fn parent_index(&self, clk: &Clk) -> usize {
+        match clk.kind {
+            Kind::Composite { mux: Some(mux), .. } => {
+                self.regs.field(mux.con, mux.shift, mux.width) as usize
+            }
+            _ => 0,
+        }
+    }
+
+    /// ID of the currently selected parent, `None` for root clocks.
+    pub fn parent(&self, clk: &Clk) -> Option<usize> {
+        clk.parents.get(self.parent_index(clk)).copied()
+    }
+
+    pub fn rate(&self, id: usize) -> Result<u64, KError> {
+        let clk = self.find(id)?;
+        let parent_rate = match self.parent(clk) {
+            Some(parent) => self.rate(parent)?,
+            None if clk.parents.is_empty() => 0,
+            None => {
+                warn!("{}: reserved parent selector", clk.name);
+                return Ok(0);
+            }
+        };
+
+        let rate = match clk.kind {
+            Kind::Fixed(rate) => rate,
+            Kind::Pll(pll) => pll::rate(&self.regs, &pll, parent_rate),
+            Kind::Peer { pll, default } => match &self.peer {
+                Some(peer) => pll::rate(peer, &pll, parent_rate),
+                None => default,
+            },
+            Kind::Factor { mul, div } => parent_rate * mul as u64 / div as u64,
+            Kind::Composite { div, .. } => match div {
+                Some(div) => {
+                    let div = self.regs.field(div.con, div.shift, div.width) as u64 + 1;
+                    parent_rate / div
+                }
+                None => parent_rate,
+            },
+            Kind::Frac { con } => {
+                let value = self.regs.read(con);
+                let (num, den) = ((value >> 16) as u64, (value & 0xffff) as u64);
+                (parent_rate * num).checked_div(den).unwrap_or(0)
+            }
+        };
+
+        Ok(rate)
+    }
+
+    /// Snapshot of every clock, in table order.
+    pub fn summary(&self) -> Vec<ClkState> {
+        self.clks
+            .iter()
+            .map(|clk| ClkState {
+                id: clk.id,
+                name: clk.name,
+                parent: self.parent(clk),
+                rate: self.rate(clk.id).unwrap_or(0),
+                enable_count: self.enable_count(clk.id),
+                gated: clk
+                    .gate
+                    .map(|gate| self.regs.field(gate.con, gate.bit, 1) != 0),
+            })
+            .collect()
+    }
+
+    pub fn enable_count(&self, id: usize) -> u32 {
+        self.enable_counts.get(&id).copied().unwrap_or(0)
+    }
+
+    /// Whether the gate of `id` lets the clock through. Clocks without a
+    /// gate of their own always do.
+    pub fn is_enabled(&self, id: usize) -> Result<bool, KError> {
+        let clk = self.find(id)?;
+        Ok(clk
+            .gate
+            .is_none_or(|gate| self.regs.field(gate.con, gate.bit, 1) == 0))
+    }
+
+    /// Takes a reference on `id`. The first reference enables the parent
+    /// chain and then opens the clock's own gate.
+    pub fn enable(&mut self, id: usize) -> Result<(), KError> {
+        let clk = self.find(id)?;
+        let count = self.enable_count(id);
+
+        if count == 0 {
+            if let Some(parent) = self.parent(clk) {
+                self.enable(parent)?;
+            }
+            if let Some(gate) = clk.gate {
+                self.regs.write_field(gate.con, gate.bit, 1, 0);
+            }
+        }
+
+        self.enable_counts.insert(id, count + 1);
+        Ok(())
+    }
+
+    /// Takes a reference on every critical clock, so that dropping the last
+    /// driver reference on a shared parent never gates it.
+    pub fn enable_critical(&mut self) -> Result<(), KError> {
+        for &id in self.critical {
+            self.enable(id)?;
+        }
+        Ok(())
+    }
+
+    /// Drops a reference on `id`. The last reference closes the gate and
+    /// releases the parent chain.
+    pub fn disable(&mut self, id: usize) -> Result<(), KError> {
+        let clk = self.find(id)?;
+        let count = self.enable_count(id);
+
+        if count == 0 {
+            warn!("{}: disabled more often than enabled", clk.name);
+            return Err(KError::InvalidArg { name: "clock_id" });
+        }
+
+        self.enable_counts.insert(id, count - 1);
+        if count == 1 {
+            if let Some(gate) = clk.gate {
+                self.regs.write_field(gate.con, gate.bit, 1, 1);
+            }
+            if let Some(parent) = self.parent(clk) {
+                self.disable(parent)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Switches the mux of `clk` to parent `index`. If `clk` is in use its
+    /// enable reference moves from the old parent to the new one.
+    fn reparent(&mut self, clk: &Clk, index: usize, mux: Field) -> Result<(), KError> {
+        let old = self.parent(clk);
+        let new = clk.parents[index];
+        let in_use = self.enable_count(clk.id) > 0 && old != Some(new);
+
+        if in_use {
+            self.enable(new)?;
+        }
+        self.regs
+            .write_field(mux.con, mux.shift, mux.width, index as u32);
+        if in_use && let Some(old) = old {
+            self.disable(old)?;
+        }
+
+        Ok(())
+    }
+
+    /// Selects `parent` as the input of `id`.
+    pub fn set_parent(&mut self, id: usize, parent: usize) -> Result<(), KError> {
+        let clk = self.find(id)?;
+        let Some(index) = clk.parents.iter().position(|&p| p == parent) else {
+            warn!("{}: {:#x} is not a possible parent", clk.name, parent);
+            return Err(KError::InvalidArg { name: "parent" });
+        };
+
+        match clk.kind {
+            Kind::Composite { mux: Some(mux), .. } => self.reparent(clk, index, mux),
+            _ if self.parent(clk) == Some(parent) => Ok(()),
+            _ => {
+                warn!("{}: parent is fixed", clk.name);
+                Err(KError::InvalidArg { name: "parent" })
+            }
+        }
+    }
+
+    /// Closest rate at or below `rate` that `id` can produce, without
+    /// touching the hardware.
+    pub fn round_rate(&self, id: usize, rate: u64) -> Result<u64, KError> {
+        let clk = self.find(id)?;
+        match self.plan(clk, rate)? {
+            (rate, Setting::Parent(parent)) => self.round_rate(parent, rate),
+            (rate, _) => Ok(rate),
+        }
+    }
+
+    /// Programs `id` to the closest rate at or below `rate` and returns the
+    /// rate actually chosen.
+    ///
+    /// Plain gates pass the request on to their parent, unless other clocks
+    /// hold references on it: the parent's rate is theirs too, so it is only
+    /// accepted if the parent already runs at the rate it would be set to.
+    pub fn set_rate(&mut self, id: usize, rate: u64) -> Result<u64, KError> {
+        let clk = self.find(id)?;
+        let (chosen, setting) = self.plan(clk, rate)?;
+
+        match (setting, clk.kind) {
+            (Setting::Keep, _) => {}
+            (Setting::Parent(parent), _) => {
+                let own = u32::from(self.enable_count(id) > 0);
+                if self.enable_count(parent) <= own {
+                    return self.set_rate(parent, rate);
+                }
+                let current = self.rate(parent)?;
+                if self.round_rate(parent, rate)? != current {
+                    warn!(
+                        "{}: parent {} is shared, keeping it at {} Hz",
+                        clk.name,
+                        self.find(parent)?.name,
+                        current
+                    );
+                    return Err(KError::InvalidArg { name: "rate" });
+                }
+                return Ok(current);
+            }
+            (Setting::Composite { index, div: value }, Kind::Composite { mux, div }) => {
+                if let Some(mux) = mux {
+                    self.reparent(clk, index, mux)?;
+                }
+                if let Some(div) = div {
+                    self.regs.write_field(div.con, div.shift, div.width, value);
+                }
+            }
+            (Setting::Frac { num, den }, Kind::Frac { con }) => {
+                self.regs.write(con, (num << 16) | den);
+            }
+            (Setting::Pll(config), Kind::Pll(pll)) => {
+                let parent_rate = match self.parent(clk) {
+                    Some(parent) => self.rate(parent)?,
+                    None => 0,
+                };
+                pll::set_rate(&self.regs, &pll, parent_rate, &config)?;
+            }
+            _ => unreachable!(),
+        }
+
+        Ok(chosen)
+    }
+
+    /// Picks the register setting that gets `clk` closest to `rate` from
+    /// below.
+    fn plan(&self, clk: &Clk, rate: u64) -> Result<(u64, Setting), KError> {
+        let unreachable = || {
+            warn!("{}: cannot generate {} Hz or less", clk.name, rate);
+            KError::InvalidArg { name: "rate" }
+        };
+        if rate == 0 {
+            return Err(unreachable());
+        }
+
+        match clk.kind {
+            Kind::Composite {
+                mux: None,
+                div: None,
+            } => match self.parent(clk) {
+                Some(parent) => Ok((rate, Setting::Parent(parent))),
+                None => Err(unreachable()),
+            },
+            Kind::Composite { mux, div } => {
+                let candidates = match mux {
+                    Some(_) => 0..clk.parents.len(),
+                    None => {
+                        let index = self.parent_index(clk);
+                        index..index + 1
+                    }
+                };
+
+                let mut best: Option<(u64, Setting)> = None;
+                for index in candidates {
+                    let Some(&parent) = clk.parents.get(index) else {
+                        continue;
+                    };
+                    let Ok(parent_rate) = self.rate(parent) else {
+                        continue;
+                    };
+
+                    let (achieved, value) = match div {
+                        Some(div) => match round_div(parent_rate, rate, div.width) {
+                            Some(value) => (parent_rate / (value as u64 + 1), value),
+                            None => continue,
+                        },
+                        None if parent_rate <= rate => (parent_rate, 0),
+                        None => continue,
+                    };
+
+                    if achieved > 0 && best.as_ref().is_none_or(|(rate, _)| achieved > *rate) {
+                        best = Some((achieved, Setting::Composite { index, div: value }));
+                    }
+                }
+
+                best.ok_or_else(unreachable)
+            }
+            Kind::Frac { .. } => {
+                let parent_rate = match self.parent(clk) {
+                    Some(parent) => self.rate(parent)?,
+                    None => 0,
+                };
+                let (num, den) = frac_approx(parent_rate, rate).ok_or_else(unreachable)?;
+                Ok((
+                    parent_rate * num / den,
+                    Setting::Frac {
+                        num: num as u32,
+                        den: den as u32,
+                    },
+                ))
+            }
+            Kind::Pll(_) => {
+                let parent_rate = match self.parent(clk) {
+                    Some(parent) => self.rate(parent)?,
+                    None => 0,
+                };
+                let config = PllConfig::calc(parent_rate, rate).ok_or_else(unreachable)?;
+                Ok((config.rate(parent_rate), Setting::Pll(config)))
+            }
+            Kind::Fixed(_) | Kind::Peer { .. } | Kind::Factor { .. } => {
+                let current = self.rate(clk.id)?;
+                if current != 0 && current <= rate {
+                    Ok((current, Setting::Keep))
+                } else {
+                    Err(unreachable())
+                }
+            }
+        }
+    }
+}
+
+/// Register changes that realise a rate picked by [`ClkTree::plan`].
+enum Setting {
+    /// The clock is not adjustable and already runs at an acceptable rate.
+    Keep,
+    Composite {
+        index: usize,
+        div: u32,
+    },
+    Frac {
+        num: u32,
+        den: u32,
+    },
+    Pll(PllConfig),
+    /// Plain gate: the parent has to be programmed instead.
+    Parent(usize),
+}
+
+/// Smallest divider field value that brings `parent` to `rate` or below.
+fn round_div(parent: u64, rate: u64, width: u32) -> Option<u32> {
+    let div = parent.div_ceil(rate).max(1);
+    (div <= 1 << width).then(|| (div - 1) as u32)
+}
+
+/// Largest `num / den <= rate / parent` with both terms fitting the 16-bit
+/// fields of a fractional divider, found through the continued fraction
+/// expansion of `rate / parent`. Once the next convergent no longer fits,
+/// the largest intermediate fraction towards it is tried as well.
+fn frac_approx(parent: u64, rate: u64) -> Option<(u64, u64)> {
+    if rate == 0 || rate >= parent {
+        return None;
+    }
+
+    let (mut p0, mut q0, mut p1, mut q1) = (0u64, 1u64, 1u64, 0u64);
+    let (mut n, mut d) = (rate, parent);
+    let mut best = None;
+
+    while d != 0 {
+        let a = n / d;
+        let (p2, q2) = (a * p1 + p0, a * q1 + q0);
+        if p2 > 0xffff || q2 > 0xffff {
+            // 介于 p0/q0 和 p2/q2 之间，与 p0/q0 同侧
+            let k = ((0xffff - p0) / p1.max(1)).min((0xffff - q0) / q1.max(1));
+            let (p, q) = (k * p1 + p0, k * q1 + q0);
+            if k > 0 && p != 0 && p * parent <= rate * q {
+                best = Some((p, q));
+            }
+            break;
+        }
+        if p2 != 0 && p2 * parent <= rate * q2 {
+            best = Some((p2, q2));
+        }
+        (p0, q0, p1, q1) = (p1, q1, p2, q2);
+        (n, d) = (d, n % d);
+    }
+
+    best
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// Every field of a register belongs to one clock only.
+    pub fn check_fields_disjoint(clks: &[Clk]) {
+        let mut used: BTreeMap<usize, (u32, &str)> = BTreeMap::new();
+        let mut claim = |con: usize, shift: u32, width: u32, name: &'static str| {
+            let bits = ((1u32 << width) - 1) << shift;
+            let (taken, owner) = used.entry(con).or_insert((0, name));
+            assert_eq!(
+                *taken & bits,
+                0,
+                "{} overlaps {} in register {:#x}",
+                name,
+                owner,
+                con
+            );
+            *taken |= bits;
+            *owner = name;
+        };
+
+        for clk in clks {
+            if let Kind::Composite { mux, div } = clk.kind {
+                for field in [mux, div].into_iter().flatten() {
+                    claim(field.con, field.shift, field.width, clk.name);
+                }
+            }
+            if let Some(gate) = clk.gate {
+                claim(gate.con, gate.bit, 1, clk.name);
+            }
+        }
+    }
+
+    /// Every mux value selects the listed parent, and values past the end
+    /// of the list read as a reserved selector.
+    pub fn check_muxes(tree: &ClkTree) {
+        for clk in tree.clks {
+            let Kind::Composite {
+                mux: Some(mux),
+                div,
+            } = clk.kind
+            else {
+                continue;
+            };
+            let saved = tree.regs.field(mux.con, mux.shift, mux.width);
+            let div = div.map_or(1, |div| {
+                tree.regs.field(div.con, div.shift, div.width) as u64 + 1
+            });
+
+            for (index, &parent) in clk.parents.iter().enumerate() {
+                tree.regs
+                    .write_field(mux.con, mux.shift, mux.width, index as u32);
+                assert_eq!(tree.parent_index(clk), index, "{}", clk.name);
+                assert_eq!(tree.parent(clk), Some(parent), "{}", clk.name);
+                assert_eq!(
+                    tree.rate(clk.id).unwrap(),
+                    tree.rate(parent).unwrap() / div,
+                    "{} from {:#x}",
+                    clk.name,
+                    parent
+                );
+            }
+            if clk.parents.len() < 1 << mux.width {
+                let reserved = clk.parents.len() as u32;
+                tree.regs
+                    .write_field(mux.con, mux.shift, mux.width, reserved);
+                assert_eq!(tree.parent(clk), None, "{}", clk.name);
+                assert_eq!(tree.rate(clk.id).unwrap(), 0, "{}", clk.name);
+            }
+
+            tree.regs.write_field(mux.con, mux.shift, mux.width, saved);
+        }
+    }
+
+    /// Every divider field value `n` divides the parent rate by `n + 1`.
+    pub fn check_dividers(tree: &ClkTree) {
+        for clk in tree.clks {
+            let Kind::Composite { div: Some(div), .. } = clk.kind else {
+                continue;
+            };
+            let saved = tree.regs.field(div.con, div.shift, div.width);
+            let parent_rate = tree.rate(tree.parent(clk).unwrap()).unwrap();
+
+            for value in [0, 1, 2, (1 << div.width) - 1] {
+                tree.regs.write_field(div.con, div.shift, div.width, value);
+                assert_eq!(
+                    tree.rate(clk.id).unwrap(),
+                    parent_rate / (value as u64 + 1),
+                    "{} divided by {}",
+                    clk.name,
+                    value + 1
+                );
+            }
+
+            tree.regs.write_field(div.con, div.shift, div.width, saved);
+        }
+    }
+
+    /// Best rate at or below `rate` over every parent and divider value
+    /// `clk` can select, found by trying them all.
+    fn best_rate(tree: &ClkTree, clk: &Clk, rate: u64) -> Option<u64> {
+        let Kind::Composite { mux, div } = clk.kind else {
+            unreachable!()
+        };
+        let indices = match mux {
+            Some(_) => 0..clk.parents.len(),
+            None => 0..1,
+        };
+        let divs = div.map_or(1, |div| 1u64 << div.width);
+
+        indices
+            .flat_map(|index| {
+                let parent_rate = tree.rate(clk.parents[index]).unwrap();
+                (1..=divs).filter_map(move |div| {
+                    // 硬件输出的是 parent / div，不能超过目标
+                    (parent_rate <= rate * div).then_some(parent_rate / div)
+                })
+            })
+            .filter(|&achieved| achieved > 0)
+            .max()
+    }
+
+    /// For every mux or divider, rounding and setting a range of rates
+    /// lands on the best setting, and the registers read back that rate.
+    pub fn check_set_rate(tree: &mut ClkTree) {
+        for clk in tree.clks {
+            let Kind::Composite { mux, div } = clk.kind else {
+                continue;
+            };
+            if mux.is_none() && div.is_none() {
+                continue;
+            }
+            let fields: Vec<Field> = [mux, div].into_iter().flatten().collect();
+            let saved: Vec<u32> = fields
+                .iter()
+                .map(|f| tree.regs.field(f.con, f.shift, f.width))
+                .collect();
+
+            let top = clk
+                .parents
+                .iter()
+                .map(|&parent| tree.rate(parent).unwrap())
+                .max()
+                .unwrap();
+            for rate in [top, top - 1, top / 2 + 1, top / 3, top / 100, 32_768, 1] {
+                let expected = best_rate(tree, clk, rate);
+                match expected {
+                    Some(expected) => {
+                        assert_eq!(tree.round_rate(clk.id, rate).ok(), Some(expected));
+                        assert_eq!(tree.set_rate(clk.id, rate).ok(), Some(expected));
+                        assert_eq!(
+                            tree.rate(clk.id).unwrap(),
+                            expected,
+                            "{} set to {}",
+                            clk.name,
+                            rate
+                        );
+                    }
+                    None => {
+                        assert!(tree.round_rate(clk.id, rate).is_err(), "{}", clk.name);
+                        assert!(tree.set_rate(clk.id, rate).is_err(), "{}", clk.name);
+                    }
+                }
+            }
+
+            for (f, value) in fields.iter().zip(saved) {
+                tree.regs.write_field(f.con, f.shift, f.width, value);
+            }
+        }
+    }
+
+    #[test]
+    fn round_div_picks_smallest_divider() {
+        assert_eq!(round_div(1_000, 1_000, 4), Some(0));
+        assert_eq!(round_div(1_000, 2_000, 4), Some(0));
+        assert_eq!(round_div(1_000, 333, 4), Some(3));
+        assert_eq!(round_div(1_000, 334, 4), Some(2));
+        assert_eq!(round_div(1_000, 62, 4), None);
+        assert_eq!(round_div(1_000, 63, 4), Some(15));
+    }
+
+    #[test]
+    fn frac_approx_stays_below_rate() {
+        let parent = 1_188_000_000;
+        for rate in [1_843_200, 7_372_800, 32_768, 48_000_000, 1_187_999_999] {
+            let (num, den) = frac_approx(parent, rate).unwrap();
+            assert!(num <= 0xffff && den <= 0xffff);
+            assert!(num * parent <= rate * den, "{} Hz", rate);
+            // 误差不超过万分之一
+            assert!((rate - parent * num / den) * 10_000 <= rate, "{} Hz", rate);
+        }
+        assert_eq!(frac_approx(parent, 10_000_000), Some((5, 594)));
+        assert_eq!(frac_approx(parent, parent - 1), Some((65534, 65535)));
+        assert_eq!(frac_approx(parent, parent), None);
+        assert_eq!(frac_approx(parent, 0), None);
+    }
+}
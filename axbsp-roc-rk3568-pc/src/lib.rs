@@ -4,5 +4,22 @@
 extern crate axklib;
 extern crate axplat_aarch64_dyn;
 
+use rdrive::{Descriptor, DeviceId, PlatformDevice};
+
 pub mod clk;
 pub mod sdhci;
+
+/// Another device slot for the node `plat_dev` was probed for.
+///
+/// rdrive hands each probe a single [`PlatformDevice`], while a clock unit
+/// also registers its reset controller and an eMMC one device per
+/// partition. The copy keeps the node's name and interrupt parent under a
+/// new device ID.
+pub(crate) fn sibling_device(plat_dev: &PlatformDevice) -> PlatformDevice {
+    PlatformDevice {
+        descriptor: Descriptor {
+            device_id: DeviceId::new(),
+            ..plat_dev.descriptor.clone()
+        },
+    }
+}
@@ -1,13 +1,19 @@
 extern crate alloc;
 
-use crate::clk::ClkDriver;
-use alloc::{boxed::Box, sync::Arc};
+use crate::clk::dt::{self, ClkRef};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use axbsp_block::{
+    BusMode, CacheConfig, CardHealth, CardIdentity, EraseConfig, EraseInterface, EraseKind,
+    EraseQueue, FlushInterface, FlushQueue, Layout, Partition, PartitionDevice, Recovery,
+    RecoveryFailed, Selector,
+};
 use axklib::{mem::iomap, time::busy_wait};
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use core::time::Duration;
+use dwcmshc::Dwcmshc;
 use log::{debug, info, warn};
-use rdif_block::{IQueue, Interface};
-use rdif_clk::Interface as _;
-use rdrive::{DriverGeneric, KError, get_one};
+use rdif_block::{Buffer, IQueue, Interface};
+use rdrive::{DeviceId, DriverGeneric, KError};
 use rdrive::{PlatformDevice, module_driver, probe::OnProbeError, register::FdtInfo};
 
 use sdmmc::{
@@ -16,13 +22,56 @@ use sdmmc::{
         EMmcHost,
         clock::{Clk, ClkError, init_global_clk},
     },
-    err::SdError,
     set_impl,
 };
 
+use sdmmc::err::SdError;
+
 use spin::Mutex;
 
-const OFFSET: usize = 0x7_A000;
+#[cfg(not(feature = "pio"))]
+mod adma;
+mod cache;
+mod caps;
+mod cmd;
+#[cfg(not(feature = "pio"))]
+mod cqe;
+mod dwcmshc;
+mod erase;
+mod hwpart;
+mod info;
+mod regs;
+mod rpmb;
+
+pub use caps::MmcCaps;
+pub use cmd::HostError;
+pub use dwcmshc::Timing;
+pub use hwpart::{BootWp, HwPart};
+pub use info::EmmcInfo;
+pub use rpmb::EmmcRpmb;
+
+use cache::WriteCache;
+use cmd::Cmd;
+use erase::Eraser;
+use hwpart::HwParts;
+
+#[cfg(not(feature = "pio"))]
+use adma::{Adma, AdmaError, Completion};
+#[cfg(not(feature = "pio"))]
+use alloc::collections::VecDeque;
+#[cfg(not(feature = "pio"))]
+use cqe::{Cqe, CqeError};
+#[cfg(not(feature = "pio"))]
+use dma_api::{DSlice, DSliceMut, Direction};
+
+/// Device to register first, from `block-partition` in `axconfig.toml`: a
+/// [`Selector`], or `boot0`/`boot1` for a hardware boot area.
+const PARTITION: &str = env!("AXBSP_BLOCK_PARTITION");
+
+/// SDHCI 的块计数寄存器只有 16 位，更大的请求拆成多条 CMD18/CMD25
+const MAX_BLOCKS_PER_CMD: usize = u16::MAX as usize;
+/// 队列 ID 与 CQE 任务槽位一一对应，最多 32 个
+const MAX_QUEUES: usize = 32;
 
 /// Driver for the RK3568 eMMC controller.
 /// Driver for the RK3568 eMMC controller.
@@ -74,95 +123,1011 @@ fn probe_mmc(info: FdtInfo<'_>, plat_dev: PlatformDevice) -> Result<(), OnProbeE
     )
     .expect("Failed to iomap MCI");
 
-    let _ = init_clk(0x7c);
+    if let Err(err) = dt::apply_assigned_clocks(&info.node, None) {
+        warn!("RK3568 eMMC: assigned clocks not fully applied: {:?}", err);
+    }
+
+    let clks = dt::clocks(&info.node)?;
+    let core_clk = dt::clock(&info.node, "core")?;
+    let emmc_clk = init_clk(core_clk.clone());
+
+    let caps = MmcCaps::from_node(&info.node);
+    debug!("RK3568 eMMC caps: {:?}", caps);
+    if !caps.non_removable {
+        // 没有卡检测：cd-gpios 需要 GPIO 驱动，这里只支持焊死的 eMMC
+        warn!("RK3568 eMMC: no card detection, assuming the card is present");
+    }
 
     let mmc_address = mci_reg_base.as_ptr() as usize;
 
     debug!("mmc address: {:#x}", mmc_address);
-    let mut emmc = EMmcHost::new(mmc_address);
+    let (emmc, dwcmshc, mode) = init_card(mmc_address, &core_clk, emmc_clk, caps)
+        .map_err(|err| OnProbeError::other(alloc::format!("[{}] {}", info.node.name(), err)))?;
+
+    let ext_csd = Cmd::new(mmc_address).read_ext_csd().map_err(|err| {
+        OnProbeError::other(alloc::format!(
+            "[{}] failed to read EXT_CSD: {}",
+            info.node.name(),
+            err
+        ))
+    })?;
+    let identity =
+        info::read_identity(mmc_address, &ext_csd, emmc.get_block_num()).map_err(|err| {
+            OnProbeError::other(alloc::format!(
+                "[{}] failed to read CID: {}",
+                info.node.name(),
+                err
+            ))
+        })?;
+    info!(
+        "RK3568 eMMC: {} {:?} rev {}.{}, serial {:#010x}, {}/{:02}",
+        identity.version,
+        identity.cid.product_name,
+        identity.cid.revision.0,
+        identity.cid.revision.1,
+        identity.cid.serial,
+        identity.cid.date.0,
+        identity.cid.date.1
+    );
+
+    let parts = HwParts::new(mmc_address, &ext_csd);
+    // 擦除是可选功能，失败时只是不提供
+    let eraser = Eraser::new(mmc_address, &ext_csd, is_sector_addressed(&emmc))
+        .map(Some)
+        .unwrap_or_else(|err| {
+            warn!("RK3568 eMMC: erase disabled: {}", err);
+            None
+        });
+
+    let cache = WriteCache::new(mmc_address, &ext_csd, is_sector_addressed(&emmc));
+
+    let host = Arc::new(Mutex::new(emmc));
+    #[cfg(not(feature = "pio"))]
+    let completion = Arc::new(Completion::default());
+    let card_info = EmmcInfo::new(
+        Arc::clone(&host),
+        mmc_address,
+        identity,
+        #[cfg(not(feature = "pio"))]
+        Arc::clone(&completion),
+    );
+    #[cfg_attr(feature = "pio", allow(unused_mut))]
+    let mut card = Card {
+        host,
+        base: mmc_address,
+        clks,
+        opened: AtomicUsize::new(0),
+        #[cfg(not(feature = "pio"))]
+        completion,
+        parts: Arc::new(parts),
+        eraser,
+        cache,
+        info: card_info,
+        ids: QueueIds::new(MAX_QUEUES),
+        #[cfg(not(feature = "pio"))]
+        cqe: None,
+        dwcmshc,
+        recovery: Mutex::new(Recovery::new("RK3568 eMMC", mode)),
+    };
+
+    // 分区表经 EMmcHost 读取，必须在卡进入命令队列模式之前
+    let hw_part = HwPart::from_name(PARTITION);
+    let selector = match hw_part {
+        Some(_) => Selector::Whole,
+        None => Selector::parse(PARTITION),
+    };
+    let layout = read_layout(&card, selector)?;
+
+    #[cfg(not(feature = "pio"))]
+    if caps.supports_cqe {
+        card.enable_cqe(&ext_csd);
+    }
+    #[cfg(feature = "pio")]
+    if caps.supports_cqe {
+        warn!("RK3568 eMMC: supports-cqe ignored, command queueing needs ADMA2");
+    }
 
-    if emmc.init().is_ok() {
-        info!("RK3568 eMMC: successfully initialized");
-    } else {
-        warn!("RK3568 eMMC: init failed");
+    #[cfg(all(feature = "irq", not(feature = "pio")))]
+    register_irq(
+        &info,
+        mmc_address,
+        Arc::clone(&card.completion),
+        card.cqe.clone(),
+    )?;
+
+    let emmc = EmmcDriver::new(card);
+
+    if let Some(transport) = emmc.rpmb() {
+        RPMB.call_once(|| transport);
     }
+    INFO.call_once(|| emmc.info());
+
+    let boot = match hw_part {
+        Some(part) => Some(emmc.hw_partition(part).ok_or_else(|| {
+            OnProbeError::other(alloc::format!(
+                "[{}] the card has no {:?} area",
+                info.node.name(),
+                part
+            ))
+        })?),
+        None => None,
+    };
 
-    let emmc = EmmcDriver::new(emmc);
-    let dev = rdif_block::Block::new(emmc);
-    plat_dev.register(dev);
+    // 分区设备共用一个用户区设备；配置选中的设备占用探测得到的设备槽位，
+    // 其余的注册为同一节点的兄弟设备
+    let disk = emmc
+        .hw_partition(HwPart::User)
+        .expect("the user area exists");
+    let disk = Arc::new(Mutex::new(disk));
+    let partition = |part: &Partition| {
+        info!("RK3568 eMMC: registering partition {part}");
+        PartitionDevice::new(Arc::clone(&disk), part.clone())
+    };
+    for part in layout.others() {
+        register(crate::sibling_device(&plat_dev), partition(part));
+    }
+    for part in [HwPart::Boot0, HwPart::Boot1] {
+        if hw_part == Some(part) {
+            continue;
+        }
+        if let Some(boot) = emmc.hw_partition(part) {
+            info!("RK3568 eMMC: registering hardware partition {:?}", part);
+            register(crate::sibling_device(&plat_dev), boot);
+        }
+    }
+    match (boot, &layout.selected) {
+        (Some(boot), _) => {
+            info!(
+                "RK3568 eMMC: registering hardware partition {:?}",
+                boot.part()
+            );
+            register(crate::sibling_device(&plat_dev), emmc);
+            register(plat_dev, boot);
+        }
+        (None, Some(part)) => {
+            register(crate::sibling_device(&plat_dev), emmc);
+            register(plat_dev, partition(part));
+        }
+        (None, None) => register(plat_dev, emmc),
+    }
 
     Ok(())
 }
 
+#[derive(Debug)]
+pub enum InitError {
+    /// `EMmcHost::init` failed.
+    Card(SdError),
+    /// The card came up but switching its timing failed.
+    Timing(HostError),
+}
+
+impl core::fmt::Display for InitError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            InitError::Card(err) => write!(f, "eMMC init failed: {:?}", err),
+            InitError::Timing(err) => write!(f, "eMMC timing switch failed: {}", err),
+        }
+    }
+}
+
+impl core::error::Error for InitError {}
+
+/// Brings the card up, retrying with the safer settings of
+/// [`MmcCaps::fallback`] until one works. Returns the last error otherwise.
+///
+/// Also returns the speed mode control and the bus mode it ended up in, for
+/// [`Recovery`].
+fn init_card(
+    base: usize,
+    core_clk: &ClkRef,
+    emmc_clk: &EmmcClk,
+    caps: MmcCaps,
+) -> Result<(EMmcHost, Dwcmshc, BusMode), InitError> {
+    let mut last_err = None;
+
+    for (attempt, caps) in core::iter::successors(Some(caps), MmcCaps::fallback).enumerate() {
+        if let Some(err) = &last_err {
+            warn!("RK3568 eMMC: {}, retrying with {:?}", err, caps);
+        }
+
+        // 每次都从传统时序重新开始，并限制 sdmmc 初始化时使用的时钟
+        let dwcmshc = Dwcmshc::new(base, core_clk.clone(), caps);
+        dwcmshc.reset_timing();
+        emmc_clk.set_max_rate(caps.max_frequency);
+
+        let mut emmc = EMmcHost::new(base);
+        if let Err(err) = emmc.init() {
+            last_err = Some(InitError::Card(err));
+            continue;
+        }
+        match dwcmshc.select_timing() {
+            Ok(timing) => {
+                info!(
+                    "RK3568 eMMC: initialized at {:?} (attempt {})",
+                    timing,
+                    attempt + 1
+                );
+                let mode = BusMode {
+                    speed: timing.bus_speed(),
+                    width: caps.bus_width,
+                };
+                return Ok((emmc, dwcmshc, mode));
+            }
+            Err(err) => last_err = Some(InitError::Timing(err)),
+        }
+    }
+
+    Err(last_err.expect("at least one attempt"))
+}
+
+/// 容量超过 2 GiB 的 eMMC 使用扇区寻址
+fn is_sector_addressed(host: &EMmcHost) -> bool {
+    host.get_block_num() > (1 << 31) / BLOCK_SIZE as u64
+}
+
+/// RPMB transport of the probed eMMC; the driver itself is owned by rdrive
+/// once registered.
+static RPMB: spin::Once<EmmcRpmb> = spin::Once::new();
+
+/// RPMB access to the eMMC, once it has been probed and if it has an RPMB
+/// partition.
+pub fn rpmb() -> Option<EmmcRpmb> {
+    RPMB.get().cloned()
+}
+
+static INFO: spin::Once<EmmcInfo> = spin::Once::new();
+
+/// Identity and health of the eMMC, once it has been probed.
+pub fn card_info() -> Option<EmmcInfo> {
+    INFO.get().cloned()
+}
+
+/// Extensions of a registered device that `rdif_block::Block` does not
+/// forward.
+trait EmmcDevice: EraseInterface + FlushInterface + Send {}
+
+impl<T: EraseInterface + FlushInterface + Send> EmmcDevice for T {}
+
+/// Every registered eMMC device, by rdrive device ID.
+static DEVICES: Mutex<Vec<(DeviceId, Box<dyn EmmcDevice>)>> = Mutex::new(Vec::new());
+
+/// Registers `dev` as the block device of `plat_dev` and keeps a handle for
+/// the erase and flush accessors below.
+fn register<T: EmmcDevice + Clone + 'static>(plat_dev: PlatformDevice, dev: T) {
+    info!(
+        "RK3568 eMMC: device {:?} erase {:?}, cache {:?}",
+        plat_dev.descriptor.device_id,
+        dev.erase_config(),
+        dev.cache_config()
+    );
+    DEVICES
+        .lock()
+        .push((plat_dev.descriptor.device_id, Box::new(dev.clone())));
+    plat_dev.register(rdif_block::Block::new(dev));
+}
+
+/// What the eMMC device `id` can erase, DISCARD included. `None` if `id` is
+/// not one of its devices.
+pub fn erase_config(id: DeviceId) -> Option<EraseConfig> {
+    let devices = DEVICES.lock();
+    let (_, dev) = devices.iter().find(|(dev_id, _)| *dev_id == id)?;
+    Some(dev.erase_config())
+}
+
+/// A queue of the eMMC device `id` that also takes erase requests, `None`
+/// if `id` is not one of its devices or no queue is left. The device must
+/// have been opened through rdrive, which turns the clocks on.
+pub fn erase_queue(id: DeviceId) -> Option<Box<dyn EraseQueue>> {
+    let mut devices = DEVICES.lock();
+    let (_, dev) = devices.iter_mut().find(|(dev_id, _)| *dev_id == id)?;
+    dev.create_erase_queue()
+}
+
+/// Whether the eMMC device `id` has its write cache on and takes FUA
+/// writes. `None` if `id` is not one of its devices.
+pub fn cache_config(id: DeviceId) -> Option<CacheConfig> {
+    let devices = DEVICES.lock();
+    let (_, dev) = devices.iter().find(|(dev_id, _)| *dev_id == id)?;
+    Some(dev.cache_config())
+}
+
+/// A queue of the eMMC device `id` that also takes flush and FUA requests,
+/// `None` if `id` is not one of its devices or no queue is left. With the
+/// write cache on, writes through plain `rdif_block` queues are only
+/// durable after a flush on one of these. The device must be open, as for
+/// [`erase_queue`].
+pub fn flush_queue(id: DeviceId) -> Option<Box<dyn FlushQueue>> {
+    let mut devices = DEVICES.lock();
+    let (_, dev) = devices.iter_mut().find(|(dev_id, _)| *dev_id == id)?;
+    dev.create_flush_queue()
+}
+
+/// Reads the partition table of the user area and resolves `selector`.
+fn read_layout(card: &Card, selector: Selector<'_>) -> Result<Layout, OnProbeError> {
+    let mut host = card.host.lock();
+    // 引导程序可能让卡停在引导分区
+    card.parts.select(HwPart::User).map_err(|err| {
+        OnProbeError::other(alloc::format!(
+            "RK3568 eMMC: failed to select the user area: {}",
+            err
+        ))
+    })?;
+
+    let num_blocks = host.get_block_num();
+    let layout = axbsp_block::layout(num_blocks, selector, |lba, buf| {
+        host.read_blocks(lba as u32, (buf.len() / BLOCK_SIZE) as u16, buf)
+    })
+    .map_err(|err| {
+        OnProbeError::other(alloc::format!(
+            "RK3568 eMMC: partition {PARTITION:?}: {err}"
+        ))
+    })?;
+    for part in &layout.partitions {
+        info!("RK3568 eMMC: partition {part}");
+    }
+    Ok(layout)
+}
+
+/// Controller the registered interrupt handler acknowledges. The platform
+/// IRQ layer takes a plain `fn()`, so it is kept here.
+#[cfg(all(feature = "irq", not(feature = "pio")))]
+static IRQ_CONTEXT: spin::Once<IrqContext> = spin::Once::new();
+
+#[cfg(all(feature = "irq", not(feature = "pio")))]
+struct IrqContext {
+    base: usize,
+    completion: Arc<Completion>,
+    cqe: Option<Arc<Cqe>>,
+}
+
+/// Acknowledges the controller and leaves the signalled queues to
+/// [`EmmcDriver::handle_irq`](Interface::handle_irq), which reports them.
+#[cfg(all(feature = "irq", not(feature = "pio")))]
+fn emmc_irq_handler() {
+    if let Some(ctx) = IRQ_CONTEXT.get() {
+        let mut queues = 0;
+        if let Some(id) = ctx.completion.handle_irq(ctx.base) {
+            queues |= 1 << id;
+        }
+        if let Some(cqe) = &ctx.cqe {
+            queues |= cqe.handle_irq();
+        }
+        ctx.completion.signal(queues);
+    }
+}
+
+/// Registers the SDHCI interrupt from the node's `interrupts` property.
+#[cfg(all(feature = "irq", not(feature = "pio")))]
+fn register_irq(
+    info: &FdtInfo<'_>,
+    base: usize,
+    completion: Arc<Completion>,
+    cqe: Option<Arc<Cqe>>,
+) -> Result<(), OnProbeError> {
+    let interrupts = info.interrupts();
+    // GIC 说明符：<类型 编号 触发方式>，SPI 从 32 开始，PPI 从 16 开始
+    let irq = match interrupts.first().map(|cells| cells.as_slice()) {
+        Some([0, num, ..]) => *num as usize + 32,
+        Some([1, num, ..]) => *num as usize + 16,
+        _ => {
+            return Err(OnProbeError::other(alloc::format!(
+                "[{}] has no usable interrupts",
+                info.node.name()
+            )));
+        }
+    };
+
+    IRQ_CONTEXT.call_once(|| IrqContext {
+        base,
+        completion,
+        cqe,
+    });
+    if !axklib::irq::register(irq, emmc_irq_handler) {
+        return Err(OnProbeError::other(alloc::format!(
+            "[{}] failed to register IRQ {}",
+            info.node.name(),
+            irq
+        )));
+    }
+    axklib::irq::set_enable(irq, true);
+    info!("RK3568 eMMC: IRQ {} registered", irq);
+
+    Ok(())
+}
+
+/// State of one card, shared by all of its devices and queues.
+struct Card {
+    host: Arc<Mutex<EMmcHost>>,
+    /// Mapped base address of the SDHCI registers.
+    #[cfg_attr(feature = "pio", allow(dead_code))]
+    base: usize,
+    /// 设备树 `clocks` 中的全部时钟，设备关闭时全部门控掉
+    clks: Vec<ClkRef>,
+    /// Open devices of the card; its clocks run while there are any.
+    opened: AtomicUsize,
+    #[cfg(not(feature = "pio"))]
+    completion: Arc<Completion>,
+    parts: Arc<HwParts>,
+    /// `None` if the card cannot erase.
+    eraser: Option<Eraser>,
+    cache: WriteCache,
+    info: EmmcInfo,
+    ids: QueueIds,
+    /// `None` unless command queueing is enabled.
+    #[cfg(not(feature = "pio"))]
+    cqe: Option<Arc<Cqe>>,
+    /// Speed mode control, for recovery after bus errors.
+    dwcmshc: Dwcmshc,
+    recovery: Mutex<Recovery>,
+}
+
+impl Card {
+    /// Moves data transfers to the command queue engine if the card
+    /// supports it, with one task slot per queue. Stays on ADMA2 otherwise.
+    /// Must be called before any queue exists.
+    #[cfg(not(feature = "pio"))]
+    fn enable_cqe(&mut self, ext_csd: &[u8; 512]) {
+        match Cqe::new(self.base, ext_csd, Arc::clone(&self.completion)) {
+            Ok(Some(cqe)) => {
+                self.ids = QueueIds::new(cqe.depth());
+                self.cqe = Some(Arc::new(cqe));
+            }
+            Ok(None) => info!("RK3568 eMMC: the card has no command queue"),
+            Err(err) => warn!("RK3568 eMMC: {}, using ADMA2", err),
+        }
+    }
+}
+
+/// One hardware partition of a card. Clones are further devices on the
+/// same partition.
+#[derive(Clone)]
 pub struct EmmcDriver {
-    pub host: Arc<Mutex<EMmcHost>>,
+    card: Arc<Card>,
+    /// Hardware partition this device reads and writes.
+    part: HwPart,
 }
 
 impl EmmcDriver {
-    /// Creates a new `EmmcDriver` instance.
-    pub fn new(emmc_host: EMmcHost) -> Self {
-        let host = Arc::new(Mutex::new(emmc_host));
-        EmmcDriver { host }
+    /// The user area of `card`.
+    fn new(card: Card) -> Self {
+        EmmcDriver {
+            card: Arc::new(card),
+            part: HwPart::User,
+        }
+    }
+
+    /// Another device on the same card for hardware partition `part`, or
+    /// `None` if the card has no such area. Requests of each device switch
+    /// the card to their partition first.
+    pub fn hw_partition(&self, part: HwPart) -> Option<EmmcDriver> {
+        let user_blocks = self.card.host.lock().get_block_num();
+        if part == HwPart::Rpmb || self.card.parts.num_blocks(part, user_blocks) == 0 {
+            return None;
+        }
+        Some(EmmcDriver {
+            part,
+            ..self.clone()
+        })
+    }
+
+    /// RPMB transport of the card, `None` without an RPMB partition.
+    pub fn rpmb(&self) -> Option<EmmcRpmb> {
+        if self.card.parts.num_blocks(HwPart::Rpmb, 0) == 0 {
+            return None;
+        }
+        Some(EmmcRpmb::new(
+            Arc::clone(&self.card.host),
+            Arc::clone(&self.card.parts),
+            self.card.base,
+            #[cfg(not(feature = "pio"))]
+            Arc::clone(&self.card.completion),
+            #[cfg(not(feature = "pio"))]
+            self.card.cqe.clone(),
+        ))
+    }
+
+    /// Identity and health queries that stay usable once the driver is
+    /// registered.
+    pub fn info(&self) -> EmmcInfo {
+        self.card.info.clone()
+    }
+
+    /// CID and `EXT_CSD` details read at probe time.
+    pub fn identity(&self) -> &CardIdentity {
+        self.card.info.identity()
+    }
+
+    /// Current wear estimates of the card, see [`EmmcInfo::health`].
+    pub fn health(&self) -> Result<CardHealth, HostError> {
+        self.card.info.health()
+    }
+
+    pub fn part(&self) -> HwPart {
+        self.part
+    }
+
+    /// Write protection of this device's boot area; writes are refused
+    /// unless it is [`BootWp::None`].
+    pub fn write_protect(&self) -> BootWp {
+        self.card.parts.write_protect(self.part)
+    }
+
+    #[cfg(not(feature = "pio"))]
+    fn set_irq_enabled(&self, enable: bool) {
+        self.card.completion.set_irq_enabled(enable);
+    }
+
+    #[cfg(feature = "pio")]
+    fn set_irq_enabled(&self, enable: bool) {
+        if enable {
+            warn!("RK3568 eMMC: interrupts are not supported in PIO mode");
+        }
+    }
+
+    #[cfg(not(feature = "pio"))]
+    fn irq_enabled(&self) -> bool {
+        self.card.completion.is_irq_enabled()
+    }
+
+    #[cfg(feature = "pio")]
+    fn irq_enabled(&self) -> bool {
+        false
+    }
+
+    /// Returns the IDs of the queues whose transfers were signalled, as a
+    /// bit mask, including those acknowledged by the registered handler.
+    #[cfg(not(feature = "pio"))]
+    fn ack_irq(&self) -> u32 {
+        let mut queues = self.card.completion.take_signalled();
+        if let Some(id) = self.card.completion.handle_irq(self.card.base) {
+            queues |= 1 << id;
+        }
+        if let Some(cqe) = &self.card.cqe {
+            // 任务槽位即队列 ID
+            queues |= cqe.handle_irq();
+        }
+        queues
+    }
+
+    #[cfg(feature = "pio")]
+    fn ack_irq(&self) -> u32 {
+        0
+    }
+
+    fn new_queue(&self) -> Option<EmmcQueue> {
+        let Some(id) = self.card.ids.alloc() else {
+            warn!("RK3568 eMMC: all {} queues are in use", self.card.ids.limit);
+            return None;
+        };
+
+        // 创建新的队列结构体实例
+        #[cfg(not(feature = "pio"))]
+        let adma = {
+            let sector_addressing = is_sector_addressed(&self.card.host.lock());
+            match Adma::new(
+                self.card.base,
+                id,
+                sector_addressing,
+                Arc::clone(&self.card.completion),
+            ) {
+                Ok(adma) => adma,
+                Err(err) => {
+                    warn!("RK3568 eMMC: {}", err);
+                    self.card.ids.free(id);
+                    return None;
+                }
+            }
+        };
+
+        Some(EmmcQueue::new(
+            id,
+            &self.card,
+            self.part,
+            #[cfg(not(feature = "pio"))]
+            adma,
+        ))
+    }
+
+    fn set_bus_clks(&self, enable: bool) -> Result<(), KError> {
+        for clk_ref in &self.card.clks {
+            let id = clk_ref.id.into();
+            clk_ref.provider.with(|clk| {
+                if enable {
+                    clk.enable(id)
+                } else {
+                    clk.disable(id)
+                }
+            })?;
+        }
+
+        Ok(())
     }
 }
 
+/// The user area, the boot areas and the partitions of a card are separate
+/// devices, so the clocks go on with the first one opened and off with the
+/// last one closed.
 impl DriverGeneric for EmmcDriver {
     fn open(&mut self) -> Result<(), KError> {
+        if self.card.opened.fetch_add(1, Ordering::AcqRel) == 0 {
+            self.set_bus_clks(true).inspect_err(|_| {
+                self.card.opened.fetch_sub(1, Ordering::AcqRel);
+            })?;
+        }
         Ok(())
     }
 
     fn close(&mut self) -> Result<(), KError> {
+        let opened = self
+            .card
+            .opened
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+        if opened == Ok(1) {
+            self.set_bus_clks(false)?;
+        }
         Ok(())
     }
 }
 
+impl EraseInterface for EmmcDriver {
+    fn erase_config(&self) -> EraseConfig {
+        self.card
+            .eraser
+            .as_ref()
+            .map_or_else(EraseConfig::default, Eraser::config)
+    }
+
+    fn create_erase_queue(&mut self) -> Option<Box<dyn EraseQueue>> {
+        Some(Box::new(self.new_queue()?))
+    }
+}
+
+impl FlushInterface for EmmcDriver {
+    fn cache_config(&self) -> CacheConfig {
+        self.card.cache.config()
+    }
+
+    fn create_flush_queue(&mut self) -> Option<Box<dyn FlushQueue>> {
+        Some(Box::new(self.new_queue()?))
+    }
+}
+
 impl Interface for EmmcDriver {
     fn create_queue(&mut self) -> Option<alloc::boxed::Box<dyn rdif_block::IQueue>> {
-        // 创建新的队列结构体实例
-        Some(alloc::boxed::Box::new(EmmcQueue {
-            host: Arc::clone(&self.host),
-        }))
+        Some(alloc::boxed::Box::new(self.new_queue()?))
     }
 
     fn enable_irq(&mut self) {
-        todo!()
+        self.set_irq_enabled(true);
     }
 
     fn disable_irq(&mut self) {
-        todo!()
+        self.set_irq_enabled(false);
     }
 
     fn is_irq_enabled(&self) -> bool {
-        false
+        self.irq_enabled()
     }
 
+    /// Acknowledges the interrupts of an ADMA2 transfer or of command queue
+    /// tasks and reports them on the queues that issued them.
     fn handle_irq(&mut self) -> rdif_block::Event {
-        rdif_block::Event::none()
+        let mut event = rdif_block::Event::none();
+        let mut queues = self.ack_irq();
+        while queues != 0 {
+            event.queue.insert(queues.trailing_zeros() as usize);
+            queues &= queues - 1;
+        }
+        event
+    }
+}
+
+/// IDs of the queues of one card; with command queueing a queue's ID is also
+/// its task slot.
+struct QueueIds {
+    used: AtomicU32,
+    limit: usize,
+}
+
+impl QueueIds {
+    fn new(limit: usize) -> Self {
+        QueueIds {
+            used: AtomicU32::new(0),
+            limit,
+        }
+    }
+
+    fn alloc(&self) -> Option<usize> {
+        let first_free = |used: u32| (!used).trailing_zeros() as usize;
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                let id = first_free(used);
+                (id < self.limit).then(|| used | 1 << id)
+            })
+            .ok()
+            .map(first_free)
+    }
+
+    fn free(&self, id: usize) {
+        self.used.fetch_and(!(1 << id), Ordering::Release);
     }
 }
 
 /// 专门用于处理I/O队列操作的结构体
+///
+/// Requests get their own IDs and complete asynchronously, in submission
+/// order within a queue, and [`IQueue::poll_request`] advances them and
+/// reports each request's outcome once.
+///
+/// Without command queueing, ADMA2 transfers of all queues run one at a
+/// time; a transfer waits while another queue's is in flight. With it, each
+/// queue keeps a task in its own slot and the card runs them concurrently,
+/// as long as they are on the same hardware partition: a queue of another
+/// partition waits until no task is in flight.
 pub struct EmmcQueue {
-    host: Arc<Mutex<EMmcHost>>,
+    id: usize,
+    card: Arc<Card>,
+    part: HwPart,
+    #[cfg(not(feature = "pio"))]
+    adma: Adma,
+    next_id: usize,
+    /// 尚未完成的请求，队首的请求正在传输
+    #[cfg(not(feature = "pio"))]
+    pending: VecDeque<Transfer>,
+    /// Outcomes not yet collected by `poll_request`.
+    finished: BTreeMap<usize, Result<(), TransferError>>,
+}
+
+#[derive(Debug)]
+enum TransferError {
+    #[cfg(not(feature = "pio"))]
+    Adma(AdmaError),
+    #[cfg(not(feature = "pio"))]
+    Cqe(CqeError),
+    #[cfg(feature = "pio")]
+    Sd(SdError),
+    /// Switching the card to the queue's hardware partition failed.
+    Switch(HostError),
+    /// A command outside of the data path failed: an erase, a cache flush
+    /// or the `SET_BLOCK_COUNT` of a reliable write.
+    Cmd(HostError),
+    /// Bus errors persisted down to the slowest bus mode.
+    Recovery(RecoveryFailed),
+}
+
+impl TransferError {
+    /// A CRC or timeout error, which [`Recovery`] deals with.
+    fn is_bus_error(&self) -> bool {
+        match self {
+            #[cfg(not(feature = "pio"))]
+            TransferError::Adma(err) => err.is_bus_error(),
+            #[cfg(not(feature = "pio"))]
+            TransferError::Cqe(err) => matches!(err, CqeError::Task),
+            #[cfg(feature = "pio")]
+            TransferError::Sd(err) => matches!(
+                err,
+                SdError::Timeout | SdError::DataTimeout | SdError::CrcError | SdError::DataCrcError
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl EmmcQueue {
+    fn new(
+        id: usize,
+        card: &Arc<Card>,
+        part: HwPart,
+        #[cfg(not(feature = "pio"))] adma: Adma,
+    ) -> Self {
+        EmmcQueue {
+            id,
+            card: Arc::clone(card),
+            part,
+            #[cfg(not(feature = "pio"))]
+            adma,
+            next_id: 0,
+            #[cfg(not(feature = "pio"))]
+            pending: VecDeque::new(),
+            finished: BTreeMap::new(),
+        }
+    }
+}
+
+impl EmmcQueue {
+    /// Aborts, resets and possibly slows down the bus after `err`, see
+    /// [`Recovery::recover`]. Returns `err` to be retried, or
+    /// [`TransferError::Recovery`] once the slowest mode has failed too.
+    fn recover(&mut self, err: TransferError) -> TransferError {
+        let _host = self.card.host.lock();
+        // 其他队列的传输或命令队列任务先跑完，结果留给它们自己的 poll
+        #[cfg(not(feature = "pio"))]
+        match &self.card.cqe {
+            Some(cqe) => cqe.drain(),
+            None => {
+                if !self.card.completion.drain(self.card.base) {
+                    warn!(
+                        "RK3568 eMMC: transfer of another queue still running, recovering anyway"
+                    );
+                }
+            }
+        }
+
+        let mut recovery = self.card.recovery.lock();
+        // Dwcmshc 只有寄存器地址和配置，各处的副本等价
+        let dwcmshc = &mut self.card.dwcmshc.clone();
+        #[cfg(not(feature = "pio"))]
+        let result = match &self.card.cqe {
+            // 命令队列模式下不能切换总线时序
+            Some(cqe) => cqe
+                .without_cmdq(|| Ok(recovery.recover(dwcmshc)))
+                .unwrap_or_else(|err| {
+                    warn!("RK3568 eMMC: {}", err);
+                    Ok(())
+                }),
+            None => recovery.recover(dwcmshc),
+        };
+        #[cfg(feature = "pio")]
+        let result = recovery.recover(dwcmshc);
+
+        match result {
+            Ok(()) => err,
+            Err(failed) => TransferError::Recovery(failed),
+        }
+    }
+}
+
+impl Drop for EmmcQueue {
+    fn drop(&mut self) {
+        self.card.ids.free(self.id);
+    }
+}
+
+/// A request accepted by the ADMA2 queue.
+#[cfg(not(feature = "pio"))]
+struct Transfer {
+    id: usize,
+    block: usize,
+    blocks: usize,
+    /// Blocks already transferred.
+    done: usize,
+    /// Whether the current chunk has been issued.
+    started: bool,
+    op: Op,
+}
+
+/// What a [`Transfer`] does.
+///
+/// Data buffers stay mapped for DMA until the request is popped off the
+/// queue, after its last chunk has completed or failed.
+#[cfg(not(feature = "pio"))]
+enum Op {
+    Read {
+        data: DSliceMut<'static, u8>,
+    },
+    Write {
+        data: DSlice<'static, u8>,
+        /// Blocks per reliable write, for FUA writes.
+        reliable: Option<usize>,
+    },
+    /// Runs by PIO when it reaches the head of the queue.
+    Erase {
+        kind: EraseKind,
+    },
+    /// Same as an erase.
+    Flush,
+}
+
+// SAFETY: 块设备层保证请求的缓冲区在 poll_request 报告完成之前一直有效，
+// 映射随请求出队一起释放
+#[cfg(not(feature = "pio"))]
+unsafe impl Send for Transfer {}
+
+#[cfg(not(feature = "pio"))]
+impl Transfer {
+    /// Takes the outcome of the chunk in flight and returns the request's
+    /// once it is known. After a CRC or timeout error `recover` runs bus
+    /// recovery; unless that gives up, the request stays in flight and the
+    /// same chunk is issued again.
+    fn chunk_done(
+        &mut self,
+        result: Result<(), TransferError>,
+        recover: impl FnOnce(TransferError) -> TransferError,
+    ) -> Option<Result<(), TransferError>> {
+        self.started = false;
+        match result {
+            Ok(()) => {
+                self.done += self.chunk();
+                (self.done >= self.blocks).then_some(Ok(()))
+            }
+            Err(err) if err.is_bus_error() => match recover(err) {
+                err if err.is_bus_error() => None,
+                err => Some(Err(err)),
+            },
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    /// Number of blocks the next command moves.
+    fn chunk(&self) -> usize {
+        let max = match &self.op {
+            Op::Write {
+                reliable: Some(max),
+                ..
+            } => *max,
+            _ => MAX_BLOCKS_PER_CMD,
+        };
+        (self.blocks - self.done).min(max)
+    }
+
+    fn is_data(&self) -> bool {
+        matches!(self.op, Op::Read { .. } | Op::Write { .. })
+    }
+
+    /// Issues the next chunk through `cqe` if command queueing is enabled,
+    /// as a plain ADMA2 transfer otherwise.
+    fn start(
+        &self,
+        adma: &mut Adma,
+        cache: &WriteCache,
+        cqe: Option<&Cqe>,
+    ) -> Result<(), TransferError> {
+        // 提交时已由 check_range 限制在 32 位地址内
+        let block = (self.block + self.done) as u32;
+        let offset = self.done * BLOCK_SIZE;
+        let blocks = self.chunk();
+
+        let result = match &self.op {
+            Op::Read { data } => {
+                let bus = data.bus_addr() + offset as u64;
+                match cqe {
+                    Some(cqe) => adma.queue_read(cqe, block, blocks as u16, bus),
+                    None => adma.start_read(block, blocks as u16, bus),
+                }
+            }
+            Op::Write { data, reliable } => {
+                let bus = data.bus_addr() + offset as u64;
+                let blocks = blocks as u16;
+                match cqe {
+                    // 命令队列中可靠写由任务描述符标记，不用 SET_BLOCK_COUNT
+                    Some(cqe) => adma.queue_write(cqe, block, blocks, bus, reliable.is_some()),
+                    None if reliable.is_some() => {
+                        cache
+                            .start_reliable(blocks as usize)
+                            .map_err(TransferError::Cmd)?;
+                        adma.start_predefined_write(block, blocks, bus)
+                    }
+                    None => adma.start_write(block, blocks, bus),
+                }
+            }
+            Op::Erase { .. } | Op::Flush => unreachable!("runs without ADMA2"),
+        };
+        result.map_err(TransferError::Adma)
+    }
 }
 
 impl IQueue for EmmcQueue {
     /// Returns the total number of blocks available on the device.
     fn num_blocks(&self) -> usize {
-        self.host.lock().get_block_num() as _
+        let user_blocks = self.card.host.lock().get_block_num();
+        self.card.parts.num_blocks(self.part, user_blocks) as _
     }
 
     /// Returns the block size in bytes.
     fn block_size(&self) -> usize {
-        self.host.lock().get_block_size()
+        self.card.host.lock().get_block_size()
     }
 
     fn id(&self) -> usize {
-        0
+        self.id
     }
 
     fn buff_config(&self) -> rdif_block::BuffConfig {
         rdif_block::BuffConfig {
+            #[cfg(not(feature = "pio"))]
+            dma_mask: adma::DMA_MASK,
+            #[cfg(feature = "pio")]
             dma_mask: u64::MAX,
             align: 0x1000,
             size: self.block_size(),
@@ -173,123 +1138,490 @@ impl IQueue for EmmcQueue {
         &mut self,
         request: rdif_block::Request<'_>,
     ) -> Result<rdif_block::RequestId, rdif_block::BlkError> {
-        let id = request.block_id + OFFSET;
+        let block = request.block_id;
+        let id = self.next_id;
 
         match request.kind {
             rdif_block::RequestKind::Read(mut buffer) => {
-                if buffer.len() < BLOCK_SIZE {
-                    return Err(rdif_block::BlkError::Other(Box::new(
-                        BufferError::InvalidSize {
-                            expected: BLOCK_SIZE,
-                            actual: buffer.len(),
-                        },
-                    )));
-                }
+                let blocks = Self::validate_buffer(&buffer)?;
+                self.check_range(block, blocks)?;
+                self.submit_read(id, block, blocks, &mut buffer);
+            }
+            rdif_block::RequestKind::Write(buffer) => {
+                let blocks = Self::validate_buffer(buffer)?;
+                self.check_range(block, blocks)?;
+                self.check_writable()?;
+                self.submit_write(id, block, blocks, buffer, false);
+            }
+        }
 
-                let (prefix, _, suffix) = unsafe { buffer.align_to_mut::<u32>() };
-                if !prefix.is_empty() || !suffix.is_empty() {
-                    return Err(rdif_block::BlkError::Other(Box::new(
-                        BufferError::InvalidAlignment,
-                    )));
-                }
+        self.next_id = self.next_id.wrapping_add(1);
+        Ok(rdif_block::RequestId::new(id))
+    }
+
+    fn poll_request(&mut self, request: rdif_block::RequestId) -> Result<(), rdif_block::BlkError> {
+        let id: usize = request.into();
+        self.progress();
+
+        if let Some(result) = self.finished.remove(&id) {
+            return result.map_err(map_transfer_error);
+        }
+        if self.is_pending(id) {
+            return Err(rdif_block::BlkError::Retry);
+        }
+
+        warn!("RK3568 eMMC: poll of unknown request {}", id);
+        Err(rdif_block::BlkError::Other(Box::new(
+            QueueError::UnknownRequest(id),
+        )))
+    }
+}
+
+impl FlushQueue for EmmcQueue {
+    fn cache_config(&self) -> CacheConfig {
+        self.card.cache.config()
+    }
+
+    fn submit_flush(&mut self) -> Result<rdif_block::RequestId, rdif_block::BlkError> {
+        let id = self.next_id;
+        self.submit_flush_op(id);
+        self.next_id = self.next_id.wrapping_add(1);
+        Ok(rdif_block::RequestId::new(id))
+    }
+
+    fn submit_write_fua(
+        &mut self,
+        block_id: usize,
+        buffer: &[u8],
+    ) -> Result<rdif_block::RequestId, rdif_block::BlkError> {
+        if !self.card.cache.config().fua {
+            return Err(rdif_block::BlkError::NotSupported);
+        }
+        let blocks = Self::validate_buffer(buffer)?;
+        self.check_range(block_id, blocks)?;
+        self.check_writable()?;
+
+        let id = self.next_id;
+        self.submit_write(id, block_id, blocks, buffer, true);
+        self.next_id = self.next_id.wrapping_add(1);
+        Ok(rdif_block::RequestId::new(id))
+    }
+}
+
+impl EraseQueue for EmmcQueue {
+    fn erase_config(&self) -> EraseConfig {
+        self.card
+            .eraser
+            .as_ref()
+            .map_or_else(EraseConfig::default, Eraser::config)
+    }
+
+    fn submit_erase(
+        &mut self,
+        block_id: usize,
+        blocks: usize,
+        kind: EraseKind,
+    ) -> Result<rdif_block::RequestId, rdif_block::BlkError> {
+        self.check_range(block_id, blocks)?;
+        self.erase_config().check(block_id, blocks, kind)?;
+        self.check_writable()?;
+
+        let id = self.next_id;
+        self.submit_erase_op(id, block_id, blocks, kind);
+        self.next_id = self.next_id.wrapping_add(1);
+        Ok(rdif_block::RequestId::new(id))
+    }
+}
+
+#[cfg(not(feature = "pio"))]
+impl EmmcQueue {
+    fn submit_read(&mut self, id: usize, block: usize, blocks: usize, buffer: &mut Buffer<'_>) {
+        // SAFETY: 见 Transfer 的 Send 实现
+        let data =
+            unsafe { core::slice::from_raw_parts_mut(buffer.as_mut_ptr(), blocks * BLOCK_SIZE) };
+        self.enqueue(Transfer {
+            id,
+            block,
+            blocks,
+            done: 0,
+            started: false,
+            op: Op::Read {
+                data: DSliceMut::from(data, Direction::FromDevice),
+            },
+        });
+    }
+
+    fn submit_write(&mut self, id: usize, block: usize, blocks: usize, buffer: &[u8], fua: bool) {
+        // SAFETY: 见 Transfer 的 Send 实现
+        let data = unsafe { core::slice::from_raw_parts(buffer.as_ptr(), blocks * BLOCK_SIZE) };
+        self.enqueue(Transfer {
+            id,
+            block,
+            blocks,
+            done: 0,
+            started: false,
+            op: Op::Write {
+                data: DSlice::from(data),
+                reliable: fua.then(|| self.card.cache.reliable_blocks()),
+            },
+        });
+    }
+
+    fn submit_flush_op(&mut self, id: usize) {
+        self.enqueue(Transfer {
+            id,
+            block: 0,
+            blocks: 0,
+            done: 0,
+            started: false,
+            op: Op::Flush,
+        });
+    }
+
+    fn submit_erase_op(&mut self, id: usize, block: usize, blocks: usize, kind: EraseKind) {
+        self.enqueue(Transfer {
+            id,
+            block,
+            blocks,
+            done: 0,
+            started: false,
+            op: Op::Erase { kind },
+        });
+    }
+
+    fn enqueue(&mut self, transfer: Transfer) {
+        self.pending.push_back(transfer);
+        if self.pending.len() == 1 {
+            self.start_next();
+        }
+    }
 
-                self.host
-                    .lock()
-                    .read_blocks(id as u32, 1, &mut buffer)
-                    .map_err(|err| map_sd_error_to_blk_error(err))?;
+    fn is_pending(&self, id: usize) -> bool {
+        self.pending.iter().any(|transfer| transfer.id == id)
+    }
 
-                Ok(rdif_block::RequestId::new(0))
+    /// Starts the request at the head of the queue, failing requests that
+    /// cannot be started until one can. Leaves it waiting while another
+    /// queue's transfer is in flight, unless both are command queue tasks on
+    /// the same partition. Erases and flushes complete right here.
+    fn start_next(&mut self) {
+        while let Some(transfer) = self.pending.front_mut() {
+            let _host = self.card.host.lock();
+            let queued = self.card.cqe.is_some() && transfer.is_data();
+            if self.adma.is_busy() && !(queued && self.card.parts.is_selected(self.part)) {
+                return;
             }
-            rdif_block::RequestKind::Write(buffer) => {
-                if buffer.len() < BLOCK_SIZE {
-                    return Err(rdif_block::BlkError::Other(Box::new(
-                        BufferError::InvalidSize {
-                            expected: BLOCK_SIZE,
-                            actual: buffer.len(),
-                        },
-                    )));
+            let started = self
+                .card
+                .parts
+                .select(self.part)
+                .map_err(TransferError::Switch)
+                .and_then(|()| match transfer.op {
+                    Op::Erase { kind } => {
+                        let eraser = self.card.eraser.as_ref().expect("checked on submit");
+                        eraser
+                            .erase(transfer.block, transfer.blocks, kind)
+                            .map(|()| false)
+                            .map_err(TransferError::Cmd)
+                    }
+                    Op::Flush => self
+                        .card
+                        .cache
+                        .flush()
+                        .map(|()| false)
+                        .map_err(TransferError::Cmd),
+                    _ => transfer
+                        .start(&mut self.adma, &self.card.cache, self.card.cqe.as_deref())
+                        .map(|()| true),
+                });
+            match started {
+                Ok(true) => {
+                    transfer.started = true;
+                    return;
                 }
-
-                let (prefix, _, suffix) = unsafe { buffer.align_to::<u32>() };
-                if !prefix.is_empty() || !suffix.is_empty() {
-                    return Err(rdif_block::BlkError::Other(Box::new(
-                        BufferError::InvalidAlignment,
-                    )));
+                Ok(false) => {
+                    self.finished.insert(transfer.id, Ok(()));
+                    self.pending.pop_front();
+                }
+                Err(err) => {
+                    self.finished.insert(transfer.id, Err(err));
+                    self.pending.pop_front();
                 }
+            }
+        }
+    }
 
-                // 修复：使用正确的 self.host.lock() 而不是 self.0
-                self.host
-                    .lock()
-                    .write_blocks(id as u32, 1, buffer)
-                    .map_err(|err| map_sd_error_to_blk_error(err))?;
+    /// Collects the outcome of the command in flight, then issues the next
+    /// chunk of the same request or the next request.
+    fn progress(&mut self) {
+        let Some(transfer) = self.pending.front_mut() else {
+            return;
+        };
+        if !transfer.started {
+            self.start_next();
+            return;
+        }
+        let result = match &self.card.cqe {
+            Some(cqe) => cqe
+                .poll(self.id)
+                .map(|result| result.map_err(TransferError::Cqe)),
+            None => self
+                .adma
+                .poll()
+                .map(|result| result.map_err(TransferError::Adma)),
+        };
+        let Some(result) = result else {
+            return;
+        };
 
-                Ok(rdif_block::RequestId::new(0))
+        let mut transfer = self.pending.pop_front().expect("checked above");
+        match transfer.chunk_done(result, |err| self.recover(err)) {
+            None => self.pending.push_front(transfer),
+            Some(result) => {
+                if result.is_ok() {
+                    self.card.recovery.lock().succeeded();
+                    // 读到的数据可能被 CPU 缓存中的旧内容遮住，DMA 完成后再失效一次
+                    if let Op::Read { data } = &transfer.op {
+                        data.preper_read_all();
+                    }
+                }
+                self.finished.insert(transfer.id, result);
             }
         }
+        self.start_next();
     }
+}
 
-    fn poll_request(
+/// PIO 模式下请求在提交时同步完成，结果留给 poll_request 取走
+#[cfg(feature = "pio")]
+impl EmmcQueue {
+    fn submit_read(&mut self, id: usize, block: usize, blocks: usize, buffer: &mut Buffer<'_>) {
+        let result = self.with_retries(|queue| {
+            let mut host = queue.card.host.lock();
+            queue
+                .card
+                .parts
+                .select(queue.part)
+                .map_err(TransferError::Switch)?;
+            buffer[..blocks * BLOCK_SIZE]
+                .chunks_mut(MAX_BLOCKS_PER_CMD * BLOCK_SIZE)
+                .enumerate()
+                .try_for_each(|(index, chunk)| {
+                    let block = block + index * MAX_BLOCKS_PER_CMD;
+                    host.read_blocks(block as u32, (chunk.len() / BLOCK_SIZE) as u16, chunk)
+                })
+                .map_err(TransferError::Sd)
+        });
+        self.finished.insert(id, result);
+    }
+
+    fn submit_write(&mut self, id: usize, block: usize, blocks: usize, buffer: &[u8], fua: bool) {
+        if fua {
+            let _host = self.card.host.lock();
+            let result = self
+                .card
+                .parts
+                .select(self.part)
+                .map_err(TransferError::Switch)
+                .and_then(|()| {
+                    self.card
+                        .cache
+                        .write_reliable(block, &buffer[..blocks * BLOCK_SIZE])
+                        .map_err(TransferError::Cmd)
+                });
+            self.finished.insert(id, result);
+            return;
+        }
+        let result = self.with_retries(|queue| {
+            let mut host = queue.card.host.lock();
+            queue
+                .card
+                .parts
+                .select(queue.part)
+                .map_err(TransferError::Switch)?;
+            buffer[..blocks * BLOCK_SIZE]
+                .chunks(MAX_BLOCKS_PER_CMD * BLOCK_SIZE)
+                .enumerate()
+                .try_for_each(|(index, chunk)| {
+                    let block = block + index * MAX_BLOCKS_PER_CMD;
+                    host.write_blocks(block as u32, (chunk.len() / BLOCK_SIZE) as u16, chunk)
+                })
+                .map_err(TransferError::Sd)
+        });
+        self.finished.insert(id, result);
+    }
+
+    /// Runs `attempt` until it succeeds or fails for good, with bus recovery
+    /// after each CRC or timeout error.
+    fn with_retries(
         &mut self,
-        _request: rdif_block::RequestId,
-    ) -> Result<(), rdif_block::BlkError> {
+        mut attempt: impl FnMut(&Self) -> Result<(), TransferError>,
+    ) -> Result<(), TransferError> {
+        loop {
+            match attempt(self) {
+                Ok(()) => {
+                    self.card.recovery.lock().succeeded();
+                    return Ok(());
+                }
+                Err(err) if err.is_bus_error() => match self.recover(err) {
+                    err if err.is_bus_error() => continue,
+                    err => return Err(err),
+                },
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn submit_erase_op(&mut self, id: usize, block: usize, blocks: usize, kind: EraseKind) {
+        let _host = self.card.host.lock();
+        let eraser = self.card.eraser.as_ref().expect("checked on submit");
+        let result = self
+            .card
+            .parts
+            .select(self.part)
+            .map_err(TransferError::Switch)
+            .and_then(|()| {
+                eraser
+                    .erase(block, blocks, kind)
+                    .map_err(TransferError::Cmd)
+            });
+        self.finished.insert(id, result);
+    }
+
+    fn submit_flush_op(&mut self, id: usize) {
+        let _host = self.card.host.lock();
+        let result = self.card.cache.flush().map_err(TransferError::Cmd);
+        self.finished.insert(id, result);
+    }
+
+    fn is_pending(&self, _id: usize) -> bool {
+        false
+    }
+
+    fn progress(&mut self) {}
+}
+
+impl EmmcQueue {
+    /// Checks that `blocks` blocks starting at `block` lie on the partition.
+    /// Block addresses are 32 bits on the bus, so every block that passes
+    /// also fits the command argument.
+    fn check_range(&self, block: usize, blocks: usize) -> Result<(), rdif_block::BlkError> {
+        let limit = self.num_blocks().min(u32::MAX as usize);
+        match block.checked_add(blocks) {
+            Some(end) if end <= limit => Ok(()),
+            _ => Err(rdif_block::BlkError::InvalidBlockIndex(block)),
+        }
+    }
+
+    /// Fails writes and erases of a write protected boot area.
+    fn check_writable(&self) -> Result<(), rdif_block::BlkError> {
+        let wp = self.card.parts.write_protect(self.part);
+        if wp != BootWp::None {
+            return Err(rdif_block::BlkError::Other(Box::new(
+                QueueError::WriteProtected(self.part, wp),
+            )));
+        }
         Ok(())
     }
+
+    /// Checks that `buffer` is u32-aligned and a whole number of blocks, and
+    /// returns that number.
+    fn validate_buffer(buffer: &[u8]) -> Result<usize, rdif_block::BlkError> {
+        if buffer.len() < BLOCK_SIZE {
+            return Err(rdif_block::BlkError::Other(Box::new(
+                BufferError::InvalidSize {
+                    expected: BLOCK_SIZE,
+                    actual: buffer.len(),
+                },
+            )));
+        }
+
+        if !buffer.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(rdif_block::BlkError::Other(Box::new(
+                BufferError::PartialBlock {
+                    actual: buffer.len(),
+                },
+            )));
+        }
+
+        let (prefix, _, suffix) = unsafe { buffer.align_to::<u32>() };
+        if !prefix.is_empty() || !suffix.is_empty() {
+            return Err(rdif_block::BlkError::Other(Box::new(
+                BufferError::InvalidAlignment,
+            )));
+        }
+
+        Ok(buffer.len() / BLOCK_SIZE)
+    }
 }
 
+/// The controller's "core" clock, as resolved from the device tree.
 pub struct EmmcClk {
-    pub core_clk_index: usize,
+    pub core_clk: ClkRef,
+    /// Upper bound for rates `EMmcHost` asks for, 0 for none.
+    max_rate: AtomicU64,
 }
 
 impl EmmcClk {
-    pub fn new(core_clk_index: usize) -> Self {
-        EmmcClk { core_clk_index }
+    pub fn new(core_clk: ClkRef) -> Self {
+        EmmcClk {
+            core_clk,
+            max_rate: AtomicU64::new(0),
+        }
+    }
+
+    /// Limits the card clock `EMmcHost` may set, e.g. to `max-frequency`.
+    pub fn set_max_rate(&self, max: Option<u64>) {
+        self.max_rate.store(max.unwrap_or(0), Ordering::Relaxed);
     }
 }
 
 impl Clk for EmmcClk {
     fn emmc_get_clk(&self) -> Result<u64, ClkError> {
-        let clk_device = get_one::<ClkDriver>().unwrap();
-
         info!(
-            "Getting eMMC clock rate using core_clk_index: {}",
-            self.core_clk_index
+            "Getting eMMC clock rate using core clock ID: {}",
+            self.core_clk.id
         );
-        info!("Found clock device: {:?}", unsafe {
-            clk_device.force_use()
-        });
 
-        let clk = clk_device.lock().unwrap();
-        let rate = clk.get_rate(self.core_clk_index.into()).unwrap();
-        Ok(rate)
+        self.core_clk
+            .provider
+            .with(|clk| clk.get_rate(self.core_clk.id.into()))
+            .map_err(|err| {
+                warn!("Failed to read eMMC clock rate: {:?}", err);
+                ClkError::InvalidClockRate
+            })
     }
 
     fn emmc_set_clk(&self, rate: u64) -> Result<u64, ClkError> {
-        let clk_device = get_one::<ClkDriver>().unwrap();
-
         info!(
-            "Getting eMMC clock rate using core_clk_index: {}",
-            self.core_clk_index
+            "Setting eMMC clock rate using core clock ID: {}",
+            self.core_clk.id
         );
-        info!("Found clock device: {:?}", unsafe {
-            clk_device.force_use()
-        });
 
-        let mut clk = clk_device.lock().unwrap();
-        let _rate = clk.set_rate(self.core_clk_index.into(), rate).unwrap();
-        Ok(0)
+        let rate = match self.max_rate.load(Ordering::Relaxed) {
+            0 => rate,
+            max => rate.min(max),
+        };
+        self.core_clk
+            .provider
+            .with(|clk| clk.set_rounded_rate(self.core_clk.id.into(), rate))
+            .map_err(|err| {
+                warn!("Failed to set eMMC clock to {} Hz: {:?}", rate, err);
+                ClkError::InvalidClockRate
+            })
     }
 }
 
-pub fn init_clk(core_clk_index: usize) -> Result<(), ClkError> {
-    let emmc_clk = EmmcClk::new(core_clk_index);
-    let static_clk: &'static dyn Clk = Box::leak(Box::new(emmc_clk));
-    init_global_clk(static_clk);
-    Ok(())
+pub fn init_clk(core_clk: ClkRef) -> &'static EmmcClk {
+    let emmc_clk: &'static EmmcClk = Box::leak(Box::new(EmmcClk::new(core_clk)));
+    init_global_clk(emmc_clk);
+    emmc_clk
 }
 
 #[derive(Debug)]
 enum BufferError {
     InvalidSize { expected: usize, actual: usize },
+    PartialBlock { actual: usize },
     InvalidAlignment,
 }
 
@@ -303,6 +1635,13 @@ impl core::fmt::Display for BufferError {
                     expected, actual
                 )
             }
+            BufferError::PartialBlock { actual } => {
+                write!(
+                    f,
+                    "Invalid buffer size: {} is not a multiple of {}",
+                    actual, BLOCK_SIZE
+                )
+            }
             BufferError::InvalidAlignment => {
                 write!(f, "Buffer is not properly aligned for u32 access")
             }
@@ -311,27 +1650,140 @@ impl core::fmt::Display for BufferError {
 }
 
 impl core::error::Error for BufferError {}
+
+#[derive(Debug)]
+enum QueueError {
+    UnknownRequest(usize),
+    WriteProtected(HwPart, BootWp),
+}
+
+impl core::fmt::Display for QueueError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            QueueError::UnknownRequest(id) => write!(f, "Unknown request ID {}", id),
+            QueueError::WriteProtected(part, wp) => {
+                write!(f, "{:?} is write protected ({:?})", part, wp)
+            }
+        }
+    }
+}
+
+impl core::error::Error for QueueError {}
+#[cfg(feature = "pio")]
 #[derive(Debug)]
 struct SdErrorWrapper(SdError);
 
+#[cfg(feature = "pio")]
 impl core::fmt::Display for SdErrorWrapper {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "SD/eMMC Error: {:?}", self.0)
     }
 }
 
+#[cfg(feature = "pio")]
 impl core::error::Error for SdErrorWrapper {}
 
 // 错误映射函数
-fn map_sd_error_to_blk_error(err: SdError) -> rdif_block::BlkError {
+fn map_transfer_error(err: TransferError) -> rdif_block::BlkError {
     match err {
-        // 超时错误通常需要重试
-        SdError::Timeout | SdError::DataTimeout => rdif_block::BlkError::Retry,
-
         // 不支持的卡类型
-        SdError::UnsupportedCard => rdif_block::BlkError::NotSupported,
+        #[cfg(feature = "pio")]
+        TransferError::Sd(SdError::UnsupportedCard) => rdif_block::BlkError::NotSupported,
 
         // 其他错误包装为Other，使用自定义包装器
-        _ => rdif_block::BlkError::Other(Box::new(SdErrorWrapper(err))),
+        #[cfg(feature = "pio")]
+        TransferError::Sd(err) => rdif_block::BlkError::Other(Box::new(SdErrorWrapper(err))),
+
+        #[cfg(not(feature = "pio"))]
+        TransferError::Adma(AdmaError::NoMemory) => rdif_block::BlkError::NoMemory,
+        #[cfg(not(feature = "pio"))]
+        TransferError::Adma(err) => rdif_block::BlkError::Other(Box::new(err)),
+        #[cfg(not(feature = "pio"))]
+        TransferError::Cqe(err) => rdif_block::BlkError::Other(Box::new(err)),
+
+        TransferError::Switch(err) | TransferError::Cmd(err) => {
+            rdif_block::BlkError::Other(Box::new(err))
+        }
+        TransferError::Recovery(err) => rdif_block::BlkError::Other(Box::new(err)),
+    }
+}
+
+#[cfg(all(test, not(feature = "pio")))]
+mod tests {
+    use super::*;
+    use axbsp_block::BusSpeed;
+
+    /// A request of `blocks` blocks; the operation does not matter for the
+    /// bookkeeping.
+    fn transfer(blocks: usize) -> Transfer {
+        Transfer {
+            id: 7,
+            block: 0,
+            blocks,
+            done: 0,
+            started: true,
+            op: Op::Flush,
+        }
+    }
+
+    fn bus_error() -> TransferError {
+        TransferError::Adma(AdmaError::DataTimeout)
+    }
+
+    #[test]
+    fn chunks_complete_in_order() {
+        let mut transfer = transfer(MAX_BLOCKS_PER_CMD + 1);
+        let recover = |_| unreachable!("no error");
+        assert!(transfer.chunk_done(Ok(()), recover).is_none());
+        assert_eq!(transfer.done, MAX_BLOCKS_PER_CMD);
+        assert_eq!(transfer.chunk(), 1);
+        assert!(matches!(transfer.chunk_done(Ok(()), recover), Some(Ok(()))));
+    }
+
+    #[test]
+    fn recovered_bus_error_reissues_the_chunk() {
+        let mut transfer = transfer(MAX_BLOCKS_PER_CMD * 2);
+        assert!(transfer.chunk_done(Ok(()), |_| unreachable!()).is_none());
+
+        // 恢复后请求仍在进行，poll_request 继续返回 Retry，同一段重新发出
+        let mut recovered = 0;
+        let outcome = transfer.chunk_done(Err(bus_error()), |err| {
+            recovered += 1;
+            err
+        });
+        assert!(outcome.is_none());
+        assert_eq!(recovered, 1);
+        assert!(!transfer.started);
+        assert_eq!(transfer.done, MAX_BLOCKS_PER_CMD);
+
+        assert!(matches!(
+            transfer.chunk_done(Ok(()), |_| unreachable!()),
+            Some(Ok(()))
+        ));
+    }
+
+    #[test]
+    fn failed_recovery_ends_the_request() {
+        let mut transfer = transfer(1);
+        let mode = BusMode {
+            speed: BusSpeed::Legacy,
+            width: 1,
+        };
+        let outcome = transfer.chunk_done(Err(bus_error()), |_| {
+            TransferError::Recovery(RecoveryFailed { mode })
+        });
+        assert!(matches!(outcome, Some(Err(TransferError::Recovery(_)))));
+    }
+
+    #[test]
+    fn other_errors_end_the_request_without_recovery() {
+        let mut transfer = transfer(1);
+        let outcome = transfer.chunk_done(Err(TransferError::Adma(AdmaError::NoMemory)), |_| {
+            unreachable!("not a bus error")
+        });
+        assert!(matches!(
+            outcome,
+            Some(Err(TransferError::Adma(AdmaError::NoMemory)))
+        ));
     }
 }
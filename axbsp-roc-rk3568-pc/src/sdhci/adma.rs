@@ -0,0 +1,497 @@
+extern crate alloc;
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use aarch64_cpu::registers::{CNTFRQ_EL0, CNTPCT_EL0, Readable};
+use axklib::time::busy_wait;
+use dma_api::{DVec, Direction};
+use log::warn;
+use sdmmc::BLOCK_SIZE;
+
+use super::cqe::Cqe;
+use super::regs::*;
+
+/// ADMA2 32 位描述符寻址，数据缓冲区必须位于 4 GiB 以内
+pub const DMA_MASK: u64 = u32::MAX as u64;
+
+const INT_ENABLED: u32 = INT_CMD_COMPLETE
+    | INT_XFER_COMPLETE
+    | INT_ERROR
+    | INT_CMD_ERRORS
+    | INT_DATA_ERRORS
+    | INT_ADMA_ERROR;
+
+const MMC_READ_SINGLE_BLOCK: u16 = 17;
+const MMC_READ_MULTIPLE_BLOCK: u16 = 18;
+const MMC_WRITE_BLOCK: u16 = 24;
+const MMC_WRITE_MULTIPLE_BLOCK: u16 = 25;
+
+const DESC_VALID: u16 = 1 << 0;
+const DESC_END: u16 = 1 << 1;
+const DESC_TRAN: u16 = 0b10 << 4;
+
+/// One descriptor moves at most 64 KiB; a length field of 0 means 65536.
+const DESC_MAX_LEN: u64 = 0x1_0000;
+/// dwcmshc 的 ADMA2 描述符不能跨越 128 MiB 边界
+const DESC_BOUNDARY: u64 = 0x800_0000;
+const DESC_COUNT: usize = 1024;
+
+const POLL_INTERVAL: Duration = Duration::from_micros(10);
+const CMD_POLLS: u32 = 1_000;
+/// Time after which a transfer that has neither completed nor failed counts
+/// as stalled.
+const XFER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 32-bit ADMA2 descriptor.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Desc {
+    attr: u16,
+    len: u16,
+    addr: u32,
+}
+
+#[derive(Debug)]
+pub enum AdmaError {
+    /// The descriptor table could not be allocated below [`DMA_MASK`].
+    NoMemory,
+    /// A data buffer lies outside the 32-bit DMA window.
+    Address(u64),
+    /// The buffer needs more descriptors than the table holds.
+    TooLarge(usize),
+    CmdTimeout,
+    DataTimeout,
+    /// Neither completion nor an error within [`XFER_TIMEOUT`].
+    Stalled,
+    /// Error interrupt status, with the ADMA error status register.
+    Transfer {
+        status: u32,
+        adma: u8,
+    },
+}
+
+impl core::fmt::Display for AdmaError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AdmaError::NoMemory => write!(f, "ADMA2 descriptor table allocation failed"),
+            AdmaError::Address(addr) => {
+                write!(f, "Buffer at {:#x} is outside the DMA window", addr)
+            }
+            AdmaError::TooLarge(len) => write!(f, "Buffer of {} bytes is too large", len),
+            AdmaError::CmdTimeout => write!(f, "Command timeout"),
+            AdmaError::DataTimeout => write!(f, "Data timeout"),
+            AdmaError::Stalled => write!(f, "Transfer stalled"),
+            AdmaError::Transfer { status, adma } => write!(
+                f,
+                "Transfer error: int status {:#x}, ADMA error {:#x}",
+                status, adma
+            ),
+        }
+    }
+}
+
+impl core::error::Error for AdmaError {}
+
+impl AdmaError {
+    /// A CRC, end bit, index or timeout error on the bus, as opposed to a
+    /// problem with the buffer or the descriptor table.
+    pub fn is_bus_error(&self) -> bool {
+        match self {
+            AdmaError::CmdTimeout | AdmaError::DataTimeout | AdmaError::Stalled => true,
+            AdmaError::Transfer { status, .. } => status & (INT_CMD_ERRORS | INT_DATA_ERRORS) != 0,
+            _ => false,
+        }
+    }
+}
+
+/// Interrupt state shared between the interrupt handler and [`Adma`].
+#[derive(Default)]
+pub struct Completion {
+    irq_enabled: AtomicBool,
+    /// Set while an ADMA2 transfer is running. Outside of one the handler
+    /// leaves the status register alone, since `EMmcHost` polls it.
+    in_flight: AtomicBool,
+    /// Queue whose ADMA2 transfer is in flight.
+    owner: AtomicUsize,
+    /// Status bits acknowledged by the handler or [`Completion::drain`] and
+    /// not yet consumed.
+    status: AtomicU32,
+    /// Set while the command queue engine is not halted, see [`Cqe`].
+    cqe_running: AtomicBool,
+    /// Queues signalled by the registered interrupt handler and not yet
+    /// reported through `Interface::handle_irq`, as a bit mask.
+    signalled: AtomicU32,
+}
+
+impl Completion {
+    pub fn is_irq_enabled(&self) -> bool {
+        self.irq_enabled.load(Ordering::Acquire)
+    }
+
+    /// Whether an ADMA2 transfer or command queue tasks are in flight;
+    /// other commands have to wait.
+    pub fn is_busy(&self) -> bool {
+        self.in_flight.load(Ordering::Acquire) || self.cqe_running.load(Ordering::Acquire)
+    }
+
+    pub fn is_cqe_running(&self) -> bool {
+        self.cqe_running.load(Ordering::Acquire)
+    }
+
+    pub fn set_cqe_running(&self, running: bool) {
+        self.cqe_running.store(running, Ordering::Release);
+    }
+
+    /// Chooses whether ADMA2 transfers complete through the interrupt
+    /// handler. The interrupt signal itself is only raised while a transfer
+    /// is in flight, so commands issued by `EMmcHost` keep polling.
+    pub fn set_irq_enabled(&self, enable: bool) {
+        self.irq_enabled.store(enable, Ordering::Release);
+    }
+
+    /// Records `queues` for [`Completion::take_signalled`].
+    #[cfg_attr(not(feature = "irq"), allow(dead_code))]
+    pub fn signal(&self, queues: u32) {
+        self.signalled.fetch_or(queues, Ordering::AcqRel);
+    }
+
+    /// The queues signalled since the last call.
+    pub fn take_signalled(&self) -> u32 {
+        self.signalled.swap(0, Ordering::AcqRel)
+    }
+
+    /// Waits for the ADMA2 transfer in flight, if any, to end, and keeps its
+    /// status for the owner's [`Adma::poll`]. `false` if it still runs
+    /// after [`XFER_TIMEOUT`].
+    pub fn drain(&self, base: usize) -> bool {
+        let deadline = deadline(XFER_TIMEOUT);
+        loop {
+            self.handle_irq(base);
+            let status = self.status.load(Ordering::Acquire);
+            if !self.in_flight.load(Ordering::Acquire)
+                || status & (INT_XFER_COMPLETE | INT_ERROR) != 0
+            {
+                return true;
+            }
+            if CNTPCT_EL0.get() >= deadline {
+                return false;
+            }
+            busy_wait(POLL_INTERVAL);
+        }
+    }
+
+    /// Interrupt handler body: acknowledges the controller at `base` and
+    /// records the status. Returns the queue whose transfer was signalled.
+    pub fn handle_irq(&self, base: usize) -> Option<usize> {
+        if !self.in_flight.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let reg = (base + SDHCI_INT_STATUS) as *mut u32;
+        let status = unsafe { reg.read_volatile() } & INT_ENABLED;
+        if status == 0 {
+            return None;
+        }
+        unsafe { reg.write_volatile(status) }
+
+        self.status.fetch_or(status, Ordering::AcqRel);
+        Some(self.owner.load(Ordering::Acquire))
+    }
+}
+
+/// ADMA2 data path of the dwcmshc SDHCI.
+///
+/// Read/write commands are issued directly through the SDHCI registers with
+/// auto-CMD12 stopping multi-block transfers; the card itself is brought up
+/// by `EMmcHost`. With command queueing the descriptor table serves as the
+/// transfer descriptor list of the queue's task slot instead.
+///
+/// Buffers are passed by bus address: the caller maps them and keeps them
+/// mapped until [`Adma::poll`] or [`Cqe::poll`] reports the transfer done.
+pub struct Adma {
+    regs: Regs,
+    /// ID of the owning queue.
+    id: usize,
+    table: DVec<Desc>,
+    /// 大于 2 GiB 的卡按扇区寻址，否则按字节寻址
+    sector_addressing: bool,
+    completion: Arc<Completion>,
+    /// Interrupt status gathered so far for the transfer in flight.
+    seen: u32,
+    /// Generic timer count at which the transfer in flight counts as
+    /// stalled.
+    deadline: u64,
+}
+
+impl Adma {
+    pub fn new(
+        base: usize,
+        id: usize,
+        sector_addressing: bool,
+        completion: Arc<Completion>,
+    ) -> Result<Self, AdmaError> {
+        let table = DVec::zeros(DMA_MASK, DESC_COUNT, 0x1000, Direction::ToDevice)
+            .map_err(|_| AdmaError::NoMemory)?;
+        Ok(Adma {
+            regs: Regs(base),
+            id,
+            table,
+            sector_addressing,
+            completion,
+            seen: 0,
+            deadline: 0,
+        })
+    }
+
+    /// Whether the controller runs a transfer, possibly of another queue.
+    pub fn is_busy(&self) -> bool {
+        self.completion.is_busy()
+    }
+
+    /// Issues a read without waiting for it; see [`Adma::poll`].
+    pub fn start_read(&mut self, block: u32, blocks: u16, bus: u64) -> Result<(), AdmaError> {
+        let cmd = if blocks > 1 {
+            MMC_READ_MULTIPLE_BLOCK
+        } else {
+            MMC_READ_SINGLE_BLOCK
+        };
+        self.start(cmd, block, blocks, bus, true, false)
+    }
+
+    /// Issues a write without waiting for it; see [`Adma::poll`].
+    pub fn start_write(&mut self, block: u32, blocks: u16, bus: u64) -> Result<(), AdmaError> {
+        let cmd = if blocks > 1 {
+            MMC_WRITE_MULTIPLE_BLOCK
+        } else {
+            MMC_WRITE_BLOCK
+        };
+        self.start(cmd, block, blocks, bus, false, false)
+    }
+
+    /// Issues a `WRITE_MULTIPLE_BLOCK` whose length was set by a preceding
+    /// `SET_BLOCK_COUNT`, e.g. a reliable write, so no auto-CMD12 follows.
+    pub fn start_predefined_write(
+        &mut self,
+        block: u32,
+        blocks: u16,
+        bus: u64,
+    ) -> Result<(), AdmaError> {
+        self.start(MMC_WRITE_MULTIPLE_BLOCK, block, blocks, bus, false, true)
+    }
+
+    /// Queues a read as a task in the queue's slot of `cqe`; see
+    /// [`Cqe::poll`].
+    pub fn queue_read(
+        &mut self,
+        cqe: &Cqe,
+        block: u32,
+        blocks: u16,
+        bus: u64,
+    ) -> Result<(), AdmaError> {
+        self.build_table(bus, blocks as usize * BLOCK_SIZE)?;
+        let arg = self.address(block);
+        cqe.start(self.id, self.table.bus_addr(), arg, blocks, true, false);
+        Ok(())
+    }
+
+    /// Queues a write as a task in the queue's slot of `cqe`, as a reliable
+    /// write if `reliable`.
+    pub fn queue_write(
+        &mut self,
+        cqe: &Cqe,
+        block: u32,
+        blocks: u16,
+        bus: u64,
+        reliable: bool,
+    ) -> Result<(), AdmaError> {
+        self.build_table(bus, blocks as usize * BLOCK_SIZE)?;
+        let arg = self.address(block);
+        cqe.start(self.id, self.table.bus_addr(), arg, blocks, false, reliable);
+        Ok(())
+    }
+
+    fn address(&self, block: u32) -> u32 {
+        if self.sector_addressing {
+            block
+        } else {
+            block * BLOCK_SIZE as u32
+        }
+    }
+
+    fn start(
+        &mut self,
+        cmd: u16,
+        block: u32,
+        blocks: u16,
+        bus: u64,
+        read: bool,
+        predefined: bool,
+    ) -> Result<(), AdmaError> {
+        let len = blocks as usize * BLOCK_SIZE;
+        self.build_table(bus, len)?;
+
+        self.wait_idle()?;
+
+        let host_ctrl = self.regs.read8(SDHCI_HOST_CONTROL) & !HOST_CTRL_DMA_MASK;
+        self.regs
+            .write8(SDHCI_HOST_CONTROL, host_ctrl | HOST_CTRL_ADMA32);
+        let table = self.table.bus_addr();
+        self.regs.write32(SDHCI_ADMA_ADDRESS, table as u32);
+        self.regs
+            .write32(SDHCI_ADMA_ADDRESS_HI, (table >> 32) as u32);
+
+        let enabled = self.regs.read32(SDHCI_INT_ENABLE);
+        self.regs.write32(SDHCI_INT_ENABLE, enabled | INT_ENABLED);
+        self.regs.write32(SDHCI_INT_STATUS, INT_ENABLED);
+
+        self.regs.write16(SDHCI_BLOCK_SIZE, BLOCK_SIZE as u16);
+        self.regs.write16(SDHCI_BLOCK_COUNT, blocks);
+
+        self.regs.write32(SDHCI_ARGUMENT, self.address(block));
+
+        let mut mode = TRNS_DMA | TRNS_BLK_CNT_EN;
+        if predefined {
+            mode |= TRNS_MULTI;
+        } else if blocks > 1 {
+            mode |= TRNS_MULTI | TRNS_AUTO_CMD12;
+        }
+        if read {
+            mode |= TRNS_READ;
+        }
+
+        self.seen = 0;
+        self.deadline = deadline(XFER_TIMEOUT);
+        self.completion.status.store(0, Ordering::Release);
+        self.completion.owner.store(self.id, Ordering::Release);
+        self.completion.in_flight.store(true, Ordering::Release);
+        if self.completion.is_irq_enabled() {
+            self.regs.write32(SDHCI_SIGNAL_ENABLE, INT_ENABLED);
+        }
+
+        self.regs.write16(SDHCI_TRANSFER_MODE, mode);
+        self.regs
+            .write16(SDHCI_COMMAND, (cmd << 8) | CMD_RESP_R1_DATA);
+
+        Ok(())
+    }
+
+    /// Checks the transfer in flight: `None` while it runs, its outcome once
+    /// it has finished.
+    ///
+    /// With interrupts enabled the status comes from [`Completion`], filled
+    /// in by the interrupt handler; otherwise it is read from the controller,
+    /// along with whatever [`Completion::drain`] collected.
+    /// Timeouts are reported by the controller's own command and data timers;
+    /// a transfer that still runs [`XFER_TIMEOUT`] after it started is
+    /// aborted as [`AdmaError::Stalled`]. Never waits itself.
+    pub fn poll(&mut self) -> Option<Result<(), AdmaError>> {
+        if !self.completion.in_flight.load(Ordering::Acquire) {
+            return Some(Ok(()));
+        }
+
+        // 轮询模式下状态也可能已被 Completion::drain 收走
+        self.seen |= self.completion.status.swap(0, Ordering::AcqRel);
+        if !self.completion.is_irq_enabled() {
+            let status = self.regs.read32(SDHCI_INT_STATUS) & INT_ENABLED;
+            self.regs.write32(SDHCI_INT_STATUS, status);
+            self.seen |= status;
+        }
+
+        let result = if self.seen & INT_ERROR != 0 {
+            Err(match self.seen {
+                s if s & INT_CMD_TIMEOUT != 0 => AdmaError::CmdTimeout,
+                s if s & INT_DATA_TIMEOUT != 0 => AdmaError::DataTimeout,
+                status => AdmaError::Transfer {
+                    status,
+                    adma: self.regs.read8(SDHCI_ADMA_ERROR),
+                },
+            })
+        } else if self.seen & INT_XFER_COMPLETE != 0 {
+            Ok(())
+        } else if CNTPCT_EL0.get() < self.deadline {
+            return None;
+        } else {
+            Err(AdmaError::Stalled)
+        };
+
+        Some(self.finish(result))
+    }
+
+    fn finish(&mut self, result: Result<(), AdmaError>) -> Result<(), AdmaError> {
+        self.regs.write32(SDHCI_SIGNAL_ENABLE, 0);
+        self.completion.in_flight.store(false, Ordering::Release);
+        if let Err(err) = &result {
+            warn!("ADMA2 transfer failed: {}", err);
+            self.reset(RESET_CMD | RESET_DATA);
+        }
+        result
+    }
+
+    /// Describes the `len`-byte buffer at `bus`, split at 64 KiB and 128 MiB
+    /// boundaries.
+    fn build_table(&mut self, bus: u64, len: usize) -> Result<(), AdmaError> {
+        let end = bus + len as u64;
+        if end - 1 > DMA_MASK {
+            return Err(AdmaError::Address(bus));
+        }
+
+        let mut addr = bus;
+        let mut index = 0;
+        while addr < end {
+            let boundary = (addr / DESC_BOUNDARY + 1) * DESC_BOUNDARY;
+            let chunk = (end - addr).min(DESC_MAX_LEN).min(boundary - addr);
+            if index == DESC_COUNT {
+                return Err(AdmaError::TooLarge(len));
+            }
+
+            let mut attr = DESC_VALID | DESC_TRAN;
+            if addr + chunk == end {
+                attr |= DESC_END;
+            }
+            self.table.set(
+                index,
+                Desc {
+                    attr,
+                    len: chunk as u16,
+                    addr: addr as u32,
+                },
+            );
+
+            addr += chunk;
+            index += 1;
+        }
+
+        Ok(())
+    }
+
+    fn wait_idle(&self) -> Result<(), AdmaError> {
+        for _ in 0..CMD_POLLS {
+            if self.regs.read32(SDHCI_PRESENT_STATE) & (PRESENT_CMD_INHIBIT | PRESENT_DAT_INHIBIT)
+                == 0
+            {
+                return Ok(());
+            }
+            busy_wait(POLL_INTERVAL);
+        }
+        Err(AdmaError::CmdTimeout)
+    }
+
+    fn reset(&self, mask: u8) {
+        self.regs.write8(SDHCI_SOFTWARE_RESET, mask);
+        for _ in 0..CMD_POLLS {
+            if self.regs.read8(SDHCI_SOFTWARE_RESET) & mask == 0 {
+                return;
+            }
+            busy_wait(POLL_INTERVAL);
+        }
+        warn!("SDHCI reset {:#x} did not complete", mask);
+    }
+}
+
+/// Generic timer count `timeout` from now.
+fn deadline(timeout: Duration) -> u64 {
+    let ticks = timeout.as_micros() as u64 * CNTFRQ_EL0.get() / 1_000_000;
+    CNTPCT_EL0.get() + ticks
+}
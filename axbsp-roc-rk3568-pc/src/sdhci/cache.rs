@@ -0,0 +1,125 @@
+//! eMMC volatile cache (`CACHE_CTRL`, `FLUSH_CACHE`) and the reliable
+//! writes that carry forced unit access.
+
+use core::time::Duration;
+
+use axbsp_block::CacheConfig;
+use log::{info, warn};
+
+#[cfg(feature = "pio")]
+use super::cmd::MMC_WRITE_MULTIPLE_BLOCK;
+use super::cmd::{Cmd, HostError};
+
+const EXT_CSD_FLUSH_CACHE: u8 = 32;
+const EXT_CSD_CACHE_CTRL: u8 = 33;
+const EXT_CSD_WR_REL_PARAM: usize = 166;
+const EXT_CSD_REV: usize = 192;
+const EXT_CSD_REL_WR_SEC_C: usize = 222;
+const EXT_CSD_CACHE_SIZE: usize = 249;
+
+/// `WR_REL_PARAM`: reliable writes of any size and alignment.
+const EN_REL_WR: u8 = 1 << 2;
+
+/// `SET_BLOCK_COUNT` 只有 16 位块数
+const MAX_RELIABLE_BLOCKS: usize = u16::MAX as usize;
+
+/// 与 Linux 相同，刷写缓存最多等 30 s
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct WriteCache {
+    cmd: Cmd,
+    #[cfg_attr(not(feature = "pio"), allow(dead_code))]
+    sector_addressing: bool,
+    config: CacheConfig,
+    /// Largest reliable write, in blocks.
+    reliable_blocks: usize,
+}
+
+impl WriteCache {
+    /// Turns on the cache of eMMC 4.5+ cards that have one. If that fails
+    /// the cache stays off, which is safe.
+    pub fn new(base: usize, ext_csd: &[u8; 512], sector_addressing: bool) -> Self {
+        let cmd = Cmd::new(base);
+
+        let cache_kib = u32::from_le_bytes(
+            ext_csd[EXT_CSD_CACHE_SIZE..EXT_CSD_CACHE_SIZE + 4]
+                .try_into()
+                .unwrap(),
+        );
+        let write_cache = ext_csd[EXT_CSD_REV] >= 6
+            && cache_kib > 0
+            && match cmd
+                .switch(EXT_CSD_CACHE_CTRL, 1)
+                .and_then(|()| cmd.check_status())
+            {
+                Ok(()) => {
+                    info!("eMMC: {} KiB write cache enabled", cache_kib);
+                    true
+                }
+                Err(err) => {
+                    warn!("eMMC: failed to enable the write cache: {}", err);
+                    false
+                }
+            };
+
+        // 旧式可靠写对长度和对齐有要求，单块写总是允许的
+        let rel_sectors = ext_csd[EXT_CSD_REL_WR_SEC_C] as usize;
+        let reliable_blocks = if ext_csd[EXT_CSD_WR_REL_PARAM] & EN_REL_WR != 0 {
+            MAX_RELIABLE_BLOCKS
+        } else {
+            1
+        };
+
+        WriteCache {
+            cmd,
+            sector_addressing,
+            config: CacheConfig {
+                write_cache,
+                fua: rel_sectors > 0,
+            },
+            reliable_blocks,
+        }
+    }
+
+    pub fn config(&self) -> CacheConfig {
+        self.config
+    }
+
+    #[cfg_attr(feature = "pio", allow(dead_code))]
+    pub fn reliable_blocks(&self) -> usize {
+        self.reliable_blocks
+    }
+
+    /// Writes the cache back to flash; nothing to do when it is off.
+    pub fn flush(&self) -> Result<(), HostError> {
+        if !self.config.write_cache {
+            return Ok(());
+        }
+        self.cmd
+            .switch_polled(EXT_CSD_FLUSH_CACHE, 1, FLUSH_TIMEOUT)
+    }
+
+    /// `SET_BLOCK_COUNT` with the reliable write flag, ahead of the
+    /// `WRITE_MULTIPLE_BLOCK` of `blocks` blocks.
+    pub fn start_reliable(&self, blocks: usize) -> Result<(), HostError> {
+        self.cmd.set_block_count(blocks as u16, true)
+    }
+
+    /// Reliable write of `data` from `block` by PIO, for builds without
+    /// ADMA2.
+    #[cfg(feature = "pio")]
+    pub fn write_reliable(&self, block: usize, data: &[u8]) -> Result<(), HostError> {
+        for (i, chunk) in data.chunks(self.reliable_blocks * 512).enumerate() {
+            let block = block + i * self.reliable_blocks;
+            let arg = if self.sector_addressing {
+                block as u32
+            } else {
+                (block * 512) as u32
+            };
+            self.start_reliable(chunk.len() / 512)?;
+            self.cmd.write_data(MMC_WRITE_MULTIPLE_BLOCK, arg, chunk)?;
+        }
+        Ok(())
+    }
+}
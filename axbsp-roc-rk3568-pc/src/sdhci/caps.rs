@@ -0,0 +1,204 @@
+use log::warn;
+use rdrive::fdt::Node;
+
+use super::dwcmshc::TXCLK_TAPNUM_DEFAULT;
+
+/// Card clock of the last-resort settings, see [`MmcCaps::fallback`].
+const SAFE_CLOCK: u64 = 25_000_000;
+
+/// Host capabilities from the standard mmc device-tree binding, plus the
+/// dwcmshc `rockchip,txclk-tapnum`.
+///
+/// `no-sd`, `no-sdio`, `disable-wp` and `cd-gpios` are not read: they
+/// describe removable SD/SDIO slots, while this host only ever initializes
+/// a soldered-down eMMC with MMC commands and has no write-protect switch.
+#[derive(Debug, Clone, Copy)]
+pub struct MmcCaps {
+    /// `bus-width`: 1, 4 or 8 data lines.
+    pub bus_width: u8,
+    /// `max-frequency`, caps the card clock in every mode.
+    pub max_frequency: Option<u64>,
+    pub non_removable: bool,
+    /// `cap-mmc-highspeed`: 52 MHz HS timing.
+    pub mmc_highspeed: bool,
+    /// `mmc-ddr-1_8v`: DDR52 at 1.8 V.
+    pub ddr_1v8: bool,
+    /// `mmc-hs200-1_8v`
+    pub hs200: bool,
+    /// `mmc-hs400-1_8v`
+    pub hs400: bool,
+    /// `mmc-hs400-enhanced-strobe`
+    pub hs400_es: bool,
+    /// `supports-cqe`: the controller's command queue engine may be used.
+    pub supports_cqe: bool,
+    pub txclk_tapnum: u32,
+}
+
+impl Default for MmcCaps {
+    /// What the binding assumes when no property is present.
+    fn default() -> Self {
+        MmcCaps {
+            bus_width: 1,
+            max_frequency: None,
+            non_removable: false,
+            mmc_highspeed: false,
+            ddr_1v8: false,
+            hs200: false,
+            hs400: false,
+            hs400_es: false,
+            supports_cqe: false,
+            txclk_tapnum: TXCLK_TAPNUM_DEFAULT,
+        }
+    }
+}
+
+impl MmcCaps {
+    pub fn from_node(node: &Node<'_>) -> Self {
+        Self::from_props(
+            node.name(),
+            |name| node.find_property(name).is_some(),
+            |name| node.find_property(name).map(|prop| prop.u32()),
+        )
+    }
+
+    /// Reads the caps of node `name` through `has`, which tells whether a
+    /// property is present, and `u32_prop`, which reads a one-cell one.
+    fn from_props(
+        name: &str,
+        has: impl Fn(&str) -> bool,
+        u32_prop: impl Fn(&str) -> Option<u32>,
+    ) -> Self {
+        let bus_width = match u32_prop("bus-width") {
+            None => 1,
+            Some(width @ (1 | 4 | 8)) => width as u8,
+            Some(width) => {
+                warn!("[{}] invalid bus-width {}, using 1", name, width);
+                1
+            }
+        };
+
+        MmcCaps {
+            bus_width,
+            max_frequency: u32_prop("max-frequency").map(u64::from),
+            non_removable: has("non-removable"),
+            mmc_highspeed: has("cap-mmc-highspeed"),
+            ddr_1v8: has("mmc-ddr-1_8v"),
+            hs200: has("mmc-hs200-1_8v"),
+            hs400: has("mmc-hs400-1_8v"),
+            hs400_es: has("mmc-hs400-enhanced-strobe"),
+            supports_cqe: has("supports-cqe"),
+            txclk_tapnum: u32_prop("rockchip,txclk-tapnum").unwrap_or(TXCLK_TAPNUM_DEFAULT),
+        }
+    }
+
+    /// The next, safer set of settings to retry initialization with: first
+    /// without HS200/HS400/DDR, then 1-bit legacy timing at 25 MHz. `None`
+    /// once nothing is left to give up.
+    pub fn fallback(&self) -> Option<MmcCaps> {
+        let mut caps = *self;
+        if caps.hs200 || caps.hs400 || caps.hs400_es || caps.ddr_1v8 {
+            caps.hs200 = false;
+            caps.hs400 = false;
+            caps.hs400_es = false;
+            caps.ddr_1v8 = false;
+            return Some(caps);
+        }
+
+        let clock = caps.clock(SAFE_CLOCK);
+        if caps.mmc_highspeed || caps.bus_width != 1 || caps.max_frequency != Some(clock) {
+            caps.mmc_highspeed = false;
+            caps.bus_width = 1;
+            caps.max_frequency = Some(clock);
+            return Some(caps);
+        }
+
+        None
+    }
+
+    /// `rate` limited by `max-frequency`.
+    pub fn clock(&self, rate: u64) -> u64 {
+        self.max_frequency.map_or(rate, |max| rate.min(max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Caps of a node with `props`, each a name and its value, if any.
+    fn caps(props: &[(&str, Option<u32>)]) -> MmcCaps {
+        let find = |name: &str| props.iter().find(|(prop, _)| *prop == name);
+        MmcCaps::from_props(
+            "mmc",
+            |name| find(name).is_some(),
+            |name| find(name).map(|(_, value)| value.expect("valueless property")),
+        )
+    }
+
+    /// The eMMC node of the ROC-RK3568-PC device tree.
+    const ROC_PC: &[(&str, Option<u32>)] = &[
+        ("bus-width", Some(8)),
+        ("max-frequency", Some(200_000_000)),
+        ("non-removable", None),
+        ("cap-mmc-highspeed", None),
+        ("mmc-hs200-1_8v", None),
+        ("supports-cqe", None),
+        ("rockchip,txclk-tapnum", Some(8)),
+        ("no-sd", None),
+        ("no-sdio", None),
+    ];
+
+    #[test]
+    fn empty_node_uses_binding_defaults() {
+        let caps = caps(&[]);
+        assert_eq!(caps.bus_width, 1);
+        assert_eq!(caps.max_frequency, None);
+        assert!(!caps.non_removable && !caps.mmc_highspeed && !caps.supports_cqe);
+        assert!(!caps.hs200 && !caps.hs400 && !caps.hs400_es && !caps.ddr_1v8);
+        assert_eq!(caps.txclk_tapnum, TXCLK_TAPNUM_DEFAULT);
+    }
+
+    #[test]
+    fn board_node() {
+        let caps = caps(ROC_PC);
+        assert_eq!(caps.bus_width, 8);
+        assert_eq!(caps.max_frequency, Some(200_000_000));
+        assert!(caps.non_removable && caps.mmc_highspeed && caps.hs200 && caps.supports_cqe);
+        assert!(!caps.hs400 && !caps.ddr_1v8);
+        assert_eq!(caps.txclk_tapnum, 8);
+        assert_eq!(caps.clock(52_000_000), 52_000_000);
+        assert_eq!(caps.clock(400_000_000), 200_000_000);
+    }
+
+    #[test]
+    fn invalid_bus_width_falls_back_to_one_bit() {
+        assert_eq!(caps(&[("bus-width", Some(2))]).bus_width, 1);
+        assert_eq!(caps(&[("bus-width", Some(4))]).bus_width, 4);
+    }
+
+    #[test]
+    fn fallback_drops_fast_timings_then_goes_one_bit_legacy() {
+        let first = caps(ROC_PC);
+        let second = first.fallback().unwrap();
+        assert!(!second.hs200 && !second.hs400 && !second.hs400_es && !second.ddr_1v8);
+        assert_eq!(second.bus_width, 8);
+        assert!(second.mmc_highspeed);
+
+        let third = second.fallback().unwrap();
+        assert_eq!(third.bus_width, 1);
+        assert!(!third.mmc_highspeed);
+        assert_eq!(third.max_frequency, Some(SAFE_CLOCK));
+        assert!(third.supports_cqe && third.non_removable);
+
+        assert!(third.fallback().is_none());
+    }
+
+    #[test]
+    fn fallback_keeps_a_lower_max_frequency() {
+        let slow = caps(&[("bus-width", Some(4)), ("max-frequency", Some(400_000))]);
+        let safe = slow.fallback().unwrap();
+        assert_eq!(safe.bus_width, 1);
+        assert_eq!(safe.max_frequency, Some(400_000));
+        assert!(safe.fallback().is_none());
+    }
+}
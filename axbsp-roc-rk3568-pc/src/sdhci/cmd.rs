@@ -0,0 +1,384 @@
+//! Commands issued directly through the SDHCI registers, outside of
+//! `EMmcHost`: `SWITCH`, `SEND_STATUS`, `SEND_EXT_CSD` and friends, and
+//! small PIO transfers through the buffer data port.
+
+use core::time::Duration;
+
+use axklib::time::busy_wait;
+use log::warn;
+
+use super::regs::*;
+
+/// 与 Linux 一样，eMMC 初始化时分配的 RCA 固定为 1
+pub const EMMC_RCA: u32 = 1;
+
+pub const MMC_SWITCH: u8 = 6;
+const MMC_SELECT_CARD: u8 = 7;
+pub const MMC_SEND_EXT_CSD: u8 = 8;
+const MMC_SEND_CSD: u8 = 9;
+const MMC_SEND_CID: u8 = 10;
+pub const MMC_STOP_TRANSMISSION: u8 = 12;
+pub const MMC_SEND_STATUS: u8 = 13;
+pub const MMC_READ_MULTIPLE_BLOCK: u8 = 18;
+pub const MMC_SET_BLOCK_COUNT: u8 = 23;
+pub const MMC_WRITE_MULTIPLE_BLOCK: u8 = 25;
+
+const DATA_BLOCK_SIZE: usize = 512;
+
+/// R1 card status bits that mean the previous command failed.
+pub const STATUS_ERRORS: u32 = 0xfdf9_8008;
+pub const STATUS_SWITCH_ERROR: u32 = 1 << 7;
+const STATUS_READY_FOR_DATA: u32 = 1 << 8;
+const STATUS_STATE_SHIFT: u32 = 9;
+const STATE_TRAN: u32 = 4;
+
+pub const POLL_INTERVAL: Duration = Duration::from_micros(10);
+/// 10 ms
+pub const CMD_POLLS: u32 = 1_000;
+const POLL_READY_INTERVAL: Duration = Duration::from_millis(1);
+/// 500 ms，覆盖 CMD6 和可靠写的忙等待
+const BUSY_POLLS: u32 = 50_000;
+
+#[derive(Debug)]
+pub enum HostError {
+    CmdTimeout(u8),
+    /// An ADMA2 transfer is in flight.
+    Busy,
+    /// Error interrupt status of a command.
+    Cmd {
+        cmd: u8,
+        status: u32,
+    },
+    DataTimeout(u8),
+    /// R1 status reporting an error, e.g. a rejected `SWITCH`.
+    CardStatus(u32),
+    Clock,
+    DllLock(u32),
+    Tuning,
+}
+
+impl core::fmt::Display for HostError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            HostError::CmdTimeout(cmd) => write!(f, "CMD{} timeout", cmd),
+            HostError::Busy => write!(f, "Controller busy with a transfer"),
+            HostError::Cmd { cmd, status } => {
+                write!(f, "CMD{} failed: int status {:#x}", cmd, status)
+            }
+            HostError::DataTimeout(cmd) => write!(f, "CMD{} data timeout", cmd),
+            HostError::CardStatus(status) => write!(f, "Card status error {:#x}", status),
+            HostError::Clock => write!(f, "Failed to set the card clock"),
+            HostError::DllLock(status) => write!(f, "DLL lock timeout, status {:#x}", status),
+            HostError::Tuning => write!(f, "Tuning failed"),
+        }
+    }
+}
+
+impl core::error::Error for HostError {}
+
+/// Polled command engine on the SDHCI registers at a base address. Callers
+/// serialize it against `EMmcHost` and ADMA2 transfers.
+#[derive(Debug, Clone, Copy)]
+pub struct Cmd {
+    regs: Regs,
+}
+
+impl Cmd {
+    pub fn new(base: usize) -> Self {
+        Cmd { regs: Regs(base) }
+    }
+
+    /// `SWITCH` with the write-byte access mode, waiting out the busy phase.
+    pub fn switch(&self, index: u8, value: u8) -> Result<(), HostError> {
+        let status = self.command(MMC_SWITCH, switch_arg(index, value), CMD_RESP_R1B)?;
+        if status & STATUS_ERRORS != 0 {
+            return Err(HostError::CardStatus(status));
+        }
+        Ok(())
+    }
+
+    /// `SWITCH` whose busy phase can outlast the controller's timeout, e.g.
+    /// `FLUSH_CACHE`: sent as R1, then the card status is polled.
+    pub fn switch_polled(&self, index: u8, value: u8, timeout: Duration) -> Result<(), HostError> {
+        let status = self.command(MMC_SWITCH, switch_arg(index, value), CMD_RESP_R1)?;
+        if status & STATUS_ERRORS != 0 {
+            return Err(HostError::CardStatus(status));
+        }
+        self.wait_ready(timeout)?;
+        self.check_status()
+    }
+
+    /// `SEND_STATUS`: the card status as is.
+    pub fn status(&self) -> Result<u32, HostError> {
+        self.command(MMC_SEND_STATUS, EMMC_RCA << 16, CMD_RESP_R1)
+    }
+
+    /// `SEND_STATUS`, failing if the last `SWITCH` was rejected.
+    pub fn check_status(&self) -> Result<(), HostError> {
+        let status = self.status()?;
+        if status & (STATUS_ERRORS | STATUS_SWITCH_ERROR) != 0 {
+            return Err(HostError::CardStatus(status));
+        }
+        Ok(())
+    }
+
+    /// Polls `SEND_STATUS` until the card is back in the transfer state, for
+    /// commands whose busy phase can outlast the controller's timeout.
+    pub fn wait_ready(&self, timeout: Duration) -> Result<(), HostError> {
+        let polls = timeout.as_micros() / POLL_READY_INTERVAL.as_micros() + 1;
+        for _ in 0..polls {
+            let status = self.status()?;
+            if status & STATUS_ERRORS != 0 {
+                return Err(HostError::CardStatus(status));
+            }
+            let state = (status >> STATUS_STATE_SHIFT) & 0xf;
+            if status & STATUS_READY_FOR_DATA != 0 && state == STATE_TRAN {
+                return Ok(());
+            }
+            busy_wait(POLL_READY_INTERVAL);
+        }
+        Err(HostError::DataTimeout(MMC_SEND_STATUS))
+    }
+
+    pub fn read_ext_csd(&self) -> Result<[u8; 512], HostError> {
+        let mut ext_csd = [0u8; 512];
+        self.read_data(MMC_SEND_EXT_CSD, 0, &mut ext_csd)?;
+        Ok(ext_csd)
+    }
+
+    /// Reads the CID, most significant byte first.
+    pub fn read_cid(&self) -> Result<[u8; 16], HostError> {
+        self.read_register(MMC_SEND_CID)
+    }
+
+    /// Reads the CSD, most significant byte first.
+    pub fn read_csd(&self) -> Result<[u8; 16], HostError> {
+        self.read_register(MMC_SEND_CSD)
+    }
+
+    /// `SEND_CID` and `SEND_CSD` are only accepted in standby, so the card
+    /// is deselected around them.
+    fn read_register(&self, cmd: u8) -> Result<[u8; 16], HostError> {
+        self.command(MMC_SELECT_CARD, 0, CMD_RESP_NONE)?;
+        let reg = self.command_r2(cmd, EMMC_RCA << 16);
+        // 无论寄存器是否读到，都要重新选中卡
+        self.command(MMC_SELECT_CARD, EMMC_RCA << 16, CMD_RESP_R1B)?;
+        reg
+    }
+
+    /// Sends a command with an R2 response and returns all 128 bits.
+    fn command_r2(&self, cmd: u8, arg: u32) -> Result<[u8; 16], HostError> {
+        self.issue(cmd, arg, CMD_RESP_R2)?;
+        self.wait_command(cmd)?;
+        // 控制器去掉了 CRC 字节，响应寄存器中是 R[127:8]
+        let resp = (0..4).rev().fold(0u128, |resp, i| {
+            (resp << 32) | self.regs.read32(SDHCI_RESPONSE + i * 4) as u128
+        });
+        Ok((resp << 8).to_be_bytes())
+    }
+
+    /// `SET_BLOCK_COUNT` for the next multi-block command, with the reliable
+    /// write flag if `reliable`.
+    pub fn set_block_count(&self, blocks: u16, reliable: bool) -> Result<(), HostError> {
+        let arg = blocks as u32 | if reliable { 1 << 31 } else { 0 };
+        let status = self.command(MMC_SET_BLOCK_COUNT, arg, CMD_RESP_R1)?;
+        if status & STATUS_ERRORS != 0 {
+            return Err(HostError::CardStatus(status));
+        }
+        Ok(())
+    }
+
+    /// Reads `buf`, whole 512-byte blocks, with a data command by PIO.
+    pub fn read_data(&self, cmd: u8, arg: u32, buf: &mut [u8]) -> Result<(), HostError> {
+        self.start_data(cmd, arg, buf.len() / DATA_BLOCK_SIZE, true)?;
+
+        for block in buf.chunks_exact_mut(DATA_BLOCK_SIZE) {
+            if !self.wait_int(INT_BUF_RD_READY) {
+                return Err(self.data_error(cmd));
+            }
+            self.regs.write32(SDHCI_INT_STATUS, INT_BUF_RD_READY);
+            for word in block.chunks_exact_mut(4) {
+                word.copy_from_slice(&self.regs.read32(SDHCI_BUFFER).to_le_bytes());
+            }
+        }
+
+        self.finish_data(cmd, CMD_POLLS)
+    }
+
+    /// Writes `buf`, whole 512-byte blocks, with a data command by PIO and
+    /// waits until the card has programmed them.
+    pub fn write_data(&self, cmd: u8, arg: u32, buf: &[u8]) -> Result<(), HostError> {
+        self.start_data(cmd, arg, buf.len() / DATA_BLOCK_SIZE, false)?;
+
+        for block in buf.chunks_exact(DATA_BLOCK_SIZE) {
+            if !self.wait_int(INT_BUF_WR_READY) {
+                return Err(self.data_error(cmd));
+            }
+            self.regs.write32(SDHCI_INT_STATUS, INT_BUF_WR_READY);
+            for word in block.chunks_exact(4) {
+                let word = u32::from_le_bytes(word.try_into().unwrap());
+                self.regs.write32(SDHCI_BUFFER, word);
+            }
+        }
+
+        self.finish_data(cmd, BUSY_POLLS)
+    }
+
+    fn start_data(&self, cmd: u8, arg: u32, blocks: usize, read: bool) -> Result<(), HostError> {
+        self.enable_status(INT_BUF_RD_READY | INT_BUF_WR_READY);
+        self.regs.write16(SDHCI_BLOCK_SIZE, DATA_BLOCK_SIZE as u16);
+        self.regs.write16(SDHCI_BLOCK_COUNT, blocks as u16);
+
+        let mut mode = TRNS_BLK_CNT_EN;
+        if blocks > 1 {
+            mode |= TRNS_MULTI;
+        }
+        if read {
+            mode |= TRNS_READ;
+        }
+        self.regs.write16(SDHCI_TRANSFER_MODE, mode);
+
+        self.issue(cmd, arg, CMD_RESP_R1_DATA)?;
+        let status = self.wait_command(cmd)?;
+        if status & STATUS_ERRORS != 0 {
+            self.reset(RESET_CMD | RESET_DATA);
+            return Err(HostError::CardStatus(status));
+        }
+        Ok(())
+    }
+
+    /// Waits for transfer complete, which for writes includes the busy
+    /// phase.
+    fn finish_data(&self, cmd: u8, polls: u32) -> Result<(), HostError> {
+        if !self.wait_status(INT_XFER_COMPLETE, polls) {
+            return Err(self.data_error(cmd));
+        }
+        self.regs.write32(SDHCI_INT_STATUS, INT_XFER_COMPLETE);
+        Ok(())
+    }
+
+    /// Clears a failed transfer and tells what went wrong.
+    fn data_error(&self, cmd: u8) -> HostError {
+        let status = self.regs.read32(SDHCI_INT_STATUS);
+        self.regs.write32(SDHCI_INT_STATUS, status);
+        self.reset(RESET_CMD | RESET_DATA);
+        if status & INT_ERROR == 0 || status & INT_DATA_TIMEOUT != 0 {
+            HostError::DataTimeout(cmd)
+        } else {
+            HostError::Cmd { cmd, status }
+        }
+    }
+
+    /// Sends a command without data and returns its R1 response.
+    pub fn command(&self, cmd: u8, arg: u32, flags: u16) -> Result<u32, HostError> {
+        self.issue(cmd, arg, flags)?;
+        let resp = self.wait_command(cmd)?;
+
+        if flags == CMD_RESP_R1B {
+            for _ in 0..BUSY_POLLS {
+                let status = self.regs.read32(SDHCI_INT_STATUS);
+                if status & INT_XFER_COMPLETE != 0 {
+                    self.regs.write32(SDHCI_INT_STATUS, INT_XFER_COMPLETE);
+                    return Ok(resp);
+                }
+                if status & INT_ERROR != 0 {
+                    self.regs.write32(SDHCI_INT_STATUS, status);
+                    self.reset(RESET_CMD | RESET_DATA);
+                    return Err(HostError::Cmd { cmd, status });
+                }
+                busy_wait(POLL_INTERVAL);
+            }
+            return Err(HostError::DataTimeout(cmd));
+        }
+        Ok(resp)
+    }
+
+    pub fn issue(&self, cmd: u8, arg: u32, flags: u16) -> Result<(), HostError> {
+        let mut inhibit = PRESENT_CMD_INHIBIT;
+        if flags != CMD_RESP_R1 {
+            inhibit |= PRESENT_DAT_INHIBIT;
+        }
+        let idle = (0..CMD_POLLS).any(|_| {
+            let idle = self.regs.read32(SDHCI_PRESENT_STATE) & inhibit == 0;
+            if !idle {
+                busy_wait(POLL_INTERVAL);
+            }
+            idle
+        });
+        if !idle {
+            return Err(HostError::CmdTimeout(cmd));
+        }
+
+        self.enable_status(INT_CMD_COMPLETE | INT_XFER_COMPLETE | INT_ERROR | INT_CMD_ERRORS);
+        self.regs.write32(SDHCI_INT_STATUS, !0);
+        if flags != CMD_RESP_R1_DATA {
+            self.regs.write16(SDHCI_TRANSFER_MODE, 0);
+        }
+        self.regs.write32(SDHCI_ARGUMENT, arg);
+        self.regs
+            .write16(SDHCI_COMMAND, ((cmd as u16) << 8) | flags);
+        Ok(())
+    }
+
+    /// Waits for command complete and returns the first response word.
+    pub fn wait_command(&self, cmd: u8) -> Result<u32, HostError> {
+        for _ in 0..CMD_POLLS {
+            let status = self.regs.read32(SDHCI_INT_STATUS);
+            if status & INT_ERROR != 0 {
+                self.regs.write32(SDHCI_INT_STATUS, status);
+                self.reset(RESET_CMD | RESET_DATA);
+                return Err(if status & INT_CMD_TIMEOUT != 0 {
+                    HostError::CmdTimeout(cmd)
+                } else {
+                    HostError::Cmd { cmd, status }
+                });
+            }
+            if status & INT_CMD_COMPLETE != 0 {
+                self.regs.write32(SDHCI_INT_STATUS, INT_CMD_COMPLETE);
+                return Ok(self.regs.read32(SDHCI_RESPONSE));
+            }
+            busy_wait(POLL_INTERVAL);
+        }
+        Err(HostError::CmdTimeout(cmd))
+    }
+
+    /// Waits for any of `mask`; false on an error interrupt or timeout.
+    pub fn wait_int(&self, mask: u32) -> bool {
+        self.wait_status(mask, CMD_POLLS)
+    }
+
+    fn wait_status(&self, mask: u32, polls: u32) -> bool {
+        for _ in 0..polls {
+            let status = self.regs.read32(SDHCI_INT_STATUS);
+            if status & mask != 0 {
+                return true;
+            }
+            if status & INT_ERROR != 0 {
+                return false;
+            }
+            busy_wait(POLL_INTERVAL);
+        }
+        false
+    }
+
+    pub fn enable_status(&self, mask: u32) {
+        let enabled = self.regs.read32(SDHCI_INT_ENABLE);
+        self.regs.write32(SDHCI_INT_ENABLE, enabled | mask);
+    }
+
+    pub fn reset(&self, mask: u8) {
+        self.regs.write8(SDHCI_SOFTWARE_RESET, mask);
+        for _ in 0..CMD_POLLS {
+            if self.regs.read8(SDHCI_SOFTWARE_RESET) & mask == 0 {
+                return;
+            }
+            busy_wait(POLL_INTERVAL);
+        }
+        warn!("SDHCI reset {:#x} did not complete", mask);
+    }
+}
+
+/// `SWITCH` argument writing `value` to EXT_CSD byte `index`.
+fn switch_arg(index: u8, value: u8) -> u32 {
+    (0b11 << 24) | ((index as u32) << 16) | ((value as u32) << 8)
+}
@@ -0,0 +1,423 @@
+//! eMMC 5.1 command queueing through the CQHCI engine of the dwcmshc.
+//!
+//! Every block queue owns the task slot equal to its ID, and its ADMA2
+//! descriptor table is the slot's transfer descriptor list. Tasks of
+//! different queues are in flight together. The engine is halted whenever
+//! no task is, so erases, flushes, partition switches and RPMB frames keep
+//! going through the SDHCI registers.
+
+extern crate alloc;
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::time::Duration;
+
+use alloc::sync::Arc;
+use axklib::time::busy_wait;
+use dma_api::{DVec, Direction};
+use log::{info, warn};
+use spin::Mutex;
+
+use super::adma::{Completion, DMA_MASK};
+use super::cmd::{Cmd, EMMC_RCA, HostError};
+use super::regs::*;
+
+/// Offset of the CQHCI registers, from the SDHCI base.
+const DWCMSHC_P_VENDOR_AREA2: usize = 0xea;
+
+const CQHCI_VER: usize = 0x00;
+const CQHCI_CFG: usize = 0x08;
+const CQHCI_CTL: usize = 0x0c;
+const CQHCI_IS: usize = 0x10;
+const CQHCI_ISTE: usize = 0x14;
+const CQHCI_ISGE: usize = 0x18;
+const CQHCI_TDLBA: usize = 0x20;
+const CQHCI_TDLBAU: usize = 0x24;
+const CQHCI_TDBR: usize = 0x28;
+const CQHCI_TCN: usize = 0x2c;
+const CQHCI_SSC2: usize = 0x44;
+const CQHCI_TERRI: usize = 0x54;
+
+const CFG_ENABLE: u32 = 1 << 0;
+const CTL_HALT: u32 = 1 << 0;
+const CTL_CLEAR_ALL_TASKS: u32 = 1 << 8;
+
+/// Halt complete.
+const IS_HAC: u32 = 1 << 0;
+/// Task complete.
+const IS_TCC: u32 = 1 << 1;
+/// Response error detected.
+const IS_RED: u32 = 1 << 2;
+const IS_MASK: u32 = IS_HAC | IS_TCC | IS_RED;
+
+/// `TERRI`: slot of a failed command and of a failed data transfer.
+const TERRI_CMD_VALID: u32 = 1 << 15;
+const TERRI_DATA_VALID: u32 = 1 << 31;
+
+const TD_VALID: u64 = 1 << 0;
+const TD_END: u64 = 1 << 1;
+const TD_INT: u64 = 1 << 2;
+const TD_ACT_TASK: u64 = 0b101 << 3;
+const TD_ACT_LINK: u64 = 0b110 << 3;
+const TD_DATA_DIR_READ: u64 = 1 << 12;
+const TD_REL_WRITE: u64 = 1 << 15;
+
+/// SDHCI 中断中属于命令队列的部分，数据错误由 SDHCI 报告
+const INT_ENABLED: u32 = INT_CQE | INT_CMD_ERRORS | INT_DATA_ERRORS | INT_ADMA_ERROR;
+
+const EXT_CSD_CMDQ_MODE_EN: u8 = 15;
+const EXT_CSD_REV: usize = 192;
+const EXT_CSD_CMDQ_DEPTH: usize = 307;
+const EXT_CSD_CMDQ_SUPPORT: usize = 308;
+
+const MMC_STOP_TRANSMISSION: u8 = 12;
+const MMC_CMDQ_TASK_MGMT: u8 = 48;
+/// `CMDQ_TASK_MGMT` 参数：丢弃整个队列
+const TASK_MGMT_DISCARD_QUEUE: u32 = 1;
+
+pub const SLOTS: usize = 32;
+
+const POLL_INTERVAL: Duration = Duration::from_micros(10);
+/// 100 ms
+const HALT_POLLS: u32 = 10_000;
+
+#[derive(Debug)]
+pub enum CqeError {
+    /// The task descriptor list could not be allocated below [`DMA_MASK`].
+    NoMemory,
+    /// The card rejected `CMDQ_MODE_EN`.
+    Enable(HostError),
+    /// The task failed; the engine has discarded the queue and recovered.
+    Task,
+}
+
+impl core::fmt::Display for CqeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CqeError::NoMemory => write!(f, "CQE task descriptor list allocation failed"),
+            CqeError::Enable(err) => write!(f, "Failed to enable command queueing: {}", err),
+            CqeError::Task => write!(f, "Command queue task failed"),
+        }
+    }
+}
+
+impl core::error::Error for CqeError {}
+
+/// The command queue engine, shared by the queues of one eMMC.
+///
+/// Tasks are started with the host locked, like every other command: no
+/// partition switch, erase or flush may come between the check that no task
+/// of another partition is in flight and the doorbell. Issuing a task thus
+/// waits for whatever command holds the host, a whole erase included, and
+/// queues issue their tasks one at a time. Only completions are collected
+/// without the lock.
+pub struct Cqe {
+    regs: Regs,
+    sdhci: Regs,
+    cmd: Cmd,
+    /// Task descriptor list: a task and a link descriptor per slot. The lock
+    /// also orders doorbells against halts and recovery.
+    list: Mutex<DVec<u64>>,
+    depth: usize,
+    completion: Arc<Completion>,
+    /// Slots with a task issued and not yet collected.
+    active: AtomicU32,
+    /// Slots whose task has ended, not yet collected.
+    done: AtomicU32,
+    /// Slots whose task has ended with an error.
+    failed: AtomicU32,
+    /// 出错后需要在下一次访问前恢复引擎
+    error: AtomicBool,
+    /// `SDHCI_INT_ENABLE` of the SDHCI path, while the engine runs.
+    int_enable: AtomicU32,
+}
+
+impl Cqe {
+    /// Turns on command queueing on eMMC 5.1 cards that support it and
+    /// leaves the engine enabled but halted. `None` if the card has no
+    /// command queue.
+    pub fn new(
+        base: usize,
+        ext_csd: &[u8; 512],
+        completion: Arc<Completion>,
+    ) -> Result<Option<Self>, CqeError> {
+        if ext_csd[EXT_CSD_REV] < 8 || ext_csd[EXT_CSD_CMDQ_SUPPORT] & 1 == 0 {
+            return Ok(None);
+        }
+        let depth = ((ext_csd[EXT_CSD_CMDQ_DEPTH] & 0x1f) as usize + 1).min(SLOTS);
+
+        let sdhci = Regs(base);
+        let regs = Regs(base + (sdhci.read16(DWCMSHC_P_VENDOR_AREA2) & 0xfff) as usize);
+        let list = DVec::zeros(DMA_MASK, SLOTS * 2, 0x1000, Direction::ToDevice)
+            .map_err(|_| CqeError::NoMemory)?;
+
+        let cmd = Cmd::new(base);
+        set_cmdq_mode(&cmd, true).map_err(CqeError::Enable)?;
+
+        // 64 位任务描述符，不使用 DCMD 槽位
+        regs.write32(CQHCI_CFG, 0);
+        let table = list.bus_addr();
+        regs.write32(CQHCI_TDLBA, table as u32);
+        regs.write32(CQHCI_TDLBAU, (table >> 32) as u32);
+        regs.write32(CQHCI_SSC2, EMMC_RCA);
+        regs.write32(CQHCI_ISGE, 0);
+        regs.write32(CQHCI_ISTE, IS_MASK);
+        regs.write32(CQHCI_CFG, CFG_ENABLE);
+
+        let cqe = Cqe {
+            regs,
+            sdhci,
+            cmd,
+            list: Mutex::new(list),
+            depth,
+            completion,
+            active: AtomicU32::new(0),
+            done: AtomicU32::new(0),
+            failed: AtomicU32::new(0),
+            error: AtomicBool::new(false),
+            int_enable: AtomicU32::new(0),
+        };
+        cqe.halt();
+        info!(
+            "eMMC: command queue enabled, CQHCI {:#x}, {} slots",
+            regs.read32(CQHCI_VER),
+            depth
+        );
+        Ok(Some(cqe))
+    }
+
+    /// Number of task slots the card queues.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Issues a task in `slot` moving `blocks` blocks at card address `arg`
+    /// through the transfer descriptors at `table`. The host must be locked.
+    pub fn start(
+        &self,
+        slot: usize,
+        table: u64,
+        arg: u32,
+        blocks: u16,
+        read: bool,
+        reliable: bool,
+    ) {
+        let mut list = self.list.lock();
+        if self.error.load(Ordering::Acquire) {
+            self.recover();
+        }
+        if !self.completion.is_cqe_running() {
+            self.resume();
+        }
+
+        let mut task = TD_VALID | TD_END | TD_INT | TD_ACT_TASK | (blocks as u64) << 16;
+        if read {
+            task |= TD_DATA_DIR_READ;
+        }
+        if reliable {
+            task |= TD_REL_WRITE;
+        }
+        list.set(slot * 2, task | (arg as u64) << 32);
+        list.set(slot * 2 + 1, TD_VALID | TD_ACT_LINK | table << 32);
+
+        self.active.fetch_or(1 << slot, Ordering::AcqRel);
+        self.regs.write32(CQHCI_TDBR, 1 << slot);
+    }
+
+    /// Checks the task in `slot`: `None` while it runs, its outcome once it
+    /// has ended. Halts the engine once no task is left.
+    pub fn poll(&self, slot: usize) -> Option<Result<(), CqeError>> {
+        let _list = self.list.lock();
+        if !self.completion.is_irq_enabled() {
+            self.collect();
+        }
+        if self.error.load(Ordering::Acquire) {
+            self.recover();
+        }
+
+        let bit = 1 << slot;
+        if self.done.fetch_and(!bit, Ordering::AcqRel) & bit == 0 {
+            return None;
+        }
+        let failed = self.failed.fetch_and(!bit, Ordering::AcqRel) & bit != 0;
+        if self.active.fetch_and(!bit, Ordering::AcqRel) == bit {
+            self.halt();
+        }
+        Some(if failed { Err(CqeError::Task) } else { Ok(()) })
+    }
+
+    /// Interrupt handler body. Returns the slots whose task has ended.
+    pub fn handle_irq(&self) -> u32 {
+        if !self.completion.is_cqe_running() {
+            return 0;
+        }
+        self.collect()
+    }
+
+    /// Waits for the tasks in flight to end and halts the engine, so that
+    /// the SDHCI can run other commands; [`Cqe::poll`] still reports each
+    /// task's outcome. After an error, or if tasks still run after
+    /// [`HALT_POLLS`] polls, discards them instead, failing those not yet
+    /// ended. The host must be locked.
+    pub fn drain(&self) {
+        let _list = self.list.lock();
+        if !self.completion.is_cqe_running() {
+            return;
+        }
+        let drained = (0..HALT_POLLS).any(|_| {
+            if !self.completion.is_irq_enabled() {
+                self.collect();
+            }
+            let running = self.active.load(Ordering::Acquire) & !self.done.load(Ordering::Acquire);
+            if running != 0 {
+                busy_wait(POLL_INTERVAL);
+            }
+            running == 0
+        });
+        if self.error.load(Ordering::Acquire) || !drained {
+            self.recover();
+        } else {
+            self.halt();
+        }
+    }
+
+    /// Runs `f` with command queueing turned off in the card, which the
+    /// RPMB partition requires. The engine must be halted.
+    pub fn without_cmdq<R>(
+        &self,
+        f: impl FnOnce() -> Result<R, HostError>,
+    ) -> Result<R, HostError> {
+        set_cmdq_mode(&self.cmd, false)?;
+        let result = f();
+        set_cmdq_mode(&self.cmd, true)?;
+        result
+    }
+
+    /// Acknowledges the engine and records ended tasks. On an error every
+    /// task in flight ends: the failed ones, and the others once
+    /// [`Cqe::recover`] has discarded them.
+    fn collect(&self) -> u32 {
+        let status = self.sdhci.read32(SDHCI_INT_STATUS) & (INT_ENABLED | INT_ERROR);
+        if status == 0 {
+            return 0;
+        }
+        self.sdhci.write32(SDHCI_INT_STATUS, status);
+        let is = self.regs.read32(CQHCI_IS);
+        self.regs.write32(CQHCI_IS, is);
+        let tcn = self.regs.read32(CQHCI_TCN);
+        self.regs.write32(CQHCI_TCN, tcn);
+
+        let mut ended = tcn;
+        if is & IS_RED != 0 || status & INT_ERROR != 0 {
+            let active = self.active.load(Ordering::Acquire);
+            let terri = self.regs.read32(CQHCI_TERRI);
+            let mut failed = active & !tcn;
+            if terri & TERRI_CMD_VALID != 0 {
+                failed |= 1 << ((terri >> 8) & 0x1f);
+            }
+            if terri & TERRI_DATA_VALID != 0 {
+                failed |= 1 << ((terri >> 24) & 0x1f);
+            }
+            warn!(
+                "eMMC: command queue error: int status {:#x}, CQHCI IS {:#x}, TERRI {:#x}",
+                status, is, terri
+            );
+            self.failed.fetch_or(failed & active, Ordering::AcqRel);
+            self.error.store(true, Ordering::Release);
+            ended = active;
+        }
+        self.done.fetch_or(ended, Ordering::AcqRel);
+        ended
+    }
+
+    /// Sets up the SDHCI for the engine and lets it run. The interrupts the
+    /// SDHCI path had enabled are put back by [`Cqe::release`].
+    fn resume(&self) {
+        let host_ctrl = self.sdhci.read8(SDHCI_HOST_CONTROL) & !HOST_CTRL_DMA_MASK;
+        self.sdhci
+            .write8(SDHCI_HOST_CONTROL, host_ctrl | HOST_CTRL_ADMA32);
+        self.sdhci.write16(SDHCI_BLOCK_SIZE, 512);
+        self.sdhci
+            .write16(SDHCI_TRANSFER_MODE, TRNS_MULTI | TRNS_BLK_CNT_EN | TRNS_DMA);
+        self.int_enable
+            .store(self.sdhci.read32(SDHCI_INT_ENABLE), Ordering::Release);
+        self.sdhci.write32(SDHCI_INT_ENABLE, INT_ENABLED);
+        self.sdhci.write32(SDHCI_INT_STATUS, !0);
+        if self.completion.is_irq_enabled() {
+            self.sdhci.write32(SDHCI_SIGNAL_ENABLE, INT_ENABLED);
+            self.regs.write32(CQHCI_ISGE, IS_TCC | IS_RED);
+        }
+
+        self.completion.set_cqe_running(true);
+        self.regs.write32(CQHCI_CTL, 0);
+    }
+
+    /// Stops the engine so the SDHCI can issue other commands.
+    fn halt(&self) {
+        self.stop();
+        self.release();
+    }
+
+    /// Hands the halted engine's SDHCI back with the interrupts it had
+    /// before [`Cqe::resume`].
+    fn release(&self) {
+        if self.completion.is_cqe_running() {
+            let enabled = self.int_enable.load(Ordering::Acquire);
+            self.sdhci.write32(SDHCI_INT_ENABLE, enabled);
+            self.completion.set_cqe_running(false);
+        }
+    }
+
+    fn stop(&self) {
+        self.regs.write32(CQHCI_ISGE, 0);
+        self.sdhci.write32(SDHCI_SIGNAL_ENABLE, 0);
+        self.regs.write32(CQHCI_CTL, CTL_HALT);
+        if !self.wait_ctl(CTL_HALT, CTL_HALT) {
+            warn!("eMMC: command queue halt did not complete");
+        }
+        self.regs.write32(CQHCI_IS, IS_HAC);
+    }
+
+    /// Discards every task in the engine and the card, failing those not yet
+    /// ended, and leaves the engine halted. Other commands keep waiting until
+    /// it is done, as the host is not locked here.
+    fn recover(&self) {
+        self.stop();
+        self.regs.write32(CQHCI_CTL, CTL_HALT | CTL_CLEAR_ALL_TASKS);
+        if !self.wait_ctl(CTL_CLEAR_ALL_TASKS, 0) {
+            warn!("eMMC: clearing the command queue did not complete");
+        }
+        self.cmd.reset(RESET_CMD | RESET_DATA);
+
+        // 与 Linux 相同：先停止可能进行中的传输，再让卡丢弃队列
+        for (cmd, arg) in [
+            (MMC_STOP_TRANSMISSION, EMMC_RCA << 16),
+            (MMC_CMDQ_TASK_MGMT, TASK_MGMT_DISCARD_QUEUE),
+        ] {
+            if let Err(err) = self.cmd.command(cmd, arg, CMD_RESP_R1B) {
+                warn!("eMMC: CMD{} during command queue recovery: {}", cmd, err);
+            }
+        }
+
+        let active = self.active.load(Ordering::Acquire);
+        let done = self.done.load(Ordering::Acquire);
+        self.failed.fetch_or(active & !done, Ordering::AcqRel);
+        self.done.fetch_or(active, Ordering::AcqRel);
+        self.error.store(false, Ordering::Release);
+        self.release();
+    }
+
+    fn wait_ctl(&self, mask: u32, value: u32) -> bool {
+        (0..HALT_POLLS).any(|_| {
+            let reached = self.regs.read32(CQHCI_CTL) & mask == value;
+            if !reached {
+                busy_wait(POLL_INTERVAL);
+            }
+            reached
+        })
+    }
+}
+
+fn set_cmdq_mode(cmd: &Cmd, enable: bool) -> Result<(), HostError> {
+    cmd.switch(EXT_CSD_CMDQ_MODE_EN, enable as u8)?;
+    cmd.check_status()
+}
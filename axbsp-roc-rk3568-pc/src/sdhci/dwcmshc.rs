@@ -0,0 +1,536 @@
+//! Bus speed modes of the Rockchip dwcmshc: HS200, HS400 and HS400 with
+//! enhanced strobe, including the vendor DLL and CMD21 tuning.
+//!
+//! `EMmcHost` brings the card up at legacy speed; [`Dwcmshc::select_timing`]
+//! then switches the card and the host to the fastest mode both allow, and
+//! [`RecoveryHost::set_bus_mode`] steps them down again after bus errors.
+
+use core::time::Duration;
+
+use axbsp_block::{BusMode, BusSpeed, RecoveryHost};
+use axklib::time::busy_wait;
+use log::{debug, info, warn};
+
+use super::caps::MmcCaps;
+use super::cmd::{CMD_POLLS, Cmd, HostError, MMC_STOP_TRANSMISSION, POLL_INTERVAL};
+use super::regs::*;
+use crate::clk::dt::ClkRef;
+
+const MMC_SEND_TUNING_BLOCK_HS200: u8 = 21;
+
+const EXT_CSD_BUS_WIDTH: u8 = 183;
+const EXT_CSD_STROBE_SUPPORT: usize = 184;
+const EXT_CSD_HS_TIMING: u8 = 185;
+const EXT_CSD_DEVICE_TYPE: usize = 196;
+
+const BUS_WIDTH_STROBE: u8 = 1 << 7;
+
+const HS_TIMING_HS: u8 = 1;
+const HS_TIMING_HS200: u8 = 2;
+const HS_TIMING_HS400: u8 = 3;
+
+const DEVICE_TYPE_HS_52: u8 = 1 << 1;
+const DEVICE_TYPE_DDR_1_8V: u8 = 1 << 2;
+const DEVICE_TYPE_HS200_1_8V: u8 = 1 << 4;
+const DEVICE_TYPE_HS400_1_8V: u8 = 1 << 6;
+
+/// 厂商寄存器区 1 的偏移保存在这个寄存器的低 12 位
+const DWCMSHC_P_VENDOR_AREA1: usize = 0xe8;
+const VENDOR_HOST_CTRL3: usize = 0x08;
+const VENDOR_EMMC_CTRL: usize = 0x2c;
+const VENDOR_AT_CTRL: usize = 0x40;
+
+const EMMC_CTRL_CARD_IS_EMMC: u16 = 1 << 0;
+const EMMC_CTRL_ENHANCED_STROBE: u16 = 1 << 8;
+const HOST_CTRL3_CMD_CONFLICT_CHECK: u32 = 1 << 0;
+
+const DLL_CTRL: usize = 0x800;
+const DLL_RXCLK: usize = 0x804;
+const DLL_TXCLK: usize = 0x808;
+const DLL_STRBIN: usize = 0x80c;
+const DLL_CMDOUT: usize = 0x810;
+const DLL_STATUS0: usize = 0x840;
+
+const DLL_CTRL_START: u32 = 1 << 0;
+const DLL_CTRL_SRST: u32 = 1 << 1;
+const DLL_CTRL_INC_SHIFT: u32 = 8;
+const DLL_CTRL_START_POINT_SHIFT: u32 = 16;
+const DLL_CTRL_BYPASS: u32 = 1 << 24;
+const DLL_DLYENA: u32 = 1 << 27;
+const DLL_RXCLK_SRCSEL_SHIFT: u32 = 29;
+const DLL_RXCLK_NO_INVERTER: u32 = 1;
+const DLL_RXCLK_ORI_GATE: u32 = 1 << 31;
+const DLL_TXCLK_TAPNUM_FROM_SW: u32 = 1 << 24;
+const DLL_STRBIN_TAPNUM_DEFAULT: u32 = 0x8;
+const DLL_STRBIN_TAPNUM_FROM_SW: u32 = 1 << 24;
+const DLL_STRBIN_DELAY_NUM_SHIFT: u32 = 16;
+const DLL_STRBIN_DELAY_NUM_DEFAULT: u32 = 0x16;
+const DLL_STRBIN_DELAY_NUM_SEL: u32 = 1 << 26;
+const DLL_STATUS_LOCKED: u32 = 1 << 8;
+const DLL_STATUS_TIMEOUT: u32 = 1 << 9;
+
+/// Default `rockchip,txclk-tapnum`.
+pub const TXCLK_TAPNUM_DEFAULT: u32 = 0x10;
+
+/// 52 MHz 及以下 DLL 旁路
+const DLL_MIN_CLOCK: u64 = 52_000_000;
+const LEGACY_CLOCK: u64 = 26_000_000;
+const HS_CLOCK: u64 = 52_000_000;
+const HS200_CLOCK: u64 = 200_000_000;
+
+/// CMD21 调谐块大小：8 位总线 128 字节，4 位总线 64 字节
+const TUNING_BLOCK_SIZE_8BIT: u16 = 128;
+const TUNING_BLOCK_SIZE_4BIT: u16 = 64;
+const MAX_TUNING_LOOPS: usize = 40;
+
+const DLL_LOCK_POLLS: u32 = 50_000;
+
+/// Bus timing of the card and the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    Legacy,
+    Hs,
+    Ddr52,
+    Hs200,
+    Hs400,
+    Hs400Es,
+}
+
+impl Timing {
+    pub fn bus_speed(self) -> BusSpeed {
+        match self {
+            Timing::Legacy => BusSpeed::Legacy,
+            Timing::Hs => BusSpeed::Hs,
+            Timing::Ddr52 => BusSpeed::Ddr52,
+            Timing::Hs200 => BusSpeed::Hs200,
+            Timing::Hs400 | Timing::Hs400Es => BusSpeed::Hs400,
+        }
+    }
+}
+
+/// Speed mode control of the RK3568 dwcmshc.
+#[derive(Clone)]
+pub struct Dwcmshc {
+    regs: Regs,
+    cmd: Cmd,
+    /// Offset of the vendor register area 1.
+    vendor: usize,
+    core_clk: ClkRef,
+    caps: MmcCaps,
+}
+
+impl Dwcmshc {
+    pub fn new(base: usize, core_clk: ClkRef, caps: MmcCaps) -> Self {
+        let regs = Regs(base);
+        let vendor = (regs.read32(DWCMSHC_P_VENDOR_AREA1) & 0xfff) as usize;
+        Dwcmshc {
+            regs,
+            cmd: Cmd::new(base),
+            vendor,
+            core_clk,
+            caps,
+        }
+    }
+
+    /// Switches an initialized card in transfer state to the fastest timing
+    /// supported by the card and allowed by [`MmcCaps`], at the bus width
+    /// the device tree gives.
+    pub fn select_timing(&self) -> Result<Timing, HostError> {
+        let ext_csd = self.cmd.read_ext_csd()?;
+        let card_type = ext_csd[EXT_CSD_DEVICE_TYPE];
+        let strobe = ext_csd[EXT_CSD_STROBE_SUPPORT] != 0;
+        debug!("eMMC device type {:#x}, strobe {}", card_type, strobe);
+
+        let caps = &self.caps;
+        let width = caps.bus_width;
+        let hs = caps.mmc_highspeed && card_type & DEVICE_TYPE_HS_52 != 0;
+        let ddr52 = hs && caps.ddr_1v8 && width >= 4 && card_type & DEVICE_TYPE_DDR_1_8V != 0;
+        let hs200 = caps.hs200 && width >= 4 && card_type & DEVICE_TYPE_HS200_1_8V != 0;
+        // HS400 只支持 8 位总线，且需要先在 HS200 下调谐
+        let hs400 = caps.hs400 && width == 8 && card_type & DEVICE_TYPE_HS400_1_8V != 0;
+
+        let timing = if hs400 && caps.hs400_es && strobe {
+            self.select_hs400es()?
+        } else if hs400 && hs200 {
+            self.select_hs200()?;
+            self.select_hs400()?
+        } else if hs200 {
+            self.select_hs200()?
+        } else if ddr52 {
+            self.select_hs()?;
+            self.select_ddr52()?
+        } else if hs {
+            self.select_hs()?
+        } else {
+            self.set_bus_width(width, false, false)?;
+            self.set_timing(Timing::Legacy, LEGACY_CLOCK)?;
+            Timing::Legacy
+        };
+
+        info!("eMMC timing {:?}, {}-bit bus", timing, width);
+        Ok(timing)
+    }
+
+    fn select_hs(&self) -> Result<Timing, HostError> {
+        self.set_bus_width(self.caps.bus_width, false, false)?;
+        self.cmd.switch(EXT_CSD_HS_TIMING, HS_TIMING_HS)?;
+        self.set_timing(Timing::Hs, HS_CLOCK)?;
+        self.cmd.check_status()?;
+        Ok(Timing::Hs)
+    }
+
+    /// HS → DDR52：只需把总线切换为 DDR
+    fn select_ddr52(&self) -> Result<Timing, HostError> {
+        self.set_bus_width(self.caps.bus_width, true, false)?;
+        self.enable_1v8_signalling();
+        self.set_timing(Timing::Ddr52, HS_CLOCK)?;
+        self.cmd.check_status()?;
+        Ok(Timing::Ddr52)
+    }
+
+    fn select_hs200(&self) -> Result<Timing, HostError> {
+        self.enable_1v8_signalling();
+        self.set_bus_width(self.caps.bus_width, false, false)?;
+        self.cmd.switch(EXT_CSD_HS_TIMING, HS_TIMING_HS200)?;
+        self.set_timing(Timing::Hs200, HS200_CLOCK)?;
+        self.cmd.check_status()?;
+        self.execute_tuning()?;
+        Ok(Timing::Hs200)
+    }
+
+    /// HS200 → HS400，先在 HS200 下完成调谐，再经 HS 切换到 DDR
+    fn select_hs400(&self) -> Result<Timing, HostError> {
+        self.cmd.switch(EXT_CSD_HS_TIMING, HS_TIMING_HS)?;
+        self.set_timing(Timing::Hs, HS_CLOCK)?;
+        self.cmd.check_status()?;
+
+        self.set_bus_width(8, true, false)?;
+        self.cmd.switch(EXT_CSD_HS_TIMING, HS_TIMING_HS400)?;
+        self.set_timing(Timing::Hs400, HS200_CLOCK)?;
+        self.cmd.check_status()?;
+        Ok(Timing::Hs400)
+    }
+
+    /// HS400 with enhanced strobe: the data strobe also clocks responses,
+    /// so no tuning is needed.
+    fn select_hs400es(&self) -> Result<Timing, HostError> {
+        self.enable_1v8_signalling();
+        self.select_hs()?;
+
+        self.set_bus_width(8, true, true)?;
+        self.set_enhanced_strobe(true);
+        self.cmd.switch(EXT_CSD_HS_TIMING, HS_TIMING_HS400)?;
+        self.set_timing(Timing::Hs400Es, HS200_CLOCK)?;
+        self.cmd.check_status()?;
+        Ok(Timing::Hs400Es)
+    }
+
+    /// Switches the card's `BUS_WIDTH` and the host's data width together.
+    fn set_bus_width(&self, width: u8, ddr: bool, strobe: bool) -> Result<(), HostError> {
+        let value = match (width, ddr) {
+            (8, false) => 2,
+            (8, true) => 6,
+            (4, false) => 1,
+            (4, true) => 5,
+            _ => 0,
+        };
+        let value = if strobe {
+            value | BUS_WIDTH_STROBE
+        } else {
+            value
+        };
+        self.cmd.switch(EXT_CSD_BUS_WIDTH, value)?;
+
+        let ctrl = self.regs.read8(SDHCI_HOST_CONTROL) & !(HOST_CTRL_4BIT | HOST_CTRL_8BIT);
+        let ctrl = match width {
+            8 => ctrl | HOST_CTRL_8BIT,
+            4 => ctrl | HOST_CTRL_4BIT,
+            _ => ctrl,
+        };
+        self.regs.write8(SDHCI_HOST_CONTROL, ctrl);
+        Ok(())
+    }
+
+    /// Steps the card and the host down to `mode`: back to SDR high speed
+    /// at 52 MHz at most, leaving HS200, HS400 and DDR, then on to the
+    /// target width and timing.
+    fn set_mode(&self, mode: BusMode) -> Result<(), HostError> {
+        let (timing, clock) = match mode.speed {
+            BusSpeed::Legacy => (Timing::Legacy, LEGACY_CLOCK),
+            // eMMC 没有 SDR25，用 HS 时序跑在传统时钟上
+            BusSpeed::Sdr25 => (Timing::Hs, LEGACY_CLOCK),
+            BusSpeed::Hs => (Timing::Hs, HS_CLOCK),
+            BusSpeed::Hs200 => (Timing::Hs200, HS200_CLOCK),
+            BusSpeed::Ddr52 | BusSpeed::Hs400 => unreachable!("{:?} is never a fallback", mode),
+        };
+
+        self.set_enhanced_strobe(false);
+        self.set_timing(Timing::Hs, clock.min(HS_CLOCK))?;
+        self.cmd.switch(EXT_CSD_HS_TIMING, HS_TIMING_HS)?;
+        self.set_bus_width(mode.width, false, false)?;
+        match timing {
+            Timing::Legacy => {
+                self.cmd.switch(EXT_CSD_HS_TIMING, 0)?;
+                self.set_timing(Timing::Legacy, clock)?;
+            }
+            Timing::Hs200 => {
+                self.cmd.switch(EXT_CSD_HS_TIMING, HS_TIMING_HS200)?;
+                self.set_timing(Timing::Hs200, clock)?;
+            }
+            _ => {}
+        }
+        self.cmd.check_status()?;
+        if timing == Timing::Hs200 {
+            self.execute_tuning()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the host to legacy timing after a failed switch, so the card
+    /// can be initialized again from scratch.
+    pub fn reset_timing(&self) {
+        self.set_enhanced_strobe(false);
+        let ctrl2 = self.regs.read16(SDHCI_HOST_CONTROL2);
+        self.regs.write16(
+            SDHCI_HOST_CONTROL2,
+            ctrl2 & !(CTRL2_UHS_MASK | CTRL2_EXEC_TUNING | CTRL2_TUNED_CLK),
+        );
+        self.bypass_dll();
+        self.cmd.reset(RESET_CMD | RESET_DATA);
+    }
+
+    /// Programs the host for `timing` with the card clock at `clock` Hz.
+    fn set_timing(&self, timing: Timing, clock: u64) -> Result<(), HostError> {
+        // 切换 UHS 模式时先停卡时钟
+        let clk = self.regs.read16(SDHCI_CLOCK_CONTROL);
+        self.regs.write16(SDHCI_CLOCK_CONTROL, clk & !CLOCK_CARD_EN);
+
+        let mode = match timing {
+            Timing::Legacy => 0,
+            Timing::Hs => CTRL2_UHS_SDR25,
+            Timing::Ddr52 => CTRL2_UHS_DDR50,
+            Timing::Hs200 => CTRL2_UHS_SDR104,
+            Timing::Hs400 | Timing::Hs400Es => CTRL2_HS400,
+        };
+        let ctrl2 = self.regs.read16(SDHCI_HOST_CONTROL2) & !CTRL2_UHS_MASK;
+        self.regs.write16(SDHCI_HOST_CONTROL2, ctrl2 | mode);
+
+        let emmc_ctrl = self.regs.read16(self.vendor + VENDOR_EMMC_CTRL);
+        self.regs.write16(
+            self.vendor + VENDOR_EMMC_CTRL,
+            emmc_ctrl | EMMC_CTRL_CARD_IS_EMMC,
+        );
+
+        self.set_clock(timing, clock)
+    }
+
+    /// Sets the core clock to `clock`, limited by `max-frequency`, runs the
+    /// card clock from it undivided and configures the DLL, following
+    /// `dwcmshc_rk3568_set_clock`.
+    fn set_clock(&self, timing: Timing, clock: u64) -> Result<(), HostError> {
+        let clock = self.caps.clock(clock);
+        let rate = self
+            .core_clk
+            .provider
+            .with(|clk| clk.set_rounded_rate(self.core_clk.id.into(), clock))
+            .map_err(|_| HostError::Clock)?;
+        debug!("eMMC card clock {} Hz (requested {})", rate, clock);
+
+        self.enable_card_clock()?;
+
+        // 关闭命令冲突检测
+        let ctrl3 = self.regs.read32(self.vendor + VENDOR_HOST_CTRL3);
+        self.regs.write32(
+            self.vendor + VENDOR_HOST_CTRL3,
+            ctrl3 & !HOST_CTRL3_CMD_CONFLICT_CHECK,
+        );
+
+        if rate <= DLL_MIN_CLOCK {
+            self.bypass_dll();
+            return Ok(());
+        }
+        self.start_dll(timing)
+    }
+
+    fn enable_card_clock(&self) -> Result<(), HostError> {
+        // 分频值为 0：卡时钟直接取核心时钟
+        self.regs.write16(SDHCI_CLOCK_CONTROL, CLOCK_INT_EN);
+        self.wait_clock_stable()?;
+
+        // SDHCI 4.10 及以上还需要打开 PLL
+        if self.regs.read16(SDHCI_HOST_VERSION) & 0xff >= 4 {
+            self.regs
+                .write16(SDHCI_CLOCK_CONTROL, CLOCK_INT_EN | CLOCK_PLL_EN);
+            self.wait_clock_stable()?;
+        }
+
+        let clk = self.regs.read16(SDHCI_CLOCK_CONTROL);
+        self.regs.write16(SDHCI_CLOCK_CONTROL, clk | CLOCK_CARD_EN);
+        Ok(())
+    }
+
+    fn wait_clock_stable(&self) -> Result<(), HostError> {
+        for _ in 0..CMD_POLLS {
+            if self.regs.read16(SDHCI_CLOCK_CONTROL) & CLOCK_INT_STABLE != 0 {
+                return Ok(());
+            }
+            busy_wait(POLL_INTERVAL);
+        }
+        Err(HostError::Clock)
+    }
+
+    /// Identification and legacy speeds: DLL bypassed, sample and drive
+    /// clocks reset, strobe delay line prepared for enhanced strobe.
+    fn bypass_dll(&self) {
+        self.regs
+            .write32(DLL_CTRL, DLL_CTRL_BYPASS | DLL_CTRL_START);
+        self.regs.write32(DLL_RXCLK, DLL_RXCLK_ORI_GATE);
+        self.regs.write32(DLL_TXCLK, 0);
+        self.regs.write32(DLL_CMDOUT, 0);
+        self.regs.write32(
+            DLL_STRBIN,
+            DLL_DLYENA
+                | DLL_STRBIN_DELAY_NUM_SEL
+                | (DLL_STRBIN_DELAY_NUM_DEFAULT << DLL_STRBIN_DELAY_NUM_SHIFT),
+        );
+    }
+
+    fn start_dll(&self, timing: Timing) -> Result<(), HostError> {
+        self.regs.write32(DLL_CTRL, DLL_CTRL_SRST);
+        busy_wait(Duration::from_micros(1));
+        self.regs.write32(DLL_CTRL, 0);
+
+        // 高速模式下接收时钟不能反相
+        self.regs.write32(
+            DLL_RXCLK,
+            DLL_DLYENA | (DLL_RXCLK_NO_INVERTER << DLL_RXCLK_SRCSEL_SHIFT),
+        );
+        self.regs.write32(
+            DLL_CTRL,
+            (0x5 << DLL_CTRL_START_POINT_SHIFT) | (0x2 << DLL_CTRL_INC_SHIFT) | DLL_CTRL_START,
+        );
+
+        let mut status = 0;
+        let locked = (0..DLL_LOCK_POLLS).any(|_| {
+            status = self.regs.read32(DLL_STATUS0);
+            if status & DLL_STATUS_LOCKED != 0 {
+                return true;
+            }
+            busy_wait(POLL_INTERVAL);
+            false
+        });
+        if !locked || status & DLL_STATUS_TIMEOUT != 0 {
+            return Err(HostError::DllLock(status));
+        }
+
+        // 调谐时钟停止使能，前后切换延迟各 3 个周期
+        self.regs.write32(
+            self.vendor + VENDOR_AT_CTRL,
+            (1 << 16) | (3 << 17) | (3 << 19),
+        );
+
+        let tapnum = match timing {
+            Timing::Hs200 | Timing::Hs400 | Timing::Hs400Es => self.caps.txclk_tapnum,
+            Timing::Legacy | Timing::Hs | Timing::Ddr52 => TXCLK_TAPNUM_DEFAULT,
+        };
+        self.regs.write32(
+            DLL_TXCLK,
+            DLL_DLYENA
+                | DLL_TXCLK_TAPNUM_FROM_SW
+                | (DLL_RXCLK_NO_INVERTER << DLL_RXCLK_SRCSEL_SHIFT)
+                | tapnum,
+        );
+        self.regs.write32(
+            DLL_STRBIN,
+            DLL_DLYENA | DLL_STRBIN_TAPNUM_DEFAULT | DLL_STRBIN_TAPNUM_FROM_SW,
+        );
+        Ok(())
+    }
+
+    fn set_enhanced_strobe(&self, enable: bool) {
+        let ctrl = self.regs.read16(self.vendor + VENDOR_EMMC_CTRL);
+        let ctrl = if enable {
+            ctrl | EMMC_CTRL_ENHANCED_STROBE
+        } else {
+            ctrl & !EMMC_CTRL_ENHANCED_STROBE
+        };
+        self.regs.write16(self.vendor + VENDOR_EMMC_CTRL, ctrl);
+    }
+
+    fn enable_1v8_signalling(&self) {
+        let ctrl2 = self.regs.read16(SDHCI_HOST_CONTROL2);
+        self.regs
+            .write16(SDHCI_HOST_CONTROL2, ctrl2 | CTRL2_VDD_180);
+    }
+
+    /// Standard SDHCI tuning: the controller issues CMD21 reads and moves
+    /// its sampling point until `EXEC_TUNING` clears.
+    fn execute_tuning(&self) -> Result<(), HostError> {
+        let ctrl2 = self.regs.read16(SDHCI_HOST_CONTROL2) & !CTRL2_TUNED_CLK;
+        self.regs
+            .write16(SDHCI_HOST_CONTROL2, ctrl2 | CTRL2_EXEC_TUNING);
+        self.cmd.enable_status(INT_BUF_RD_READY);
+
+        let block_size = if self.caps.bus_width == 8 {
+            TUNING_BLOCK_SIZE_8BIT
+        } else {
+            TUNING_BLOCK_SIZE_4BIT
+        };
+
+        for _ in 0..MAX_TUNING_LOOPS {
+            self.regs.write16(SDHCI_BLOCK_SIZE, block_size);
+            self.regs.write16(SDHCI_BLOCK_COUNT, 1);
+            self.regs.write16(SDHCI_TRANSFER_MODE, TRNS_READ);
+            self.cmd
+                .issue(MMC_SEND_TUNING_BLOCK_HS200, 0, CMD_RESP_R1_DATA)?;
+
+            // 调谐块由控制器自己消费，只需等待缓冲区就绪
+            let ready = self.cmd.wait_int(INT_BUF_RD_READY);
+            self.regs.write32(SDHCI_INT_STATUS, !0);
+            if !ready {
+                self.cmd.reset(RESET_CMD | RESET_DATA);
+            }
+
+            if self.regs.read16(SDHCI_HOST_CONTROL2) & CTRL2_EXEC_TUNING == 0 {
+                break;
+            }
+        }
+
+        let ctrl2 = self.regs.read16(SDHCI_HOST_CONTROL2);
+        if ctrl2 & CTRL2_TUNED_CLK != 0 && ctrl2 & CTRL2_EXEC_TUNING == 0 {
+            return Ok(());
+        }
+
+        warn!("eMMC tuning failed, host control 2 {:#x}", ctrl2);
+        self.regs.write16(
+            SDHCI_HOST_CONTROL2,
+            ctrl2 & !(CTRL2_EXEC_TUNING | CTRL2_TUNED_CLK),
+        );
+        self.cmd.reset(RESET_CMD | RESET_DATA);
+        Err(HostError::Tuning)
+    }
+}
+
+/// Recovery steps for [`axbsp_block::Recovery`], on the polled command
+/// engine.
+impl RecoveryHost for Dwcmshc {
+    type Error = HostError;
+
+    fn abort(&mut self) -> Result<(), HostError> {
+        self.cmd
+            .command(MMC_STOP_TRANSMISSION, 0, CMD_RESP_R1B)
+            .map(drop)
+    }
+
+    fn reset_lines(&mut self) {
+        self.cmd.reset(RESET_CMD | RESET_DATA);
+    }
+
+    fn send_status(&mut self) -> Result<u32, HostError> {
+        self.cmd.status()
+    }
+
+    fn set_bus_mode(&mut self, mode: BusMode) -> Result<(), HostError> {
+        self.set_mode(mode)
+    }
+}
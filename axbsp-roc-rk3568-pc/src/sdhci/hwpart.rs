@@ -0,0 +1,142 @@
+//! eMMC hardware partitions: the user area and the two boot areas, selected
+//! with the access bits of `PARTITION_CONFIG`.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use log::info;
+
+use super::cmd::{Cmd, HostError};
+
+const EXT_CSD_RPMB_SIZE_MULT: usize = 168;
+const EXT_CSD_BOOT_WP: usize = 173;
+const EXT_CSD_BOOT_WP_STATUS: usize = 174;
+const EXT_CSD_PARTITION_CONFIG: u8 = 179;
+const EXT_CSD_BOOT_SIZE_MULT: usize = 226;
+
+const PARTITION_ACCESS_MASK: u8 = 0b111;
+
+/// `BOOT_WP_STATUS` per boot area: 0 unprotected, 1 power-on, 2 permanent.
+const BOOT_WP_STATUS_MASK: u8 = 0b11;
+
+/// Boot areas are `BOOT_SIZE_MULT` × 128 KiB, the RPMB partition
+/// `RPMB_SIZE_MULT` × 128 KiB.
+const SIZE_UNIT_BLOCKS: u64 = 128 * 1024 / 512;
+
+/// A hardware partition of the eMMC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwPart {
+    User,
+    Boot0,
+    Boot1,
+    /// Only accessible through the RPMB protocol, see [`super::EmmcRpmb`].
+    Rpmb,
+}
+
+impl HwPart {
+    fn access(self) -> u8 {
+        match self {
+            HwPart::User => 0,
+            HwPart::Boot0 => 1,
+            HwPart::Boot1 => 2,
+            HwPart::Rpmb => 3,
+        }
+    }
+
+    /// Parses the `block-partition` names `boot0` and `boot1`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "boot0" => Some(HwPart::Boot0),
+            "boot1" => Some(HwPart::Boot1),
+            _ => None,
+        }
+    }
+}
+
+/// Write protection of a boot area, from `BOOT_WP_STATUS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootWp {
+    None,
+    /// Cleared by the next power cycle only.
+    PowerOn,
+    Permanent,
+}
+
+/// Hardware partition state shared by the devices of one eMMC.
+///
+/// [`HwParts::select`] must run with the host locked and no transfer in
+/// flight, right before the command it prepares for.
+pub struct HwParts {
+    cmd: Cmd,
+    /// 最近一次写入的 PARTITION_CONFIG，低 3 位为当前分区
+    config: AtomicU8,
+    boot_blocks: u64,
+    boot_wp: [BootWp; 2],
+    rpmb_blocks: u64,
+}
+
+impl HwParts {
+    /// Reads the boot area layout and the currently selected partition from
+    /// `EXT_CSD`.
+    pub fn new(base: usize, ext_csd: &[u8; 512]) -> Self {
+        let cmd = Cmd::new(base);
+        let config = ext_csd[EXT_CSD_PARTITION_CONFIG as usize];
+        let boot_blocks = ext_csd[EXT_CSD_BOOT_SIZE_MULT] as u64 * SIZE_UNIT_BLOCKS;
+        let rpmb_blocks = ext_csd[EXT_CSD_RPMB_SIZE_MULT] as u64 * SIZE_UNIT_BLOCKS;
+        let status = ext_csd[EXT_CSD_BOOT_WP_STATUS];
+        let boot_wp = [0, 2].map(|shift| match (status >> shift) & BOOT_WP_STATUS_MASK {
+            0 => BootWp::None,
+            1 => BootWp::PowerOn,
+            _ => BootWp::Permanent,
+        });
+        info!(
+            "eMMC boot areas: {} blocks each, write protect {:?} (BOOT_WP {:#x}); RPMB: {} blocks",
+            boot_blocks, boot_wp, ext_csd[EXT_CSD_BOOT_WP], rpmb_blocks
+        );
+
+        HwParts {
+            cmd,
+            config: AtomicU8::new(config),
+            boot_blocks,
+            boot_wp,
+            rpmb_blocks,
+        }
+    }
+
+    /// Size of `part` in blocks; `user_blocks` is the user area's.
+    pub fn num_blocks(&self, part: HwPart, user_blocks: u64) -> u64 {
+        match part {
+            HwPart::User => user_blocks,
+            HwPart::Boot0 | HwPart::Boot1 => self.boot_blocks,
+            HwPart::Rpmb => self.rpmb_blocks,
+        }
+    }
+
+    pub fn write_protect(&self, part: HwPart) -> BootWp {
+        match part {
+            HwPart::User | HwPart::Rpmb => BootWp::None,
+            HwPart::Boot0 => self.boot_wp[0],
+            HwPart::Boot1 => self.boot_wp[1],
+        }
+    }
+
+    /// Whether the card is already pointed at `part`.
+    #[cfg_attr(feature = "pio", allow(dead_code))]
+    pub fn is_selected(&self, part: HwPart) -> bool {
+        self.config.load(Ordering::Acquire) & PARTITION_ACCESS_MASK == part.access()
+    }
+
+    /// Points the card at `part`, keeping the boot configuration bits. Does
+    /// nothing if it is already selected.
+    pub fn select(&self, part: HwPart) -> Result<(), HostError> {
+        let config = self.config.load(Ordering::Acquire);
+        if config & PARTITION_ACCESS_MASK == part.access() {
+            return Ok(());
+        }
+
+        let config = (config & !PARTITION_ACCESS_MASK) | part.access();
+        self.cmd.switch(EXT_CSD_PARTITION_CONFIG, config)?;
+        self.cmd.check_status()?;
+        self.config.store(config, Ordering::Release);
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! Identity and wear of the eMMC, for monitoring.
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+
+use axbsp_block::{CardHealth, CardIdentity, CardVersion, Cid};
+use sdmmc::emmc::EMmcHost;
+use spin::Mutex;
+
+#[cfg(not(feature = "pio"))]
+use super::adma::Completion;
+use super::cmd::{Cmd, HostError};
+
+const EXT_CSD_REV: usize = 192;
+
+/// Reads the CID and decodes it with the `EXT_CSD` fields. Done once at
+/// probe time, since reading the CID deselects the card.
+pub fn read_identity(
+    base: usize,
+    ext_csd: &[u8; 512],
+    capacity_blocks: u64,
+) -> Result<CardIdentity, HostError> {
+    let cid = Cmd::new(base).read_cid()?;
+    Ok(CardIdentity {
+        cid: Cid::from_mmc(&cid, ext_csd[EXT_CSD_REV]),
+        version: CardVersion::Emmc(ext_csd[EXT_CSD_REV]),
+        capacity_blocks,
+    })
+}
+
+/// Identity and health queries of the eMMC; usable alongside the block
+/// queues.
+#[derive(Clone)]
+pub struct EmmcInfo {
+    host: Arc<Mutex<EMmcHost>>,
+    cmd: Cmd,
+    identity: Arc<CardIdentity>,
+    #[cfg(not(feature = "pio"))]
+    completion: Arc<Completion>,
+}
+
+impl EmmcInfo {
+    pub(super) fn new(
+        host: Arc<Mutex<EMmcHost>>,
+        base: usize,
+        identity: CardIdentity,
+        #[cfg(not(feature = "pio"))] completion: Arc<Completion>,
+    ) -> Self {
+        EmmcInfo {
+            host,
+            cmd: Cmd::new(base),
+            identity: Arc::new(identity),
+            #[cfg(not(feature = "pio"))]
+            completion,
+        }
+    }
+
+    /// CID and `EXT_CSD` details read at probe time.
+    pub fn identity(&self) -> &CardIdentity {
+        &self.identity
+    }
+
+    /// Reads the current wear estimates from `EXT_CSD`. Fails with
+    /// [`HostError::Busy`] while a block transfer is in flight.
+    pub fn health(&self) -> Result<CardHealth, HostError> {
+        let _host = self.host.lock();
+        #[cfg(not(feature = "pio"))]
+        if self.completion.is_busy() {
+            return Err(HostError::Busy);
+        }
+        let ext_csd = self.cmd.read_ext_csd()?;
+        Ok(CardHealth::from_ext_csd(&ext_csd))
+    }
+}
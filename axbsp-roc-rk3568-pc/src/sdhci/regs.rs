@@ -0,0 +1,115 @@
+//! SDHCI standard registers used outside of `EMmcHost`.
+
+#![allow(dead_code)]
+
+pub const SDHCI_BLOCK_SIZE: usize = 0x04;
+pub const SDHCI_BLOCK_COUNT: usize = 0x06;
+pub const SDHCI_ARGUMENT: usize = 0x08;
+pub const SDHCI_TRANSFER_MODE: usize = 0x0c;
+pub const SDHCI_COMMAND: usize = 0x0e;
+pub const SDHCI_RESPONSE: usize = 0x10;
+pub const SDHCI_BUFFER: usize = 0x20;
+pub const SDHCI_PRESENT_STATE: usize = 0x24;
+pub const SDHCI_HOST_CONTROL: usize = 0x28;
+pub const SDHCI_CLOCK_CONTROL: usize = 0x2c;
+pub const SDHCI_SOFTWARE_RESET: usize = 0x2f;
+pub const SDHCI_INT_STATUS: usize = 0x30;
+pub const SDHCI_INT_ENABLE: usize = 0x34;
+pub const SDHCI_SIGNAL_ENABLE: usize = 0x38;
+pub const SDHCI_HOST_CONTROL2: usize = 0x3e;
+pub const SDHCI_ADMA_ERROR: usize = 0x54;
+pub const SDHCI_ADMA_ADDRESS: usize = 0x58;
+pub const SDHCI_ADMA_ADDRESS_HI: usize = 0x5c;
+pub const SDHCI_HOST_VERSION: usize = 0xfe;
+
+pub const PRESENT_CMD_INHIBIT: u32 = 1 << 0;
+pub const PRESENT_DAT_INHIBIT: u32 = 1 << 1;
+
+pub const HOST_CTRL_4BIT: u8 = 1 << 1;
+pub const HOST_CTRL_DMA_MASK: u8 = 0b11 << 3;
+pub const HOST_CTRL_ADMA32: u8 = 0b10 << 3;
+pub const HOST_CTRL_8BIT: u8 = 1 << 5;
+
+pub const CLOCK_INT_EN: u16 = 1 << 0;
+pub const CLOCK_INT_STABLE: u16 = 1 << 1;
+pub const CLOCK_CARD_EN: u16 = 1 << 2;
+pub const CLOCK_PLL_EN: u16 = 1 << 3;
+
+pub const CTRL2_UHS_MASK: u16 = 0b111;
+pub const CTRL2_UHS_SDR25: u16 = 1;
+pub const CTRL2_UHS_SDR104: u16 = 3;
+pub const CTRL2_UHS_DDR50: u16 = 4;
+/// dwcmshc 私有的 HS400 模式编码
+pub const CTRL2_HS400: u16 = 7;
+pub const CTRL2_VDD_180: u16 = 1 << 3;
+pub const CTRL2_EXEC_TUNING: u16 = 1 << 6;
+pub const CTRL2_TUNED_CLK: u16 = 1 << 7;
+
+pub const RESET_CMD: u8 = 1 << 1;
+pub const RESET_DATA: u8 = 1 << 2;
+
+pub const TRNS_DMA: u16 = 1 << 0;
+pub const TRNS_BLK_CNT_EN: u16 = 1 << 1;
+pub const TRNS_AUTO_CMD12: u16 = 1 << 2;
+pub const TRNS_READ: u16 = 1 << 4;
+pub const TRNS_MULTI: u16 = 1 << 5;
+
+const CMD_RESP_136: u16 = 0b01;
+const CMD_RESP_48: u16 = 0b10;
+const CMD_RESP_48_BUSY: u16 = 0b11;
+const CMD_CRC: u16 = 1 << 3;
+const CMD_INDEX: u16 = 1 << 4;
+const CMD_DATA: u16 = 1 << 5;
+
+/// 无响应
+pub const CMD_RESP_NONE: u16 = 0;
+/// R2: 136 位 CID/CSD 响应，不检查命令索引
+pub const CMD_RESP_R2: u16 = CMD_RESP_136 | CMD_CRC;
+/// R1: 48 位响应，检查 CRC 和命令索引
+pub const CMD_RESP_R1: u16 = CMD_RESP_48 | CMD_CRC | CMD_INDEX;
+/// R1 带数据
+pub const CMD_RESP_R1_DATA: u16 = CMD_RESP_R1 | CMD_DATA;
+/// R1b: R1 之后卡在 DAT0 上保持忙
+pub const CMD_RESP_R1B: u16 = CMD_RESP_48_BUSY | CMD_CRC | CMD_INDEX;
+
+pub const INT_CMD_COMPLETE: u32 = 1 << 0;
+pub const INT_XFER_COMPLETE: u32 = 1 << 1;
+pub const INT_BUF_WR_READY: u32 = 1 << 4;
+pub const INT_BUF_RD_READY: u32 = 1 << 5;
+pub const INT_CQE: u32 = 1 << 14;
+pub const INT_ERROR: u32 = 1 << 15;
+pub const INT_CMD_TIMEOUT: u32 = 1 << 16;
+pub const INT_CMD_ERRORS: u32 = 0b1111 << 16;
+pub const INT_DATA_TIMEOUT: u32 = 1 << 20;
+pub const INT_DATA_ERRORS: u32 = 0b111 << 20;
+pub const INT_ADMA_ERROR: u32 = 1 << 25;
+
+/// Mapped SDHCI register block.
+#[derive(Debug, Clone, Copy)]
+pub struct Regs(pub usize);
+
+impl Regs {
+    pub fn read8(&self, offset: usize) -> u8 {
+        unsafe { ((self.0 + offset) as *const u8).read_volatile() }
+    }
+
+    pub fn write8(&self, offset: usize, value: u8) {
+        unsafe { ((self.0 + offset) as *mut u8).write_volatile(value) }
+    }
+
+    pub fn read16(&self, offset: usize) -> u16 {
+        unsafe { ((self.0 + offset) as *const u16).read_volatile() }
+    }
+
+    pub fn write16(&self, offset: usize, value: u16) {
+        unsafe { ((self.0 + offset) as *mut u16).write_volatile(value) }
+    }
+
+    pub fn read32(&self, offset: usize) -> u32 {
+        unsafe { ((self.0 + offset) as *const u32).read_volatile() }
+    }
+
+    pub fn write32(&self, offset: usize, value: u32) {
+        unsafe { ((self.0 + offset) as *mut u32).write_volatile(value) }
+    }
+}
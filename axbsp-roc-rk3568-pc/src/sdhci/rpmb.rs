@@ -0,0 +1,100 @@
+//! RPMB transport: frames go to the RPMB partition by PIO, between the
+//! block queues' transfers.
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use axbsp_block::rpmb::{FRAME_SIZE, Frame, RpmbTransport};
+use sdmmc::emmc::EMmcHost;
+use spin::Mutex;
+
+#[cfg(not(feature = "pio"))]
+use super::adma::Completion;
+use super::cmd::{Cmd, HostError, MMC_READ_MULTIPLE_BLOCK, MMC_WRITE_MULTIPLE_BLOCK};
+#[cfg(not(feature = "pio"))]
+use super::cqe::Cqe;
+use super::hwpart::{HwPart, HwParts};
+
+/// [`RpmbTransport`] of the eMMC, for use with [`axbsp_block::rpmb::Rpmb`].
+#[derive(Clone)]
+pub struct EmmcRpmb {
+    host: Arc<Mutex<EMmcHost>>,
+    parts: Arc<HwParts>,
+    cmd: Cmd,
+    #[cfg(not(feature = "pio"))]
+    completion: Arc<Completion>,
+    #[cfg(not(feature = "pio"))]
+    cqe: Option<Arc<Cqe>>,
+}
+
+impl EmmcRpmb {
+    pub(super) fn new(
+        host: Arc<Mutex<EMmcHost>>,
+        parts: Arc<HwParts>,
+        base: usize,
+        #[cfg(not(feature = "pio"))] completion: Arc<Completion>,
+        #[cfg(not(feature = "pio"))] cqe: Option<Arc<Cqe>>,
+    ) -> Self {
+        EmmcRpmb {
+            host,
+            parts,
+            cmd: Cmd::new(base),
+            #[cfg(not(feature = "pio"))]
+            completion,
+            #[cfg(not(feature = "pio"))]
+            cqe,
+        }
+    }
+
+    /// Size of the RPMB partition in bytes.
+    pub fn size(&self) -> u64 {
+        self.parts.num_blocks(HwPart::Rpmb, 0) * FRAME_SIZE as u64
+    }
+
+    /// Runs `f` on the RPMB partition with the host locked. The block queues
+    /// switch back to their own partition on their next request.
+    fn with_rpmb<R>(&self, f: impl FnOnce(&Cmd) -> Result<R, HostError>) -> Result<R, HostError> {
+        let _host = self.host.lock();
+        #[cfg(not(feature = "pio"))]
+        if self.completion.is_busy() {
+            return Err(HostError::Busy);
+        }
+        #[cfg(not(feature = "pio"))]
+        if let Some(cqe) = &self.cqe {
+            return cqe.without_cmdq(|| {
+                let result = self.parts.select(HwPart::Rpmb).and_then(|()| f(&self.cmd));
+                // 命令队列模式下不能停留在 RPMB 分区
+                self.parts.select(HwPart::User)?;
+                result
+            });
+        }
+        self.parts.select(HwPart::Rpmb)?;
+        f(&self.cmd)
+    }
+}
+
+impl RpmbTransport for EmmcRpmb {
+    type Error = HostError;
+
+    fn send(&mut self, frames: &[Frame], reliable: bool) -> Result<(), HostError> {
+        let data: Vec<u8> = frames.iter().flat_map(|frame| frame.0).collect();
+        self.with_rpmb(|cmd| {
+            cmd.set_block_count(frames.len() as u16, reliable)?;
+            cmd.write_data(MMC_WRITE_MULTIPLE_BLOCK, 0, &data)
+        })
+    }
+
+    fn receive(&mut self, frames: &mut [Frame]) -> Result<(), HostError> {
+        let mut data = vec![0u8; frames.len() * FRAME_SIZE];
+        self.with_rpmb(|cmd| {
+            cmd.set_block_count(frames.len() as u16, false)?;
+            cmd.read_data(MMC_READ_MULTIPLE_BLOCK, 0, &mut data)
+        })?;
+
+        for (frame, block) in frames.iter_mut().zip(data.chunks_exact(FRAME_SIZE)) {
+            frame.0.copy_from_slice(block);
+        }
+        Ok(())
+    }
+}